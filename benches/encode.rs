@@ -0,0 +1,67 @@
+//! Compares the byte-at-a-time `Iterator` encoders with `encode_into`.
+//!
+//! Run with `cargo bench`.
+
+#[macro_use]
+extern crate criterion;
+extern crate tockloader_proto;
+
+use criterion::{black_box, Criterion, Throughput};
+use tockloader_proto::prelude::*;
+use tockloader_proto::{Command, CommandEncoder, Response, ResponseEncoder};
+
+fn page() -> [u8; 512] {
+    let mut page = [0u8; 512];
+    for (i, b) in page.iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    page
+}
+
+fn bench_write_page(c: &mut Criterion) {
+    let page = page();
+    let cmd = Command::WritePage {
+        address: 0x0001_0000,
+        data: &page,
+    };
+    let mut buffer = [0u8; 1100];
+    let mut group = c.benchmark_group("write_page");
+    group.throughput(Throughput::Bytes(page.len() as u64));
+    group.bench_function("iterator", |b| {
+        b.iter(|| {
+            let mut e = CommandEncoder::new(black_box(&cmd)).unwrap();
+            black_box(e.write(&mut buffer))
+        })
+    });
+    group.bench_function("encode_into", |b| {
+        b.iter(|| {
+            let e = CommandEncoder::new(black_box(&cmd)).unwrap();
+            black_box(e.encode_into(&mut buffer).unwrap())
+        })
+    });
+    group.finish();
+}
+
+fn bench_read_range(c: &mut Criterion) {
+    let page = page();
+    let rsp = Response::ReadRange { data: &page };
+    let mut buffer = [0u8; 1100];
+    let mut group = c.benchmark_group("read_range");
+    group.throughput(Throughput::Bytes(page.len() as u64));
+    group.bench_function("iterator", |b| {
+        b.iter(|| {
+            let mut e = ResponseEncoder::new(black_box(&rsp)).unwrap();
+            black_box(e.write(&mut buffer))
+        })
+    });
+    group.bench_function("encode_into", |b| {
+        b.iter(|| {
+            let e = ResponseEncoder::new(black_box(&rsp)).unwrap();
+            black_box(e.encode_into(&mut buffer).unwrap())
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_write_page, bench_read_range);
+criterion_main!(benches);
@@ -0,0 +1,318 @@
+/* Generated by cbindgen from src/ffi.rs. Do not edit. */
+
+#ifndef TOCKLOADER_PROTO_H
+#define TOCKLOADER_PROTO_H
+
+
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// The size of `TlDecoder`, in 64-bit words.
+#define TL_DECODER_WORDS 128
+
+// The sub-command of `TlCommand::ChangeBaud`.
+typedef enum tl_baud_mode {
+  // Switch to the new baud rate.
+  TL_BAUD_MODE_SET = 1,
+  // Confirm the new baud rate works.
+  TL_BAUD_MODE_VERIFY = 2,
+} tl_baud_mode;
+
+// The outcome of a call.
+typedef enum tl_status {
+  // It worked. For `tl_decoder_feed`, a command has been decoded.
+  TL_STATUS_OK = 0,
+  // More bytes are needed before there's a command.
+  TL_STATUS_PENDING,
+  // A pointer argument was null.
+  TL_STATUS_NULL_POINTER,
+  // See `Error::UnknownCommand`.
+  TL_STATUS_UNKNOWN_COMMAND,
+  // See `Error::BadArguments`.
+  TL_STATUS_BAD_ARGUMENTS,
+  // See `Error::BadMode`.
+  TL_STATUS_BAD_MODE,
+  // See `Error::BadIndex`.
+  TL_STATUS_BAD_INDEX,
+  // See `Error::BadLength`.
+  TL_STATUS_BAD_LENGTH,
+  // See `Error::UnalignedAddress`.
+  TL_STATUS_UNALIGNED_ADDRESS,
+  // See `Error::UnsetLength`.
+  TL_STATUS_UNSET_LENGTH,
+  // See `Error::SetLength`.
+  TL_STATUS_SET_LENGTH,
+  // See `Error::UnexpectedResponse`.
+  TL_STATUS_UNEXPECTED_RESPONSE,
+  // See `Error::BufferTooSmall`.
+  TL_STATUS_BUFFER_TOO_SMALL,
+  // See `Error::BadKey`.
+  TL_STATUS_BAD_KEY,
+} tl_status;
+
+// Memory for a command decoder. Only touch it through the `tl_decoder_*`
+// functions.
+typedef struct tl_decoder {
+  uint64_t storage[TL_DECODER_WORDS];
+} tl_decoder;
+
+// A decoded command. See `Command` for what each one means.
+//
+// `key` always points to 8 bytes.
+enum tl_command_Tag {
+  TL_COMMAND_PING,
+  TL_COMMAND_INFO,
+  TL_COMMAND_ID,
+  TL_COMMAND_RESET,
+  TL_COMMAND_ERASE_PAGE,
+  TL_COMMAND_WRITE_PAGE,
+  TL_COMMAND_ERASE_EX_BLOCK,
+  TL_COMMAND_WRITE_EX_PAGE,
+  TL_COMMAND_CRC_RX_BUFFER,
+  TL_COMMAND_READ_RANGE,
+  TL_COMMAND_EX_READ_RANGE,
+  TL_COMMAND_SET_ATTR,
+  TL_COMMAND_GET_ATTR,
+  TL_COMMAND_CRC_INT_FLASH,
+  TL_COMMAND_CRC_EXT_FLASH,
+  TL_COMMAND_ERASE_EX_PAGE,
+  TL_COMMAND_EXT_FLASH_INIT,
+  TL_COMMAND_CLOCK_OUT,
+  TL_COMMAND_WRITE_FLASH_USER_PAGES,
+  TL_COMMAND_CHANGE_BAUD,
+  TL_COMMAND_EXIT,
+  TL_COMMAND_SET_START_ADDRESS,
+  TL_COMMAND_VENDOR,
+};
+typedef uint8_t tl_command_Tag;
+
+typedef struct tl_command_ErasePage_Body {
+  uint32_t address;
+} tl_command_ErasePage_Body;
+
+typedef struct tl_command_WritePage_Body {
+  uint32_t address;
+  const uint8_t *data;
+  size_t data_len;
+} tl_command_WritePage_Body;
+
+typedef struct tl_command_EraseExBlock_Body {
+  uint32_t address;
+} tl_command_EraseExBlock_Body;
+
+typedef struct tl_command_WriteExPage_Body {
+  uint32_t address;
+  const uint8_t *data;
+  size_t data_len;
+} tl_command_WriteExPage_Body;
+
+typedef struct tl_command_ReadRange_Body {
+  uint32_t address;
+  uint16_t length;
+} tl_command_ReadRange_Body;
+
+typedef struct tl_command_ExReadRange_Body {
+  uint32_t address;
+  uint16_t length;
+} tl_command_ExReadRange_Body;
+
+typedef struct tl_command_SetAttr_Body {
+  uint8_t index;
+  const uint8_t *key;
+  const uint8_t *value;
+  size_t value_len;
+} tl_command_SetAttr_Body;
+
+typedef struct tl_command_GetAttr_Body {
+  uint8_t index;
+} tl_command_GetAttr_Body;
+
+typedef struct tl_command_CrcIntFlash_Body {
+  uint32_t address;
+  uint32_t length;
+} tl_command_CrcIntFlash_Body;
+
+typedef struct tl_command_CrcExtFlash_Body {
+  uint32_t address;
+  uint32_t length;
+} tl_command_CrcExtFlash_Body;
+
+typedef struct tl_command_EraseExPage_Body {
+  uint32_t address;
+} tl_command_EraseExPage_Body;
+
+typedef struct tl_command_WriteFlashUserPages_Body {
+  uint32_t page1;
+  uint32_t page2;
+} tl_command_WriteFlashUserPages_Body;
+
+typedef struct tl_command_ChangeBaud_Body {
+  enum tl_baud_mode mode;
+  uint32_t baud;
+} tl_command_ChangeBaud_Body;
+
+typedef struct tl_command_SetStartAddress_Body {
+  uint32_t address;
+} tl_command_SetStartAddress_Body;
+
+typedef struct tl_command_Vendor_Body {
+  uint8_t code;
+  const uint8_t *payload;
+  size_t payload_len;
+} tl_command_Vendor_Body;
+
+typedef struct tl_command {
+  tl_command_Tag tag;
+  union {
+    tl_command_ErasePage_Body ERASE_PAGE;
+    tl_command_WritePage_Body WRITE_PAGE;
+    tl_command_EraseExBlock_Body ERASE_EX_BLOCK;
+    tl_command_WriteExPage_Body WRITE_EX_PAGE;
+    tl_command_ReadRange_Body READ_RANGE;
+    tl_command_ExReadRange_Body EX_READ_RANGE;
+    tl_command_SetAttr_Body SET_ATTR;
+    tl_command_GetAttr_Body GET_ATTR;
+    tl_command_CrcIntFlash_Body CRC_INT_FLASH;
+    tl_command_CrcExtFlash_Body CRC_EXT_FLASH;
+    tl_command_EraseExPage_Body ERASE_EX_PAGE;
+    tl_command_WriteFlashUserPages_Body WRITE_FLASH_USER_PAGES;
+    tl_command_ChangeBaud_Body CHANGE_BAUD;
+    tl_command_SetStartAddress_Body SET_START_ADDRESS;
+    tl_command_Vendor_Body VENDOR;
+  };
+} tl_command;
+
+// A response to encode. See `Response` for what each one means.
+//
+// A pointer may be null if its length is zero. `key` must point to 8
+// bytes.
+enum tl_response_Tag {
+  TL_RESPONSE_OVERFLOW,
+  TL_RESPONSE_PONG,
+  TL_RESPONSE_BAD_ADDRESS,
+  TL_RESPONSE_INTERNAL_ERROR,
+  TL_RESPONSE_BAD_ARGUMENTS,
+  TL_RESPONSE_OK,
+  TL_RESPONSE_UNKNOWN,
+  TL_RESPONSE_EXT_FLASH_TIMEOUT,
+  TL_RESPONSE_EXT_FLASH_PAGE_ERROR,
+  TL_RESPONSE_CRC_RX_BUFFER,
+  TL_RESPONSE_READ_RANGE,
+  TL_RESPONSE_EX_READ_RANGE,
+  TL_RESPONSE_GET_ATTR,
+  TL_RESPONSE_CRC_INT_FLASH,
+  TL_RESPONSE_CRC_EXT_FLASH,
+  TL_RESPONSE_INFO,
+  TL_RESPONSE_CHANGE_BAUD_FAIL,
+  TL_RESPONSE_ID,
+  TL_RESPONSE_VENDOR,
+};
+typedef uint8_t tl_response_Tag;
+
+typedef struct tl_response_CrcRxBuffer_Body {
+  uint16_t length;
+  uint32_t crc;
+} tl_response_CrcRxBuffer_Body;
+
+typedef struct tl_response_ReadRange_Body {
+  const uint8_t *data;
+  size_t data_len;
+} tl_response_ReadRange_Body;
+
+typedef struct tl_response_ExReadRange_Body {
+  const uint8_t *data;
+  size_t data_len;
+} tl_response_ExReadRange_Body;
+
+typedef struct tl_response_GetAttr_Body {
+  const uint8_t *key;
+  const uint8_t *value;
+  size_t value_len;
+} tl_response_GetAttr_Body;
+
+typedef struct tl_response_CrcIntFlash_Body {
+  uint32_t crc;
+} tl_response_CrcIntFlash_Body;
+
+typedef struct tl_response_CrcExtFlash_Body {
+  uint32_t crc;
+} tl_response_CrcExtFlash_Body;
+
+typedef struct tl_response_Info_Body {
+  const uint8_t *info;
+  size_t info_len;
+} tl_response_Info_Body;
+
+typedef struct tl_response_Id_Body {
+  const uint8_t *id;
+  size_t id_len;
+} tl_response_Id_Body;
+
+typedef struct tl_response_Vendor_Body {
+  uint8_t code;
+  const uint8_t *payload;
+  size_t payload_len;
+} tl_response_Vendor_Body;
+
+typedef struct tl_response {
+  tl_response_Tag tag;
+  union {
+    tl_response_CrcRxBuffer_Body CRC_RX_BUFFER;
+    tl_response_ReadRange_Body READ_RANGE;
+    tl_response_ExReadRange_Body EX_READ_RANGE;
+    tl_response_GetAttr_Body GET_ATTR;
+    tl_response_CrcIntFlash_Body CRC_INT_FLASH;
+    tl_response_CrcExtFlash_Body CRC_EXT_FLASH;
+    tl_response_Info_Body INFO;
+    tl_response_Id_Body ID;
+    tl_response_Vendor_Body VENDOR;
+  };
+} tl_response;
+
+// Set up a decoder in memory provided by the caller.
+//
+// # Safety
+//
+// `decoder` must be null or point to a `TlDecoder`.
+enum tl_status tl_decoder_init(struct tl_decoder *decoder);
+
+// Empty the decoder's buffer, as for `CommandDecoder::reset`.
+//
+// # Safety
+//
+// `decoder` must be null or point to a `TlDecoder` set up with
+// `tl_decoder_init`.
+enum tl_status tl_decoder_reset(struct tl_decoder *decoder);
+
+// Pass a received byte to the decoder.
+//
+// Returns `TlStatus::Ok` and fills in `command` if the byte finished a
+// command, `TlStatus::Pending` if more bytes are needed, or the error if
+// the command was bad. The decoder is ready for more bytes either way.
+//
+// # Safety
+//
+// `decoder` must be null or point to a `TlDecoder` set up with
+// `tl_decoder_init`, and `command` must be null or point to a `TlCommand`.
+enum tl_status tl_decoder_feed(struct tl_decoder *decoder,
+                               uint8_t byte,
+                               struct tl_command *command);
+
+// Encode `response` into `buffer`, which is `buffer_len` bytes long, and
+// set `written` to the number of bytes used.
+//
+// # Safety
+//
+// Each argument must be null or valid: `response` must point to a
+// `TlResponse` whose pointers are valid for their lengths, `buffer` must
+// point to `buffer_len` bytes, and `written` must point to a `size_t`.
+enum tl_status tl_response_encode(const struct tl_response *response,
+                                  uint8_t *buffer,
+                                  size_t buffer_len,
+                                  size_t *written);
+
+#endif /* TOCKLOADER_PROTO_H */
@@ -0,0 +1,1029 @@
+//! Typed attributes, for `Command::SetAttr` and `Response::GetAttr`.
+//!
+//! On the wire, an attribute is an 8 byte null-padded key plus a value of
+//! up to 55 bytes. Tock defines a handful of well-known attributes, which
+//! tockloader stores as ASCII strings (addresses in hex).
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::convert::TryFrom;
+use core::fmt;
+use core::str;
+
+use heapless;
+
+use super::{Command, Error, Field, Response, CMD_SATTR, KEY_LEN, MAX_ATTR_LEN, MAX_INDEX};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// An attribute key: up to 8 characters of printable ASCII.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub struct AttributeKey {
+    bytes: [u8; KEY_LEN],
+}
+
+/// A key and its value.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+pub struct Attribute {
+    key: AttributeKey,
+    value: heapless::Vec<u8, MAX_ATTR_LEN>,
+}
+
+/// The attributes Tock gives a meaning to.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum WellKnown {
+    /// The name of the board, e.g. `nrf52dk`.
+    Board,
+    /// The CPU architecture, e.g. `cortex-m4`.
+    Arch,
+    /// Where applications start in flash.
+    AppAddr,
+    /// A hash of the bootloader.
+    BootHash,
+    /// Where the bootloader starts the kernel.
+    BootAddr,
+    /// The device name to give to JLinkExe.
+    JlDevice,
+}
+
+/// The value of a well-known attribute. Addresses are parsed, everything
+/// else is left as a string.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum KnownValue<'a> {
+    Board(&'a str),
+    Arch(&'a str),
+    AppAddr(u32),
+    BootHash(&'a str),
+    BootAddr(u32),
+    JlDevice(&'a str),
+}
+
+/// A copy of all of a bootloader's attribute slots.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Default)]
+pub struct AttributeTable {
+    slots: [Option<Attribute>; MAX_INDEX as usize],
+}
+
+/// One slot which differs between two `AttributeTable`s.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Change<'a> {
+    /// Write this attribute to the slot.
+    Set { index: u8, attribute: &'a Attribute },
+    /// Empty the slot.
+    Clear { index: u8 },
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl AttributeKey {
+    /// Make a key from a string of 1 to 8 printable ASCII characters. Use
+    /// `AttributeKey::empty` for the key of an unused slot.
+    pub fn new(key: &str) -> Result<AttributeKey, Error> {
+        let key = key.as_bytes();
+        if key.is_empty() {
+            return Err(Error::BadKey {
+                position: 0,
+                byte: 0,
+            });
+        }
+        if key.len() > KEY_LEN {
+            return Err(Error::BadLength {
+                code: CMD_SATTR,
+                field: Field::Key,
+                limit: KEY_LEN,
+                actual: key.len(),
+            });
+        }
+        if let Some(position) = key.iter().position(|&b| !is_printable(b)) {
+            return Err(Error::BadKey {
+                position,
+                byte: key[position],
+            });
+        }
+        let mut bytes = [0u8; KEY_LEN];
+        bytes[..key.len()].copy_from_slice(key);
+        Ok(AttributeKey { bytes })
+    }
+
+    /// Make a key from its 8 byte, null padded, wire format.
+    pub fn from_bytes(key: &[u8]) -> Result<AttributeKey, Error> {
+        if key.len() != KEY_LEN {
+            return Err(Error::BadLength {
+                code: CMD_SATTR,
+                field: Field::Key,
+                limit: KEY_LEN,
+                actual: key.len(),
+            });
+        }
+        let len = key.iter().position(|&b| b == 0).unwrap_or(KEY_LEN);
+        let (text, padding) = key.split_at(len);
+        let bad = text
+            .iter()
+            .position(|&b| !is_printable(b))
+            .or_else(|| padding.iter().position(|&b| b != 0).map(|p| len + p));
+        if let Some(position) = bad {
+            return Err(Error::BadKey {
+                position,
+                byte: key[position],
+            });
+        }
+        let mut bytes = [0u8; KEY_LEN];
+        bytes.copy_from_slice(key);
+        Ok(AttributeKey { bytes })
+    }
+
+    /// The all-zero key, which marks an unused attribute slot.
+    pub fn empty() -> AttributeKey {
+        AttributeKey {
+            bytes: [0u8; KEY_LEN],
+        }
+    }
+
+    /// Is this the all-zero key?
+    pub fn is_empty(&self) -> bool {
+        self.bytes[0] == 0
+    }
+
+    /// The key, without padding.
+    pub fn as_str(&self) -> &str {
+        let len = self.bytes.iter().position(|&b| b == 0).unwrap_or(KEY_LEN);
+        // We only ever hold ASCII
+        str::from_utf8(&self.bytes[..len]).unwrap_or("")
+    }
+
+    /// The key in wire format, with null padding.
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        &self.bytes
+    }
+
+    /// If this is a well-known key, which one.
+    pub fn well_known(&self) -> Option<WellKnown> {
+        WellKnown::ALL
+            .iter()
+            .cloned()
+            .find(|known| known.name() == self.as_str())
+    }
+}
+
+impl fmt::Display for AttributeKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<'a> TryFrom<&'a str> for AttributeKey {
+    type Error = Error;
+
+    fn try_from(key: &'a str) -> Result<AttributeKey, Error> {
+        AttributeKey::new(key)
+    }
+}
+
+impl Attribute {
+    /// Make an attribute from a key and a value of 1 to 55 bytes. An empty
+    /// value would read back as a cleared slot, so it is refused.
+    pub fn new(key: AttributeKey, value: &[u8]) -> Result<Attribute, Error> {
+        let bad_length = Error::BadLength {
+            code: CMD_SATTR,
+            field: Field::Value,
+            limit: MAX_ATTR_LEN,
+            actual: value.len(),
+        };
+        if value.is_empty() {
+            return Err(bad_length);
+        }
+        let value = heapless::Vec::from_slice(value).map_err(|_| bad_length)?;
+        Ok(Attribute { key, value })
+    }
+
+    /// Make an attribute from a string key and a string value.
+    pub fn from_strs(key: &str, value: &str) -> Result<Attribute, Error> {
+        Attribute::new(AttributeKey::new(key)?, value.as_bytes())
+    }
+
+    /// Make a well-known attribute holding an address, which is written in
+    /// hex like tockloader does.
+    pub fn address(known: WellKnown, address: u32) -> Attribute {
+        let mut value = heapless::String::<MAX_ATTR_LEN>::new();
+        // Ten characters always fits
+        let _ = fmt::write(&mut value, format_args!("0x{:x}", address));
+        Attribute {
+            key: known.key(),
+            value: value.into_bytes(),
+        }
+    }
+
+    /// Get the attribute from a `Response::GetAttr`. Returns `None` if it's
+    /// a different response.
+    pub fn from_response(response: &Response) -> Option<Result<Attribute, Error>> {
+        match *response {
+            Response::GetAttr { key, value } => {
+                Some(AttributeKey::from_bytes(key).and_then(|key| Attribute::new(key, value)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Get the attribute from a `Command::SetAttr`, along with its index.
+    /// Returns `None` if it's a different command.
+    pub fn from_command(command: &Command) -> Option<Result<(u8, Attribute), Error>> {
+        match *command {
+            Command::SetAttr { index, key, value } => Some(
+                AttributeKey::from_bytes(key)
+                    .and_then(|key| Attribute::new(key, value))
+                    .map(|attr| (index, attr)),
+            ),
+            _ => None,
+        }
+    }
+
+    /// A `Command::SetAttr` which stores this attribute at `index`.
+    pub fn to_command(&self, index: u8) -> Command<'_> {
+        Command::SetAttr {
+            index,
+            key: self.key.as_bytes(),
+            value: &self.value,
+        }
+    }
+
+    /// A `Response::GetAttr` holding this attribute.
+    pub fn to_response(&self) -> Response<'_> {
+        Response::GetAttr {
+            key: self.key.as_bytes(),
+            value: &self.value,
+        }
+    }
+
+    /// The key.
+    pub fn key(&self) -> &AttributeKey {
+        &self.key
+    }
+
+    /// The raw value.
+    pub fn value(&self) -> &[u8] {
+        &self.value
+    }
+
+    /// The value as a string, if it is valid UTF-8.
+    pub fn value_str(&self) -> Option<&str> {
+        str::from_utf8(&self.value).ok()
+    }
+
+    /// The value as a number, written either in decimal or in hex with a
+    /// `0x` prefix.
+    pub fn value_u32(&self) -> Option<u32> {
+        let text = self.value_str()?.trim();
+        if text.starts_with("0x") || text.starts_with("0X") {
+            u32::from_str_radix(&text[2..], 16).ok()
+        } else {
+            text.parse().ok()
+        }
+    }
+
+    /// If this is a well-known attribute, its value. Returns `None` for
+    /// unknown keys and for values which don't make sense for the key.
+    pub fn known_value(&self) -> Option<KnownValue<'_>> {
+        Some(match self.key.well_known()? {
+            WellKnown::Board => KnownValue::Board(self.value_str()?),
+            WellKnown::Arch => KnownValue::Arch(self.value_str()?),
+            WellKnown::AppAddr => KnownValue::AppAddr(self.value_u32()?),
+            WellKnown::BootHash => KnownValue::BootHash(self.value_str()?),
+            WellKnown::BootAddr => KnownValue::BootAddr(self.value_u32()?),
+            WellKnown::JlDevice => KnownValue::JlDevice(self.value_str()?),
+        })
+    }
+}
+
+impl AttributeTable {
+    /// A table with every slot empty.
+    pub fn new() -> AttributeTable {
+        AttributeTable::default()
+    }
+
+    /// The attribute in slot `index`, if there is one.
+    pub fn get(&self, index: u8) -> Option<&Attribute> {
+        self.slots.get(usize::from(index)).and_then(Option::as_ref)
+    }
+
+    /// Put an attribute in slot `index`, replacing whatever was there.
+    pub fn set(&mut self, index: u8, attribute: Attribute) -> Result<(), Error> {
+        *self.slot(index)? = Some(attribute);
+        Ok(())
+    }
+
+    /// Empty slot `index`.
+    pub fn clear(&mut self, index: u8) -> Result<(), Error> {
+        *self.slot(index)? = None;
+        Ok(())
+    }
+
+    /// Store an attribute in the slot which already has its key, or failing
+    /// that the first empty slot. Returns the slot used.
+    pub fn insert(&mut self, attribute: Attribute) -> Result<u8, Error> {
+        let index = match self.find(attribute.key()) {
+            Some((index, _)) => index,
+            None => self
+                .slots
+                .iter()
+                .position(Option::is_none)
+                .map(|index| index as u8)
+                .ok_or(Error::BadIndex {
+                    code: CMD_SATTR,
+                    index: MAX_INDEX,
+                })?,
+        };
+        self.set(index, attribute)?;
+        Ok(index)
+    }
+
+    /// Find the attribute with this key, and which slot it is in.
+    pub fn find(&self, key: &AttributeKey) -> Option<(u8, &Attribute)> {
+        self.iter().find(|&(_, attribute)| attribute.key() == key)
+    }
+
+    /// The attributes in the table, with their slot numbers.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &Attribute)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| slot.as_ref().map(|attribute| (index as u8, attribute)))
+    }
+
+    /// The slots which must be written to turn this table into `desired`.
+    /// Slots which are empty in `desired` are cleared.
+    pub fn diff<'a>(&'a self, desired: &'a AttributeTable) -> impl Iterator<Item = Change<'a>> {
+        self.slots
+            .iter()
+            .zip(desired.slots.iter())
+            .enumerate()
+            .filter_map(|(index, (current, desired))| {
+                let index = index as u8;
+                match (current.as_ref(), desired.as_ref()) {
+                    (current, Some(attribute)) if current != Some(attribute) => {
+                        Some(Change::Set { index, attribute })
+                    }
+                    (Some(_), None) => Some(Change::Clear { index }),
+                    _ => None,
+                }
+            })
+    }
+
+    fn slot(&mut self, index: u8) -> Result<&mut Option<Attribute>, Error> {
+        self.slots
+            .get_mut(usize::from(index))
+            .ok_or(Error::BadIndex {
+                code: CMD_SATTR,
+                index,
+            })
+    }
+}
+
+impl<'a> Change<'a> {
+    /// The slot which changed.
+    pub fn index(&self) -> u8 {
+        match *self {
+            Change::Set { index, .. } | Change::Clear { index } => index,
+        }
+    }
+
+    /// The `Command::SetAttr` which makes this change.
+    pub fn to_command(&self) -> Command<'a> {
+        match *self {
+            Change::Set { index, attribute } => attribute.to_command(index),
+            Change::Clear { index } => Command::SetAttr {
+                index,
+                key: &[0u8; KEY_LEN],
+                value: &[],
+            },
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for AttributeKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ::serde::Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for AttributeKey {
+    fn deserialize<D>(deserializer: D) -> Result<AttributeKey, D::Error>
+    where
+        D: ::serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(KeyVisitor)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for Attribute {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ::serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("Attribute", 2)?;
+        state.serialize_field("key", &self.key)?;
+        state.serialize_field("value", &ValueRef(&self.value))?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for Attribute {
+    fn deserialize<D>(deserializer: D) -> Result<Attribute, D::Error>
+    where
+        D: ::serde::Deserializer<'de>,
+    {
+        let raw = RawAttribute::deserialize(deserializer)?;
+        Ok(Attribute {
+            key: raw.key,
+            value: raw.value.0,
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for AttributeTable {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ::serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("AttributeTable", 1)?;
+        state.serialize_field("attributes", &EntriesRef(self))?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for AttributeTable {
+    fn deserialize<D>(deserializer: D) -> Result<AttributeTable, D::Error>
+    where
+        D: ::serde::Deserializer<'de>,
+    {
+        RawTable::deserialize(deserializer).map(|raw| raw.attributes.0)
+    }
+}
+
+impl WellKnown {
+    /// All of the well-known attributes.
+    pub const ALL: [WellKnown; 6] = [
+        WellKnown::Board,
+        WellKnown::Arch,
+        WellKnown::AppAddr,
+        WellKnown::BootHash,
+        WellKnown::BootAddr,
+        WellKnown::JlDevice,
+    ];
+
+    /// The key, as a string.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            WellKnown::Board => "board",
+            WellKnown::Arch => "arch",
+            WellKnown::AppAddr => "appaddr",
+            WellKnown::BootHash => "boothash",
+            WellKnown::BootAddr => "bootaddr",
+            WellKnown::JlDevice => "jldevice",
+        }
+    }
+
+    /// The key.
+    pub fn key(&self) -> AttributeKey {
+        let mut bytes = [0u8; KEY_LEN];
+        let name = self.name().as_bytes();
+        bytes[..name.len()].copy_from_slice(name);
+        AttributeKey { bytes }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+fn is_printable(b: u8) -> bool {
+    (0x20..0x7F).contains(&b)
+}
+
+/// Decode a `GetAttr` response. Slots which have been cleared, or never
+/// written (so read back as erased flash), are `None`.
+pub(crate) fn from_slot(key: &[u8], value: &[u8]) -> Result<Option<Attribute>, Error> {
+    if value.is_empty() || key.iter().all(|&b| b == 0x00) || key.iter().all(|&b| b == 0xFF) {
+        Ok(None)
+    } else {
+        AttributeKey::from_bytes(key)
+            .and_then(|key| Attribute::new(key, value))
+            .map(Some)
+    }
+}
+
+#[cfg(feature = "serde")]
+struct KeyVisitor;
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::de::Visitor<'de> for KeyVisitor {
+    type Value = AttributeKey;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at most {} characters of printable ASCII", KEY_LEN)
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<AttributeKey, E>
+    where
+        E: ::serde::de::Error,
+    {
+        AttributeKey::new(value).map_err(E::custom)
+    }
+}
+
+/// Values are written as strings where possible, otherwise as bytes.
+#[cfg(feature = "serde")]
+struct ValueRef<'a>(&'a [u8]);
+
+#[cfg(feature = "serde")]
+impl<'a> ::serde::Serialize for ValueRef<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ::serde::Serializer,
+    {
+        match str::from_utf8(self.0) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => serializer.serialize_bytes(self.0),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+struct Value(heapless::Vec<u8, MAX_ATTR_LEN>);
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for Value {
+    fn deserialize<D>(deserializer: D) -> Result<Value, D::Error>
+    where
+        D: ::serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+#[cfg(feature = "serde")]
+struct ValueVisitor;
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::de::Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a string or bytes, 1 to {} bytes long", MAX_ATTR_LEN)
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Value, E>
+    where
+        E: ::serde::de::Error,
+    {
+        self.visit_bytes(value.as_bytes())
+    }
+
+    fn visit_bytes<E>(self, value: &[u8]) -> Result<Value, E>
+    where
+        E: ::serde::de::Error,
+    {
+        if value.is_empty() {
+            return Err(E::invalid_length(0, &self));
+        }
+        heapless::Vec::from_slice(value)
+            .map(Value)
+            .map_err(|_| E::invalid_length(value.len(), &self))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Value, A::Error>
+    where
+        A: ::serde::de::SeqAccess<'de>,
+    {
+        let mut value = heapless::Vec::new();
+        while let Some(byte) = seq.next_element()? {
+            value
+                .push(byte)
+                .map_err(|_| ::serde::de::Error::invalid_length(value.len() + 1, &self))?;
+        }
+        if value.is_empty() {
+            return Err(::serde::de::Error::invalid_length(0, &self));
+        }
+        Ok(Value(value))
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(::serde::Deserialize)]
+struct RawAttribute {
+    key: AttributeKey,
+    value: Value,
+}
+
+#[cfg(feature = "serde")]
+#[derive(::serde::Deserialize)]
+struct RawEntry {
+    index: u8,
+    key: AttributeKey,
+    value: Value,
+}
+
+#[cfg(feature = "serde")]
+#[derive(::serde::Deserialize)]
+struct RawTable {
+    #[serde(default)]
+    attributes: Entries,
+}
+
+/// The occupied slots, written as a list of `{index, key, value}`.
+#[cfg(feature = "serde")]
+struct EntriesRef<'a>(&'a AttributeTable);
+
+#[cfg(feature = "serde")]
+impl<'a> ::serde::Serialize for EntriesRef<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ::serde::Serializer,
+    {
+        serializer.collect_seq(
+            self.0
+                .iter()
+                .map(|(index, attribute)| EntryRef { index, attribute }),
+        )
+    }
+}
+
+#[cfg(feature = "serde")]
+struct EntryRef<'a> {
+    index: u8,
+    attribute: &'a Attribute,
+}
+
+#[cfg(feature = "serde")]
+impl<'a> ::serde::Serialize for EntryRef<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ::serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+        let mut state = serializer.serialize_struct("Entry", 3)?;
+        state.serialize_field("index", &self.index)?;
+        state.serialize_field("key", &self.attribute.key)?;
+        state.serialize_field("value", &ValueRef(&self.attribute.value))?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(Default)]
+struct Entries(AttributeTable);
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::Deserialize<'de> for Entries {
+    fn deserialize<D>(deserializer: D) -> Result<Entries, D::Error>
+    where
+        D: ::serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(EntriesVisitor)
+    }
+}
+
+#[cfg(feature = "serde")]
+struct EntriesVisitor;
+
+#[cfg(feature = "serde")]
+impl<'de> ::serde::de::Visitor<'de> for EntriesVisitor {
+    type Value = Entries;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "a list of attributes with distinct indices below {}",
+            MAX_INDEX
+        )
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Entries, A::Error>
+    where
+        A: ::serde::de::SeqAccess<'de>,
+    {
+        let mut table = AttributeTable::new();
+        while let Some(entry) = seq.next_element::<RawEntry>()? {
+            if table.get(entry.index).is_some() {
+                return Err(::serde::de::Error::custom(format_args!(
+                    "duplicate attribute index {}",
+                    entry.index
+                )));
+            }
+            let attribute = Attribute {
+                key: entry.key,
+                value: entry.value.0,
+            };
+            table
+                .set(entry.index, attribute)
+                .map_err(::serde::de::Error::custom)?;
+        }
+        Ok(Entries(table))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{ResponseDecoder, ResponseEncoder};
+    use super::*;
+
+    #[test]
+    fn keys() {
+        let key = AttributeKey::new("board").unwrap();
+        assert_eq!(key.as_bytes(), b"board\0\0\0");
+        assert_eq!(key.as_str(), "board");
+        assert_eq!(key.well_known(), Some(WellKnown::Board));
+        assert_eq!(AttributeKey::from_bytes(b"board\0\0\0"), Ok(key));
+        assert_eq!(
+            AttributeKey::try_from("jldevice").unwrap().as_str(),
+            "jldevice"
+        );
+        assert_eq!(AttributeKey::new("custom").unwrap().well_known(), None);
+        assert!(AttributeKey::from_bytes(&[0u8; KEY_LEN])
+            .unwrap()
+            .is_empty());
+
+        assert_eq!(
+            AttributeKey::new("ninechars"),
+            Err(Error::BadLength {
+                code: CMD_SATTR,
+                field: Field::Key,
+                limit: KEY_LEN,
+                actual: 9,
+            })
+        );
+        assert_eq!(
+            AttributeKey::new(""),
+            Err(Error::BadKey {
+                position: 0,
+                byte: 0
+            })
+        );
+        assert_eq!(
+            AttributeKey::new("b\u{e9}"),
+            Err(Error::BadKey {
+                position: 1,
+                byte: 0xC3
+            })
+        );
+        assert_eq!(
+            AttributeKey::new("a\nb"),
+            Err(Error::BadKey {
+                position: 1,
+                byte: b'\n'
+            })
+        );
+        assert_eq!(
+            AttributeKey::from_bytes(b"ab\0cd\0\0\0"),
+            Err(Error::BadKey {
+                position: 3,
+                byte: b'c'
+            })
+        );
+        assert_eq!(
+            AttributeKey::from_bytes(b"a\tb\0\0\0\0\0"),
+            Err(Error::BadKey {
+                position: 1,
+                byte: b'\t'
+            })
+        );
+    }
+
+    #[test]
+    fn well_known_values() {
+        let attr = Attribute::from_strs("appaddr", "0x30000").unwrap();
+        assert_eq!(attr.known_value(), Some(KnownValue::AppAddr(0x30000)));
+        let attr = Attribute::from_strs("bootaddr", "65536").unwrap();
+        assert_eq!(attr.known_value(), Some(KnownValue::BootAddr(0x10000)));
+        let attr = Attribute::from_strs("bootaddr", "lots").unwrap();
+        assert_eq!(attr.known_value(), None);
+        let attr = Attribute::from_strs("arch", "cortex-m4").unwrap();
+        assert_eq!(attr.known_value(), Some(KnownValue::Arch("cortex-m4")));
+        let attr = Attribute::from_strs("misc", "1").unwrap();
+        assert_eq!(attr.known_value(), None);
+        assert_eq!(attr.value_u32(), Some(1));
+
+        let attr = Attribute::address(WellKnown::AppAddr, 0x40000);
+        assert_eq!(attr.key().as_str(), "appaddr");
+        assert_eq!(attr.value(), b"0x40000");
+
+        for known in WellKnown::ALL.iter() {
+            assert_eq!(known.key().well_known(), Some(*known));
+        }
+
+        let long = [b'x'; MAX_ATTR_LEN + 1];
+        assert_eq!(
+            Attribute::new(WellKnown::Board.key(), &long),
+            Err(Error::BadLength {
+                code: CMD_SATTR,
+                field: Field::Value,
+                limit: MAX_ATTR_LEN,
+                actual: MAX_ATTR_LEN + 1,
+            })
+        );
+        assert_eq!(
+            Attribute::new(WellKnown::Board.key(), &[]),
+            Err(Error::BadLength {
+                code: CMD_SATTR,
+                field: Field::Value,
+                limit: MAX_ATTR_LEN,
+                actual: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let attr = Attribute::from_strs("board", "nrf52dk").unwrap();
+        let response = attr.to_response();
+        let mut buffer = [0u8; 128];
+        let len = ResponseEncoder::new(&response)
+            .unwrap()
+            .encode_into(&mut buffer)
+            .unwrap();
+        let mut p = ResponseDecoder::new();
+        let decoded = p.feed(&buffer[..len]).1.unwrap().unwrap();
+        assert_eq!(Attribute::from_response(&decoded), Some(Ok(attr.clone())));
+        assert_eq!(
+            Attribute::from_command(&attr.to_command(3)),
+            Some(Ok((3, attr)))
+        );
+        assert_eq!(Attribute::from_response(&Response::Pong), None);
+    }
+
+    #[test]
+    fn table_diff() {
+        let mut device = AttributeTable::new();
+        device
+            .set(0, Attribute::from_strs("board", "nrf52dk").unwrap())
+            .unwrap();
+        device
+            .set(1, Attribute::address(WellKnown::AppAddr, 0x20000))
+            .unwrap();
+        device
+            .set(5, Attribute::from_strs("old", "x").unwrap())
+            .unwrap();
+
+        let mut desired = device.clone();
+        assert_eq!(desired.diff(&device).count(), 0);
+        assert_eq!(
+            desired.insert(Attribute::address(WellKnown::AppAddr, 0x30000)),
+            Ok(1)
+        );
+        assert_eq!(
+            desired.insert(Attribute::from_strs("arch", "cortex-m4").unwrap()),
+            Ok(2)
+        );
+        desired.clear(5).unwrap();
+        assert_eq!(
+            desired.find(&WellKnown::Arch.key()).map(|(index, _)| index),
+            Some(2)
+        );
+
+        let changes: heapless::Vec<Change, 16> = device.diff(&desired).collect();
+        assert_eq!(
+            changes,
+            [
+                Change::Set {
+                    index: 1,
+                    attribute: desired.get(1).unwrap(),
+                },
+                Change::Set {
+                    index: 2,
+                    attribute: desired.get(2).unwrap(),
+                },
+                Change::Clear { index: 5 },
+            ]
+        );
+        assert_eq!(
+            changes[2].to_command(),
+            Command::SetAttr {
+                index: 5,
+                key: &[0; KEY_LEN],
+                value: &[],
+            }
+        );
+        let mut full = AttributeTable::new();
+        assert_eq!(
+            full.set(MAX_INDEX, Attribute::from_strs("a", "b").unwrap()),
+            Err(Error::BadIndex {
+                code: CMD_SATTR,
+                index: MAX_INDEX,
+            })
+        );
+        for index in 0..MAX_INDEX {
+            full.set(index, Attribute::from_strs("a", "b").unwrap())
+                .unwrap();
+        }
+        assert_eq!(
+            full.insert(Attribute::from_strs("c", "d").unwrap()),
+            Err(Error::BadIndex {
+                code: CMD_SATTR,
+                index: MAX_INDEX,
+            })
+        );
+    }
+
+    #[test]
+    fn slots() {
+        assert_eq!(from_slot(&[0; KEY_LEN], &[]), Ok(None));
+        assert_eq!(from_slot(&[0xFF; KEY_LEN], &[0xFF; 3]), Ok(None));
+        assert_eq!(from_slot(b"board\0\0\0", &[]), Ok(None));
+        assert_eq!(
+            from_slot(b"board\0\0\0", b"x"),
+            Ok(Some(Attribute::from_strs("board", "x").unwrap()))
+        );
+        assert_eq!(
+            from_slot(b"bo\xFFrd\0\0\0", b"x"),
+            Err(Error::BadKey {
+                position: 2,
+                byte: 0xFF
+            })
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn table_serde() {
+        extern crate serde_json;
+        extern crate std;
+        extern crate toml;
+
+        let mut table = AttributeTable::new();
+        table
+            .set(0, Attribute::from_strs("board", "nrf52dk").unwrap())
+            .unwrap();
+        table
+            .set(3, Attribute::address(WellKnown::BootAddr, 0x10000))
+            .unwrap();
+        table
+            .set(
+                4,
+                Attribute::new(AttributeKey::new("blob").unwrap(), &[0xFF, 0x00]).unwrap(),
+            )
+            .unwrap();
+
+        let text = toml::to_string(&table).unwrap();
+        assert_eq!(
+            text,
+            "[[attributes]]\nindex = 0\nkey = \"board\"\nvalue = \"nrf52dk\"\n\n\
+             [[attributes]]\nindex = 3\nkey = \"bootaddr\"\nvalue = \"0x10000\"\n\n\
+             [[attributes]]\nindex = 4\nkey = \"blob\"\nvalue = [255, 0]\n"
+        );
+        assert_eq!(toml::from_str::<AttributeTable>(&text).unwrap(), table);
+
+        let text = serde_json::to_string(&table).unwrap();
+        assert_eq!(
+            serde_json::from_str::<AttributeTable>(&text).unwrap(),
+            table
+        );
+        assert_eq!(
+            serde_json::from_str::<AttributeTable>("{}").unwrap(),
+            AttributeTable::new()
+        );
+
+        let bad = [
+            r#"{"attributes":[{"index":16,"key":"a","value":"b"}]}"#,
+            r#"{"attributes":[{"index":1,"key":"toolongkey","value":"b"}]}"#,
+            r#"{"attributes":[{"index":1,"key":"a","value":""}]}"#,
+            r#"{"attributes":[{"index":1,"key":"a","value":"b"},{"index":1,"key":"c","value":"d"}]}"#,
+        ];
+        for text in bad.iter() {
+            assert!(
+                serde_json::from_str::<AttributeTable>(text).is_err(),
+                "{}",
+                text
+            );
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,1618 @@
+//! Helpers for bootloaders.
+//!
+//! You supply a `FlashBackend` for your chip's internal flash and these
+//! types take care of storing things in it the way the Tock bootloader
+//! does, and of producing the `Response` to send back.
+//!
+//! Each group of commands is carried out by a `Service`. A `Dispatcher`
+//! passes each decoded `Command` to the first `Service` which handles it.
+//! With the `embedded-storage` feature, `NorFlashService` handles the
+//! internal flash commands on any `NorFlash` implementation.
+//! `ExtFlashService` handles the external flash commands on anything which
+//! implements `ExternalFlash`.
+//!
+//! To stop the host erasing the bootloader, give the `Dispatcher` a
+//! `Policy` saying which parts of flash it may read and write.
+//!
+//! A `Supervisor` wraps the `CommandDecoder` and watches the clock, so
+//! half-received commands are thrown away and the bootloader knows when to
+//! give up and boot the application.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::fmt;
+
+#[cfg(feature = "embedded-storage")]
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind};
+
+use super::attr::{self, Attribute, AttributeKey};
+use super::validate;
+use super::{
+    Command, CommandDecoder, Error, Response, BUFFER_LEN, CMD_GATTR, EXT_BLOCK_SIZE, EXT_PAGE_SIZE,
+    INT_PAGE_SIZE, KEY_LEN, MAX_ATTR_LEN, MAX_INDEX,
+};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Access to a chip's internal flash, in `INT_PAGE_SIZE` pages.
+pub trait FlashBackend {
+    /// The error the flash driver produces.
+    type Error;
+
+    /// Fill `buffer` with the flash contents starting at `address`.
+    fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Erase the page starting at `address`.
+    fn erase_page(&mut self, address: u32) -> Result<(), Self::Error>;
+
+    /// Write a whole page, which has been erased, starting at `address`.
+    fn write_page(&mut self, address: u32, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// An external flash chip, such as a SPI flash. It is written in
+/// `EXT_PAGE_SIZE` pages and erased in pages or `EXT_BLOCK_SIZE` blocks.
+/// Addresses start from zero.
+pub trait ExternalFlash {
+    /// Get the chip ready for use, e.g. by waking it up.
+    fn init(&mut self) -> Result<(), ExtFlashError>;
+
+    /// The size of the chip in bytes.
+    fn capacity(&self) -> u32;
+
+    /// Fill `buffer` with the chip's contents starting at `address`.
+    fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), ExtFlashError>;
+
+    /// Erase the block starting at `address`.
+    fn erase_block(&mut self, address: u32) -> Result<(), ExtFlashError>;
+
+    /// Erase the page starting at `address`.
+    fn erase_page(&mut self, address: u32) -> Result<(), ExtFlashError>;
+
+    /// Program the page starting at `address`. Chips which can only program
+    /// erased pages must either erase it first or report `PageError`.
+    fn write_page(&mut self, address: u32, data: &[u8]) -> Result<(), ExtFlashError>;
+}
+
+/// Something went wrong with an `ExternalFlash`.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum ExtFlashError {
+    /// The chip didn't finish in time. Sent as `Response::ExtFlashTimeout`.
+    Timeout,
+    /// The chip failed to erase or program a page. Sent as
+    /// `Response::ExtFlashPageError`.
+    PageError,
+    /// The address isn't on the chip. Sent as `Response::BadAddress`.
+    OutOfRange,
+    /// Anything else. Sent as `Response::InternalError`.
+    Other,
+}
+
+/// Carries out some of the bootloader's commands.
+pub trait Service {
+    /// Carry out `command`, giving the response to send. Returns `None` if
+    /// this service doesn't handle that command.
+    fn handle(&mut self, command: &Command) -> Option<Response<'_>>;
+}
+
+/// Passes each `Command` to the first `Service` which handles it.
+///
+/// `Command::Ping` is answered directly, and commands which no service
+/// handles get `Response::Unknown`. Commands which the `Policy` (if any)
+/// forbids get `Response::BadAddress`.
+pub struct Dispatcher<'a, 'b> {
+    services: &'a mut [&'b mut dyn Service],
+    policy: Option<Policy<'a>>,
+}
+
+/// What the host may do with a region of flash.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub enum Access {
+    /// Nothing.
+    NoAccess,
+    /// Read it, or CRC it.
+    ReadOnly,
+    /// Read, erase and write it.
+    ReadWrite,
+}
+
+/// Which flash a `Region` is in.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Memory {
+    /// The chip's internal flash.
+    Internal,
+    /// The external flash chip.
+    External,
+}
+
+/// A range of flash addresses with the same `Access`.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Region {
+    pub memory: Memory,
+    pub start: u32,
+    pub length: u32,
+    pub access: Access,
+}
+
+/// A table of `Region`s, for checking commands against.
+///
+/// Where regions overlap, the most restrictive one applies. Addresses
+/// outside every region get the default access.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Policy<'a> {
+    regions: &'a [Region],
+    default: Access,
+}
+
+/// Stores the 16 attribute slots in flash, using the same layout as the
+/// Tock bootloader.
+///
+/// Each slot is `SLOT_LEN` bytes: an 8 byte key, a length byte and a 55 byte
+/// value. Slots which have never been written read back as empty.
+pub struct AttributeStore<F> {
+    flash: F,
+    base: u32,
+    slot: [u8; SLOT_LEN],
+}
+
+/// Something went wrong reading or writing the attributes.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum StoreError<E> {
+    /// The `FlashBackend` failed.
+    Flash(E),
+    /// The index, key or value wasn't valid.
+    Protocol(Error),
+    /// The page didn't read back the same as we wrote it.
+    Verify { address: u32 },
+}
+
+/// Handles `ErasePage`, `WritePage`, `ReadRange` and `CrcIntFlash` on a
+/// `NorFlash`.
+///
+/// Addresses in commands are absolute, so `base` is the address at which
+/// the flash starts. As in the Tock bootloader, `WritePage` erases the page
+/// before writing it. Anything the flash can't do, because it's out of
+/// range or the flash's `ERASE_SIZE`, `WRITE_SIZE` or `READ_SIZE` don't
+/// allow it, gets `Response::BadAddress`.
+#[cfg(feature = "embedded-storage")]
+pub struct NorFlashService<F> {
+    flash: F,
+    base: u32,
+    buffer: [u8; BUFFER_LEN - 1],
+}
+
+/// Handles `ExtFlashInit`, `EraseExBlock`, `EraseExPage`, `WriteExPage`,
+/// `ExReadRange` and `CrcExtFlash` on an `ExternalFlash`.
+pub struct ExtFlashService<F> {
+    flash: F,
+    buffer: [u8; BUFFER_LEN - 1],
+}
+
+/// Decodes commands, discarding half-received ones when the host goes
+/// quiet, and decides when the bootloader has waited long enough.
+///
+/// Time is measured in ticks of whatever clock the caller likes, passed in
+/// as `now`. The tick count may wrap.
+pub struct Supervisor {
+    decoder: CommandDecoder,
+    inter_byte_timeout: u32,
+    idle_timeout: Option<u32>,
+    last_byte: u32,
+    last_frame: u32,
+    mid_frame: bool,
+    discarded: u32,
+}
+
+/// What the bootloader should do next, according to the `Supervisor`.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Status {
+    /// Keep waiting for commands.
+    Continue,
+    /// Jump to the application, because nothing has been heard for the
+    /// idle timeout.
+    Boot,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// Where the Tock bootloader keeps its attributes.
+pub const ATTRIBUTES_ADDRESS: u32 = 0x600;
+/// The space each attribute takes in flash.
+pub const SLOT_LEN: usize = 64;
+/// The space all the attributes take in flash.
+pub const ATTRIBUTES_LEN: usize = SLOT_LEN * MAX_INDEX as usize;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a, 'b> Dispatcher<'a, 'b> {
+    /// Try `services` in order.
+    pub fn new(services: &'a mut [&'b mut dyn Service]) -> Dispatcher<'a, 'b> {
+        Dispatcher {
+            services,
+            policy: None,
+        }
+    }
+
+    /// Check every command against `policy` before carrying it out.
+    pub fn set_policy(&mut self, policy: Policy<'a>) {
+        self.policy = Some(policy);
+    }
+
+    /// Carry out `command`, giving the response to send.
+    pub fn dispatch(&mut self, command: &Command) -> Response<'_> {
+        if let Command::Ping = *command {
+            return Response::Pong;
+        }
+        if let Some(ref policy) = self.policy {
+            if !policy.allows(command) {
+                return Response::BadAddress;
+            }
+        }
+        for service in self.services.iter_mut() {
+            if let Some(response) = service.handle(command) {
+                return response;
+            }
+        }
+        Response::Unknown
+    }
+}
+
+impl Supervisor {
+    /// Start supervising at tick `now`. A frame with a gap longer than
+    /// `inter_byte_timeout` is thrown away. If no command arrives for
+    /// `idle_timeout`, `poll` says to boot; pass `None` to wait forever.
+    pub fn new(now: u32, inter_byte_timeout: u32, idle_timeout: Option<u32>) -> Supervisor {
+        Supervisor {
+            decoder: CommandDecoder::new(),
+            inter_byte_timeout,
+            idle_timeout,
+            last_byte: now,
+            last_frame: now,
+            mid_frame: false,
+            discarded: 0,
+        }
+    }
+
+    /// Decode a byte which arrived at tick `now`, as for
+    /// `CommandDecoder::receive`.
+    pub fn receive(&mut self, now: u32, byte: u8) -> Result<Option<Command<'_>>, Error> {
+        self.check_gap(now);
+        self.last_byte = now;
+        let result = self.decoder.receive(byte);
+        match result {
+            Ok(None) => self.mid_frame = true,
+            // Even a bad command shows the host is there
+            Ok(Some(_)) | Err(_) => {
+                self.mid_frame = false;
+                self.last_frame = now;
+            }
+        }
+        result
+    }
+
+    /// Check the timeouts. Call this whenever there's no byte to receive.
+    pub fn poll(&mut self, now: u32) -> Status {
+        self.check_gap(now);
+        match self.idle_timeout {
+            Some(timeout) if !self.mid_frame && now.wrapping_sub(self.last_frame) >= timeout => {
+                Status::Boot
+            }
+            _ => Status::Continue,
+        }
+    }
+
+    /// How many half-received frames have been thrown away.
+    pub fn discarded(&self) -> u32 {
+        self.discarded
+    }
+
+    /// Throw away the frame in progress if the host has gone quiet.
+    fn check_gap(&mut self, now: u32) {
+        if self.mid_frame && now.wrapping_sub(self.last_byte) > self.inter_byte_timeout {
+            self.decoder.reset();
+            self.mid_frame = false;
+            self.discarded = self.discarded.wrapping_add(1);
+        }
+    }
+}
+
+impl Region {
+    /// A region of internal flash.
+    pub const fn internal(start: u32, length: u32, access: Access) -> Region {
+        Region {
+            memory: Memory::Internal,
+            start,
+            length,
+            access,
+        }
+    }
+
+    /// A region of external flash.
+    pub const fn external(start: u32, length: u32, access: Access) -> Region {
+        Region {
+            memory: Memory::External,
+            start,
+            length,
+            access,
+        }
+    }
+
+    /// One past the last address, which may not fit in a `u32`.
+    fn end(&self) -> u64 {
+        u64::from(self.start) + u64::from(self.length)
+    }
+
+    fn contains(&self, address: u64) -> bool {
+        u64::from(self.start) <= address && address < self.end()
+    }
+}
+
+impl<'a> Policy<'a> {
+    /// Apply `regions`, with `default` for anywhere they don't cover.
+    pub const fn new(regions: &'a [Region], default: Access) -> Policy<'a> {
+        Policy { regions, default }
+    }
+
+    /// May the host carry out `command`? Commands which don't touch flash
+    /// are always allowed.
+    pub fn allows(&self, command: &Command) -> bool {
+        use self::Memory::{External, Internal};
+        let (memory, address, length, write) = match *command {
+            Command::ErasePage { address } => (Internal, address, INT_PAGE_SIZE as u32, true),
+            Command::WritePage { address, data } => (Internal, address, data.len() as u32, true),
+            Command::ReadRange { address, length } => (Internal, address, u32::from(length), false),
+            Command::CrcIntFlash { address, length } => (Internal, address, length, false),
+            Command::EraseExBlock { address } => (External, address, EXT_BLOCK_SIZE as u32, true),
+            Command::EraseExPage { address } => (External, address, EXT_PAGE_SIZE as u32, true),
+            Command::WriteExPage { address, data } => (External, address, data.len() as u32, true),
+            Command::ExReadRange { address, length } => {
+                (External, address, u32::from(length), false)
+            }
+            Command::CrcExtFlash { address, length } => (External, address, length, false),
+            _ => return true,
+        };
+        let needed = if write {
+            Access::ReadWrite
+        } else {
+            Access::ReadOnly
+        };
+        self.allows_range(memory, address, length, needed)
+    }
+
+    /// Does every byte in the `length` bytes at `address` have at least
+    /// `needed` access?
+    pub fn allows_range(&self, memory: Memory, address: u32, length: u32, needed: Access) -> bool {
+        let start = u64::from(address);
+        let end = start + u64::from(length);
+        let regions = self
+            .regions
+            .iter()
+            .filter(|region| region.memory == memory && region.length > 0);
+        // Every region the range touches has to allow it...
+        if regions.clone().any(|region| {
+            u64::from(region.start) < end && start < region.end() && region.access < needed
+        }) {
+            return false;
+        }
+        if self.default >= needed {
+            return true;
+        }
+        // ...and if the default doesn't, the regions have to cover all of it
+        let mut covered = start;
+        while covered < end {
+            match regions
+                .clone()
+                .filter(|region| region.contains(covered))
+                .map(Region::end)
+                .max()
+            {
+                Some(region_end) => covered = region_end,
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+impl<F> AttributeStore<F>
+where
+    F: FlashBackend,
+{
+    /// Keep the attributes in `flash`, in the `ATTRIBUTES_LEN` bytes
+    /// starting at `base`.
+    ///
+    /// Panics if `base` isn't page aligned.
+    pub fn new(flash: F, base: u32) -> AttributeStore<F> {
+        assert!(
+            (base as usize) % INT_PAGE_SIZE == 0,
+            "attribute page must be page aligned"
+        );
+        AttributeStore {
+            flash,
+            base,
+            slot: [0u8; SLOT_LEN],
+        }
+    }
+
+    /// Give back the `FlashBackend`.
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    /// Read slot `index` as a `Response::GetAttr`, ready to send. Empty
+    /// slots have an empty value.
+    pub fn get(&mut self, index: u8) -> Result<Response<'_>, StoreError<F::Error>> {
+        let length = self.read_slot(index)?;
+        Ok(Response::GetAttr {
+            key: &self.slot[..KEY_LEN],
+            value: &self.slot[KEY_LEN + 1..KEY_LEN + 1 + length],
+        })
+    }
+
+    /// Read slot `index` as an `Attribute`. Returns `None` if it's empty.
+    pub fn attribute(&mut self, index: u8) -> Result<Option<Attribute>, StoreError<F::Error>> {
+        let length = self.read_slot(index)?;
+        attr::from_slot(
+            &self.slot[..KEY_LEN],
+            &self.slot[KEY_LEN + 1..KEY_LEN + 1 + length],
+        )
+        .map_err(StoreError::Protocol)
+    }
+
+    /// Find the attribute with this key, e.g. so the bootloader can find
+    /// `bootaddr`.
+    pub fn find(&mut self, key: &AttributeKey) -> Result<Option<Attribute>, StoreError<F::Error>> {
+        for index in 0..MAX_INDEX {
+            // Skip slots we can't decode, as other slots may still be fine
+            if let Ok(Some(attribute)) = self.attribute(index) {
+                if attribute.key() == key {
+                    return Ok(Some(attribute));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Write slot `index`, as for `Command::SetAttr`. An all-zero key with
+    /// an empty value clears the slot.
+    ///
+    /// The rest of the page is preserved. If the slot already holds this
+    /// attribute the flash isn't touched.
+    pub fn set(&mut self, index: u8, key: &[u8], value: &[u8]) -> Result<(), StoreError<F::Error>> {
+        validate::command(&Command::SetAttr { index, key, value }).map_err(StoreError::Protocol)?;
+        let address = self.slot_address(index);
+        let page_address = address - (address % INT_PAGE_SIZE as u32);
+        let offset = (address - page_address) as usize;
+
+        let mut page = [0u8; INT_PAGE_SIZE];
+        self.flash
+            .read(page_address, &mut page)
+            .map_err(StoreError::Flash)?;
+        let mut slot = [0u8; SLOT_LEN];
+        slot[..KEY_LEN].copy_from_slice(key);
+        slot[KEY_LEN] = value.len() as u8;
+        slot[KEY_LEN + 1..KEY_LEN + 1 + value.len()].copy_from_slice(value);
+        if page[offset..offset + SLOT_LEN] == slot[..] {
+            return Ok(());
+        }
+        page[offset..offset + SLOT_LEN].copy_from_slice(&slot);
+
+        self.flash
+            .erase_page(page_address)
+            .map_err(StoreError::Flash)?;
+        self.flash
+            .write_page(page_address, &page)
+            .map_err(StoreError::Flash)?;
+
+        let mut check = [0u8; INT_PAGE_SIZE];
+        self.flash
+            .read(page_address, &mut check)
+            .map_err(StoreError::Flash)?;
+        if check[..] != page[..] {
+            return Err(StoreError::Verify {
+                address: page_address,
+            });
+        }
+        Ok(())
+    }
+
+    /// Empty slot `index`.
+    pub fn clear(&mut self, index: u8) -> Result<(), StoreError<F::Error>> {
+        self.set(index, &[0u8; KEY_LEN], &[])
+    }
+
+    /// Read slot `index` into `self.slot`, giving the value's length.
+    fn read_slot(&mut self, index: u8) -> Result<usize, StoreError<F::Error>> {
+        if index >= MAX_INDEX {
+            return Err(StoreError::Protocol(Error::BadIndex {
+                code: CMD_GATTR,
+                index,
+            }));
+        }
+        let address = self.slot_address(index);
+        self.flash
+            .read(address, &mut self.slot)
+            .map_err(StoreError::Flash)?;
+        let length = usize::from(self.slot[KEY_LEN]);
+        // Erased flash has a length of 0xFF
+        Ok(if length > MAX_ATTR_LEN { 0 } else { length })
+    }
+
+    fn slot_address(&self, index: u8) -> u32 {
+        self.base + u32::from(index) * SLOT_LEN as u32
+    }
+}
+
+impl<F> Service for AttributeStore<F>
+where
+    F: FlashBackend,
+{
+    /// Handles `Command::SetAttr` and `Command::GetAttr`.
+    fn handle(&mut self, command: &Command) -> Option<Response<'_>> {
+        match *command {
+            Command::SetAttr { index, key, value } => Some(match self.set(index, key, value) {
+                Ok(()) => Response::Ok,
+                Err(e) => failure(&e),
+            }),
+            Command::GetAttr { index } => Some(match self.get(index) {
+                Ok(response) => response,
+                Err(e) => failure(&e),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "embedded-storage")]
+impl<F> NorFlashService<F>
+where
+    F: NorFlash,
+{
+    /// Serve `flash`, which starts at address `base`.
+    pub fn new(flash: F, base: u32) -> NorFlashService<F> {
+        NorFlashService {
+            flash,
+            base,
+            buffer: [0u8; BUFFER_LEN - 1],
+        }
+    }
+
+    /// Give back the flash.
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    fn erase_page(&mut self, address: u32) -> Result<(), Response<'static>> {
+        let offset = self.offset(address, INT_PAGE_SIZE)?;
+        // We can't erase part of a sector without losing the rest of it
+        if INT_PAGE_SIZE % F::ERASE_SIZE != 0 || (offset as usize) % F::ERASE_SIZE != 0 {
+            return Err(Response::BadAddress);
+        }
+        self.flash
+            .erase(offset, offset + INT_PAGE_SIZE as u32)
+            .map_err(|e| nor_failure(&e))
+    }
+
+    fn write_page(&mut self, address: u32, data: &[u8]) -> Result<(), Response<'static>> {
+        if INT_PAGE_SIZE % F::WRITE_SIZE != 0 {
+            return Err(Response::BadAddress);
+        }
+        self.erase_page(address)?;
+        let offset = self.offset(address, data.len())?;
+        self.flash.write(offset, data).map_err(|e| nor_failure(&e))
+    }
+
+    fn read_range(&mut self, address: u32, length: u16) -> Result<&[u8], Response<'static>> {
+        let length = usize::from(length);
+        if length > self.buffer.len() {
+            return Err(Response::BadArguments);
+        }
+        let offset = self.offset(address, length)?;
+        self.read(offset, length)?;
+        Ok(&self.buffer[..length])
+    }
+
+    fn crc(&mut self, address: u32, length: u32) -> Result<u32, Response<'static>> {
+        let mut offset = self.offset(address, length as usize)?;
+        let mut remaining = length as usize;
+        // Read in chunks the flash can cope with
+        let chunk = self.buffer.len() - (self.buffer.len() % F::READ_SIZE);
+        if chunk == 0 {
+            // Not even one read fits in our buffer
+            return Err(Response::BadAddress);
+        }
+        let mut crc = Crc32::new();
+        while remaining > 0 {
+            let len = remaining.min(chunk);
+            self.read(offset, len)?;
+            crc.update(&self.buffer[..len]);
+            offset += len as u32;
+            remaining -= len;
+        }
+        Ok(crc.finish())
+    }
+
+    /// Read `length` bytes into the start of `self.buffer`.
+    fn read(&mut self, offset: u32, length: usize) -> Result<(), Response<'static>> {
+        if (offset as usize) % F::READ_SIZE != 0 || length % F::READ_SIZE != 0 {
+            return Err(Response::BadAddress);
+        }
+        self.flash
+            .read(offset, &mut self.buffer[..length])
+            .map_err(|e| nor_failure(&e))
+    }
+
+    /// Convert an address to an offset into the flash, checking that
+    /// `length` bytes from there are all in the flash.
+    fn offset(&self, address: u32, length: usize) -> Result<u32, Response<'static>> {
+        let offset = address.checked_sub(self.base).ok_or(Response::BadAddress)?;
+        match (offset as usize).checked_add(length) {
+            Some(end) if end <= self.flash.capacity() => Ok(offset),
+            _ => Err(Response::BadAddress),
+        }
+    }
+}
+
+#[cfg(feature = "embedded-storage")]
+impl<F> Service for NorFlashService<F>
+where
+    F: NorFlash,
+{
+    /// Handles `ErasePage`, `WritePage`, `ReadRange` and `CrcIntFlash`.
+    fn handle(&mut self, command: &Command) -> Option<Response<'_>> {
+        let result = match *command {
+            Command::ErasePage { address } => self.erase_page(address).map(|()| Response::Ok),
+            Command::WritePage { address, data } => {
+                self.write_page(address, data).map(|()| Response::Ok)
+            }
+            Command::ReadRange { address, length } => self
+                .read_range(address, length)
+                .map(|data| Response::ReadRange { data }),
+            Command::CrcIntFlash { address, length } => self
+                .crc(address, length)
+                .map(|crc| Response::CrcIntFlash { crc }),
+            _ => return None,
+        };
+        Some(result.unwrap_or_else(|failure| failure))
+    }
+}
+
+impl<F> ExtFlashService<F>
+where
+    F: ExternalFlash,
+{
+    /// Serve the external flash chip `flash`.
+    pub fn new(flash: F) -> ExtFlashService<F> {
+        ExtFlashService {
+            flash,
+            buffer: [0u8; BUFFER_LEN - 1],
+        }
+    }
+
+    /// Give back the flash chip.
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    fn read_range(&mut self, address: u32, length: u16) -> Result<&[u8], ExtFlashError> {
+        let length = usize::from(length);
+        self.check(address, length)?;
+        let data = &mut self.buffer[..length];
+        self.flash.read(address, data)?;
+        Ok(data)
+    }
+
+    fn crc(&mut self, address: u32, length: u32) -> Result<u32, ExtFlashError> {
+        self.check(address, length as usize)?;
+        let mut crc = Crc32::new();
+        let mut address = address;
+        let mut remaining = length as usize;
+        while remaining > 0 {
+            let len = remaining.min(self.buffer.len());
+            self.flash.read(address, &mut self.buffer[..len])?;
+            crc.update(&self.buffer[..len]);
+            address += len as u32;
+            remaining -= len;
+        }
+        Ok(crc.finish())
+    }
+
+    /// Check that `length` bytes from `address` are all on the chip.
+    fn check(&self, address: u32, length: usize) -> Result<(), ExtFlashError> {
+        match (address as usize).checked_add(length) {
+            Some(end) if end <= self.flash.capacity() as usize => Ok(()),
+            _ => Err(ExtFlashError::OutOfRange),
+        }
+    }
+}
+
+impl<F> Service for ExtFlashService<F>
+where
+    F: ExternalFlash,
+{
+    /// Handles the external flash commands.
+    fn handle(&mut self, command: &Command) -> Option<Response<'_>> {
+        let result = match *command {
+            Command::ExtFlashInit => self.flash.init().map(|()| Response::Ok),
+            Command::EraseExBlock { address } => self
+                .check(address, EXT_BLOCK_SIZE)
+                .and_then(|()| self.flash.erase_block(address))
+                .map(|()| Response::Ok),
+            Command::EraseExPage { address } => self
+                .check(address, EXT_PAGE_SIZE)
+                .and_then(|()| self.flash.erase_page(address))
+                .map(|()| Response::Ok),
+            Command::WriteExPage { address, data } => self
+                .check(address, data.len())
+                .and_then(|()| self.flash.write_page(address, data))
+                .map(|()| Response::Ok),
+            Command::ExReadRange { length, .. } if usize::from(length) > self.buffer.len() => {
+                return Some(Response::BadArguments);
+            }
+            Command::ExReadRange { address, length } => self
+                .read_range(address, length)
+                .map(|data| Response::ExReadRange { data }),
+            Command::CrcExtFlash { address, length } => self
+                .crc(address, length)
+                .map(|crc| Response::CrcExtFlash { crc }),
+            _ => return None,
+        };
+        Some(result.unwrap_or_else(|e| e.as_response()))
+    }
+}
+
+impl ExtFlashError {
+    /// The response to send the host.
+    pub fn as_response(&self) -> Response<'static> {
+        match *self {
+            ExtFlashError::Timeout => Response::ExtFlashTimeout,
+            ExtFlashError::PageError => Response::ExtFlashPageError,
+            ExtFlashError::OutOfRange => Response::BadAddress,
+            ExtFlashError::Other => Response::InternalError,
+        }
+    }
+}
+
+/// The CRC-32 (as used by zlib) of `data`, which is what `CrcIntFlash` and
+/// `CrcExtFlash` report.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+impl<E> From<Error> for StoreError<E> {
+    fn from(error: Error) -> StoreError<E> {
+        StoreError::Protocol(error)
+    }
+}
+
+impl<E> fmt::Display for StoreError<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StoreError::Flash(ref e) => write!(f, "flash error: {}", e),
+            StoreError::Protocol(ref e) => write!(f, "protocol error: {}", e),
+            StoreError::Verify { address } => {
+                write!(f, "page at 0x{:08x} failed to verify", address)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E> ::std::error::Error for StoreError<E> where E: fmt::Debug + fmt::Display {}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// A CRC-32 computed a piece at a time.
+struct Crc32 {
+    value: u32,
+}
+
+impl Crc32 {
+    fn new() -> Crc32 {
+        Crc32 { value: 0xFFFF_FFFF }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.value ^= u32::from(byte);
+            for _ in 0..8 {
+                let mask = (self.value & 1).wrapping_neg();
+                self.value = (self.value >> 1) ^ (0xEDB8_8320 & mask);
+            }
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.value
+    }
+}
+
+/// The response to send when the `NorFlash` fails.
+#[cfg(feature = "embedded-storage")]
+fn nor_failure<E: NorFlashError>(error: &E) -> Response<'static> {
+    match error.kind() {
+        NorFlashErrorKind::NotAligned | NorFlashErrorKind::OutOfBounds => Response::BadAddress,
+        _ => Response::InternalError,
+    }
+}
+
+/// The response to send when the store fails.
+fn failure<E>(error: &StoreError<E>) -> Response<'static> {
+    match *error {
+        StoreError::Protocol(_) => Response::BadArguments,
+        StoreError::Flash(_) | StoreError::Verify { .. } => Response::InternalError,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Internal flash in RAM. Like real NOR flash, writes can only clear
+    /// bits, so forgetting to erase shows up as corruption.
+    struct RamFlash {
+        data: [u8; 4 * INT_PAGE_SIZE],
+        erases: usize,
+        broken: bool,
+    }
+
+    impl RamFlash {
+        fn new() -> RamFlash {
+            RamFlash {
+                data: [0xFF; 4 * INT_PAGE_SIZE],
+                erases: 0,
+                broken: false,
+            }
+        }
+    }
+
+    impl FlashBackend for RamFlash {
+        type Error = ();
+
+        fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), ()> {
+            let start = address as usize;
+            buffer.copy_from_slice(&self.data[start..start + buffer.len()]);
+            Ok(())
+        }
+
+        fn erase_page(&mut self, address: u32) -> Result<(), ()> {
+            let start = address as usize;
+            assert_eq!(start % INT_PAGE_SIZE, 0);
+            self.erases += 1;
+            if self.broken {
+                return Err(());
+            }
+            for byte in self.data[start..start + INT_PAGE_SIZE].iter_mut() {
+                *byte = 0xFF;
+            }
+            Ok(())
+        }
+
+        fn write_page(&mut self, address: u32, data: &[u8]) -> Result<(), ()> {
+            let start = address as usize;
+            assert_eq!(start % INT_PAGE_SIZE, 0);
+            assert_eq!(data.len(), INT_PAGE_SIZE);
+            for (byte, new) in self.data[start..].iter_mut().zip(data) {
+                *byte &= *new;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn empty_flash() {
+        let mut store = AttributeStore::new(RamFlash::new(), INT_PAGE_SIZE as u32);
+        assert_eq!(
+            store.get(0),
+            Ok(Response::GetAttr {
+                key: &[0xFF; KEY_LEN],
+                value: &[],
+            })
+        );
+        assert_eq!(store.attribute(15), Ok(None));
+        assert_eq!(
+            store.get(16),
+            Err(StoreError::Protocol(Error::BadIndex {
+                code: CMD_GATTR,
+                index: 16,
+            }))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "page aligned")]
+    fn unaligned_store() {
+        AttributeStore::new(RamFlash::new(), ATTRIBUTES_ADDRESS + 4);
+    }
+
+    #[test]
+    fn set_preserves_neighbours() {
+        let flash = RamFlash::new();
+        let mut store = AttributeStore::new(flash, INT_PAGE_SIZE as u32);
+        store.set(0, b"board\0\0\0", b"hail").unwrap();
+        store.set(9, b"appaddr\0", b"0x30000").unwrap();
+        store.set(1, b"arch\0\0\0\0", b"cortex-m4").unwrap();
+        // Slot 9 is on the second page
+        assert_eq!(store.flash.erases, 3);
+
+        assert_eq!(
+            store.get(0),
+            Ok(Response::GetAttr {
+                key: b"board\0\0\0",
+                value: b"hail",
+            })
+        );
+        let appaddr = store
+            .find(&attr::WellKnown::AppAddr.key())
+            .unwrap()
+            .unwrap();
+        assert_eq!(appaddr.value(), b"0x30000");
+
+        // Writing the same thing again doesn't wear the flash
+        store.set(1, b"arch\0\0\0\0", b"cortex-m4").unwrap();
+        assert_eq!(store.flash.erases, 3);
+
+        store.clear(0).unwrap();
+        assert_eq!(store.attribute(0), Ok(None));
+        assert_eq!(
+            store.attribute(1),
+            Ok(Some(Attribute::from_strs("arch", "cortex-m4").unwrap()))
+        );
+
+        // The layout matches the Tock bootloader
+        let flash = store.release();
+        let slot = &flash.data[INT_PAGE_SIZE + SLOT_LEN..INT_PAGE_SIZE + 2 * SLOT_LEN];
+        assert_eq!(&slot[..KEY_LEN], b"arch\0\0\0\0");
+        assert_eq!(slot[KEY_LEN], 9);
+        assert_eq!(&slot[KEY_LEN + 1..KEY_LEN + 10], b"cortex-m4");
+    }
+
+    #[test]
+    fn handle_commands() {
+        let mut store = AttributeStore::new(RamFlash::new(), 0);
+        let set = Command::SetAttr {
+            index: 2,
+            key: b"jldevice",
+            value: b"ATSAM4LC8C",
+        };
+        assert_eq!(store.handle(&set), Some(Response::Ok));
+        assert_eq!(
+            store.handle(&Command::GetAttr { index: 2 }),
+            Some(Response::GetAttr {
+                key: b"jldevice",
+                value: b"ATSAM4LC8C",
+            })
+        );
+        let bad = Command::SetAttr {
+            index: 2,
+            key: b"jldevice",
+            value: &[],
+        };
+        assert_eq!(store.handle(&bad), Some(Response::BadArguments));
+        assert_eq!(store.handle(&Command::Ping), None);
+
+        store.flash.broken = true;
+        let set = Command::SetAttr {
+            index: 3,
+            key: b"board\0\0\0",
+            value: b"imix",
+        };
+        assert_eq!(store.handle(&set), Some(Response::InternalError));
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn dispatch() {
+        let mut store = AttributeStore::new(RamFlash::new(), 0);
+        let mut services: [&mut dyn Service; 1] = [&mut store];
+        let mut dispatcher = Dispatcher::new(&mut services);
+        assert_eq!(dispatcher.dispatch(&Command::Ping), Response::Pong);
+        assert_eq!(dispatcher.dispatch(&Command::Reset), Response::Unknown);
+        assert_eq!(
+            dispatcher.dispatch(&Command::GetAttr { index: 0 }),
+            Response::GetAttr {
+                key: &[0xFF; KEY_LEN],
+                value: &[],
+            }
+        );
+    }
+
+    /// Bootloader in the first 64 KiB, apps after that, external flash all
+    /// writable.
+    const REGIONS: [Region; 3] = [
+        Region::internal(0x0, 0x1_0000, Access::ReadOnly),
+        Region::internal(0x1_0000, 0x7_0000, Access::ReadWrite),
+        Region::external(0x0, 0x10_0000, Access::ReadWrite),
+    ];
+
+    #[test]
+    fn policy() {
+        let policy = Policy::new(&REGIONS, Access::NoAccess);
+        let page = [0u8; INT_PAGE_SIZE];
+        assert!(!policy.allows(&Command::ErasePage { address: 0x0 }));
+        assert!(policy.allows(&Command::ErasePage { address: 0x1_0000 }));
+        assert!(policy.allows(&Command::ReadRange {
+            address: 0x0,
+            length: 16,
+        }));
+        assert!(!policy.allows(&Command::WritePage {
+            address: 0xFE00,
+            data: &page,
+        }));
+        assert!(policy.allows(&Command::WritePage {
+            address: 0x7_FE00,
+            data: &page,
+        }));
+        // Off the end of the flash
+        assert!(!policy.allows(&Command::WritePage {
+            address: 0x8_0000,
+            data: &page,
+        }));
+        // External flash has its own rules
+        assert!(policy.allows(&Command::EraseExBlock { address: 0x0 }));
+        assert!(!policy.allows(&Command::EraseExPage { address: 0x10_0000 }));
+        // Commands which don't touch flash are fine
+        assert!(policy.allows(&Command::Ping));
+        assert!(policy.allows(&Command::GetAttr { index: 0 }));
+    }
+
+    #[test]
+    fn policy_straddles() {
+        let policy = Policy::new(&REGIONS, Access::NoAccess);
+        // Reading across the edge is fine, writing isn't
+        assert!(policy.allows_range(Memory::Internal, 0xFF00, 0x200, Access::ReadOnly));
+        assert!(!policy.allows_range(Memory::Internal, 0xFF00, 0x200, Access::ReadWrite));
+        // Starts in a region but runs past the end of every region
+        assert!(!policy.allows(&Command::CrcIntFlash {
+            address: 0x7_FFF0,
+            length: 0x20,
+        }));
+        assert!(policy.allows(&Command::CrcIntFlash {
+            address: 0x0,
+            length: 0x8_0000,
+        }));
+
+        // The most restrictive of overlapping regions wins
+        let regions = [
+            Region::internal(0x0, 0x8_0000, Access::ReadWrite),
+            Region::internal(0x600, 0x400, Access::ReadOnly),
+            Region::internal(0x600, 0x400, Access::ReadWrite),
+        ];
+        let policy = Policy::new(&regions, Access::NoAccess);
+        assert!(!policy.allows(&Command::ErasePage { address: 0x800 }));
+        assert!(policy.allows(&Command::ErasePage { address: 0xA00 }));
+    }
+
+    #[test]
+    fn policy_overflow() {
+        let regions = [
+            Region::internal(0x0, 0x100, Access::NoAccess),
+            Region::internal(0xFFFF_F000, 0x1000, Access::ReadOnly),
+        ];
+        let policy = Policy::new(&regions, Access::ReadWrite);
+        // This would wrap round to address 0 if we weren't careful
+        assert!(policy.allows(&Command::CrcIntFlash {
+            address: 0xFFFF_FF00,
+            length: 0x200,
+        }));
+        assert!(!policy.allows(&Command::ErasePage {
+            address: 0xFFFF_FE00,
+        }));
+        let policy = Policy::new(&regions, Access::NoAccess);
+        assert!(policy.allows(&Command::CrcIntFlash {
+            address: 0xFFFF_F000,
+            length: 0x1000,
+        }));
+        assert!(!policy.allows(&Command::CrcIntFlash {
+            address: 0xFFFF_FF00,
+            length: 0x200,
+        }));
+        assert!(!policy.allows(&Command::CrcIntFlash {
+            address: 0xFFFF_FFFF,
+            length: u32::MAX,
+        }));
+    }
+
+    #[test]
+    fn dispatch_with_policy() {
+        let mut store = AttributeStore::new(RamFlash::new(), 0);
+        let mut chip = SimChip::new();
+        chip.awake = true;
+        let mut external = ExtFlashService::new(chip);
+        let mut services: [&mut dyn Service; 2] = [&mut store, &mut external];
+        let mut dispatcher = Dispatcher::new(&mut services);
+        let regions = [Region::external(0x0, 0x1000, Access::ReadOnly)];
+        dispatcher.set_policy(Policy::new(&regions, Access::NoAccess));
+        assert_eq!(
+            dispatcher.dispatch(&Command::EraseExPage { address: 0x100 }),
+            Response::BadAddress
+        );
+        assert_eq!(
+            dispatcher.dispatch(&Command::ExReadRange {
+                address: 0x100,
+                length: 1,
+            }),
+            Response::ExReadRange { data: &[0xFF] }
+        );
+        assert_eq!(dispatcher.dispatch(&Command::Ping), Response::Pong);
+        let set = Command::SetAttr {
+            index: 0,
+            key: b"board\0\0\0",
+            value: b"hail",
+        };
+        assert_eq!(dispatcher.dispatch(&set), Response::Ok);
+    }
+
+    #[test]
+    fn supervisor_discards_stale_frames() {
+        let mut supervisor = Supervisor::new(0, 10, None);
+        // Half of an ErasePage, then a long pause
+        for (tick, byte) in [0x00u8, 0x02].iter().enumerate() {
+            assert_eq!(supervisor.receive(tick as u32, *byte), Ok(None));
+        }
+        assert_eq!(supervisor.poll(5), Status::Continue);
+        assert_eq!(supervisor.discarded(), 0);
+        assert_eq!(supervisor.poll(20), Status::Continue);
+        assert_eq!(supervisor.discarded(), 1);
+        // A whole command goes through cleanly afterwards
+        let mut result = None;
+        for byte in [0x00u8, 0x04, 0x00, 0x00, 0xFC, 0x06].iter() {
+            result = supervisor.receive(21, *byte).map(|c| c.is_some()).ok();
+        }
+        assert_eq!(result, Some(true));
+
+        // An escape left hanging doesn't affect the next frame, even when
+        // the gap is only noticed on the next byte
+        assert_eq!(supervisor.receive(30, 0xFC), Ok(None));
+        assert_eq!(supervisor.receive(100, 0xFC), Ok(None));
+        assert_eq!(supervisor.receive(101, 0x01), Ok(Some(Command::Ping)));
+        assert_eq!(supervisor.discarded(), 2);
+    }
+
+    #[test]
+    fn supervisor_boots_when_idle() {
+        let start = u32::MAX - 150;
+        let mut supervisor = Supervisor::new(start, 10, Some(100));
+        assert_eq!(supervisor.poll(start + 99), Status::Continue);
+        assert_eq!(supervisor.receive(start + 99, 0xFC), Ok(None));
+        assert_eq!(
+            supervisor.receive(start + 100, 0x01),
+            Ok(Some(Command::Ping))
+        );
+        // The tick count wraps, and the clock restarted at the Ping
+        assert_eq!(supervisor.poll(start.wrapping_add(199)), Status::Continue);
+        assert_eq!(supervisor.poll(start.wrapping_add(200)), Status::Boot);
+
+        // A frame in progress holds off booting until it goes stale
+        let mut supervisor = Supervisor::new(0, 10, Some(100));
+        assert_eq!(supervisor.receive(95, 0x00), Ok(None));
+        assert_eq!(supervisor.poll(100), Status::Continue);
+        assert_eq!(supervisor.poll(106), Status::Boot);
+        assert_eq!(supervisor.discarded(), 1);
+
+        let mut supervisor = Supervisor::new(0, 10, None);
+        assert_eq!(supervisor.poll(u32::MAX), Status::Continue);
+    }
+
+    /// A SPI flash chip. It doesn't answer until it's been initialised,
+    /// and reports a page error if asked to program a page which isn't
+    /// erased.
+    struct SimChip {
+        data: [u8; 4 * EXT_BLOCK_SIZE],
+        awake: bool,
+        worn_out: Option<u32>,
+    }
+
+    impl SimChip {
+        fn new() -> SimChip {
+            SimChip {
+                data: [0xFF; 4 * EXT_BLOCK_SIZE],
+                awake: false,
+                worn_out: None,
+            }
+        }
+
+        fn ready(&self) -> Result<(), ExtFlashError> {
+            if self.awake {
+                Ok(())
+            } else {
+                Err(ExtFlashError::Timeout)
+            }
+        }
+
+        fn erase(&mut self, address: u32, len: usize) -> Result<(), ExtFlashError> {
+            self.ready()?;
+            let start = address as usize;
+            for byte in self.data[start..start + len].iter_mut() {
+                *byte = 0xFF;
+            }
+            Ok(())
+        }
+    }
+
+    impl ExternalFlash for SimChip {
+        fn init(&mut self) -> Result<(), ExtFlashError> {
+            self.awake = true;
+            Ok(())
+        }
+
+        fn capacity(&self) -> u32 {
+            self.data.len() as u32
+        }
+
+        fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), ExtFlashError> {
+            self.ready()?;
+            let start = address as usize;
+            buffer.copy_from_slice(&self.data[start..start + buffer.len()]);
+            Ok(())
+        }
+
+        fn erase_block(&mut self, address: u32) -> Result<(), ExtFlashError> {
+            self.erase(address, EXT_BLOCK_SIZE)
+        }
+
+        fn erase_page(&mut self, address: u32) -> Result<(), ExtFlashError> {
+            self.erase(address, EXT_PAGE_SIZE)
+        }
+
+        fn write_page(&mut self, address: u32, data: &[u8]) -> Result<(), ExtFlashError> {
+            self.ready()?;
+            let start = address as usize;
+            let page = &mut self.data[start..start + data.len()];
+            if self.worn_out == Some(address) || page.iter().any(|&b| b != 0xFF) {
+                return Err(ExtFlashError::PageError);
+            }
+            page.copy_from_slice(data);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn external_flash() {
+        let mut service = ExtFlashService::new(SimChip::new());
+        let page = [0x5A; EXT_PAGE_SIZE];
+        let write = Command::WriteExPage {
+            address: 0x100,
+            data: &page,
+        };
+        assert_eq!(service.handle(&write), Some(Response::ExtFlashTimeout));
+        assert_eq!(service.handle(&Command::ExtFlashInit), Some(Response::Ok));
+        assert_eq!(service.handle(&write), Some(Response::Ok));
+        assert_eq!(
+            service.handle(&Command::ExReadRange {
+                address: 0xFE,
+                length: 4,
+            }),
+            Some(Response::ExReadRange {
+                data: &[0xFF, 0xFF, 0x5A, 0x5A],
+            })
+        );
+        assert_eq!(
+            service.handle(&Command::CrcExtFlash {
+                address: 0x100,
+                length: EXT_PAGE_SIZE as u32,
+            }),
+            Some(Response::CrcExtFlash { crc: crc32(&page) })
+        );
+
+        // Programming it again without an erase fails
+        assert_eq!(service.handle(&write), Some(Response::ExtFlashPageError));
+        assert_eq!(
+            service.handle(&Command::EraseExPage { address: 0x100 }),
+            Some(Response::Ok)
+        );
+        assert_eq!(service.handle(&write), Some(Response::Ok));
+        assert_eq!(
+            service.handle(&Command::EraseExBlock { address: 0 }),
+            Some(Response::Ok)
+        );
+        let chip = service.release();
+        assert!(chip.data.iter().all(|&b| b == 0xFF));
+
+        // Whole chip CRC, read in several chunks
+        let crc = crc32(&chip.data);
+        let mut service = ExtFlashService::new(chip);
+        assert_eq!(
+            service.handle(&Command::CrcExtFlash {
+                address: 0,
+                length: 4 * EXT_BLOCK_SIZE as u32,
+            }),
+            Some(Response::CrcExtFlash { crc })
+        );
+        assert_eq!(
+            service.handle(&Command::ReadRange {
+                address: 0,
+                length: 1
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn external_flash_failures() {
+        let mut chip = SimChip::new();
+        chip.worn_out = Some(0x200);
+        let mut service = ExtFlashService::new(chip);
+        service.handle(&Command::ExtFlashInit);
+        let page = [0u8; EXT_PAGE_SIZE];
+        assert_eq!(
+            service.handle(&Command::WriteExPage {
+                address: 0x200,
+                data: &page,
+            }),
+            Some(Response::ExtFlashPageError)
+        );
+        let end = 4 * EXT_BLOCK_SIZE as u32;
+        let bad = [
+            Command::EraseExBlock { address: end },
+            Command::EraseExPage { address: end },
+            Command::WriteExPage {
+                address: end - 0x80,
+                data: &page,
+            },
+            Command::ExReadRange {
+                address: end - 1,
+                length: 2,
+            },
+            Command::CrcExtFlash {
+                address: 1,
+                length: u32::MAX,
+            },
+        ];
+        for command in bad.iter() {
+            assert_eq!(
+                service.handle(command),
+                Some(Response::BadAddress),
+                "{:?}",
+                command
+            );
+        }
+        assert_eq!(
+            service.handle(&Command::ExReadRange {
+                address: 0,
+                length: BUFFER_LEN as u16,
+            }),
+            Some(Response::BadArguments)
+        );
+    }
+
+    #[cfg(feature = "embedded-storage")]
+    mod nor_flash {
+        use super::super::*;
+        use embedded_storage::nor_flash::{ErrorType, ReadNorFlash};
+
+        const CAPACITY: usize = 8192;
+
+        /// NOR flash in RAM, with the given erase and read sizes.
+        struct MockNor<const ERASE: usize, const READ: usize = 1> {
+            data: [u8; CAPACITY],
+        }
+
+        impl<const ERASE: usize, const READ: usize> MockNor<ERASE, READ> {
+            fn new() -> MockNor<ERASE, READ> {
+                let mut data = [0xFF; CAPACITY];
+                for (i, byte) in data.iter_mut().enumerate() {
+                    *byte = i as u8;
+                }
+                MockNor { data }
+            }
+        }
+
+        impl<const ERASE: usize, const READ: usize> ErrorType for MockNor<ERASE, READ> {
+            type Error = NorFlashErrorKind;
+        }
+
+        impl<const ERASE: usize, const READ: usize> ReadNorFlash for MockNor<ERASE, READ> {
+            const READ_SIZE: usize = READ;
+
+            fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), NorFlashErrorKind> {
+                let start = offset as usize;
+                bytes.copy_from_slice(&self.data[start..start + bytes.len()]);
+                Ok(())
+            }
+
+            fn capacity(&self) -> usize {
+                CAPACITY
+            }
+        }
+
+        impl<const ERASE: usize, const READ: usize> NorFlash for MockNor<ERASE, READ> {
+            const WRITE_SIZE: usize = 4;
+            const ERASE_SIZE: usize = ERASE;
+
+            fn erase(&mut self, from: u32, to: u32) -> Result<(), NorFlashErrorKind> {
+                if (from as usize) % ERASE != 0 || (to as usize) % ERASE != 0 {
+                    return Err(NorFlashErrorKind::NotAligned);
+                }
+                for byte in self.data[from as usize..to as usize].iter_mut() {
+                    *byte = 0xFF;
+                }
+                Ok(())
+            }
+
+            fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), NorFlashErrorKind> {
+                let start = offset as usize;
+                for (byte, new) in self.data[start..].iter_mut().zip(bytes) {
+                    // NOR flash can only clear bits
+                    assert_eq!(*byte, 0xFF);
+                    *byte = *new;
+                }
+                Ok(())
+            }
+        }
+
+        const BASE: u32 = 0x1_0000;
+
+        #[test]
+        fn pages() {
+            let mut service = NorFlashService::new(MockNor::<256>::new(), BASE);
+            let page = [0xA5; INT_PAGE_SIZE];
+            let write = Command::WritePage {
+                address: BASE + 0x200,
+                data: &page,
+            };
+            assert_eq!(service.handle(&write), Some(Response::Ok));
+            // Written over old data, so it must have been erased first
+            assert_eq!(
+                service.handle(&Command::ReadRange {
+                    address: BASE + 0x3FE,
+                    length: 4,
+                }),
+                Some(Response::ReadRange {
+                    data: &[0xA5, 0xA5, 0x00, 0x01],
+                })
+            );
+            assert_eq!(
+                service.handle(&Command::CrcIntFlash {
+                    address: BASE + 0x200,
+                    length: INT_PAGE_SIZE as u32,
+                }),
+                Some(Response::CrcIntFlash { crc: crc32(&page) })
+            );
+            assert_eq!(
+                service.handle(&Command::ErasePage {
+                    address: BASE + 0x200,
+                }),
+                Some(Response::Ok)
+            );
+            assert_eq!(
+                service.handle(&Command::ReadRange {
+                    address: BASE + 0x200,
+                    length: 2,
+                }),
+                Some(Response::ReadRange {
+                    data: &[0xFF, 0xFF]
+                })
+            );
+            assert_eq!(service.handle(&Command::Ping), None);
+
+            // The whole flash, in several chunks
+            let flash = service.release();
+            let crc = crc32(&flash.data);
+            let mut service = NorFlashService::new(flash, BASE);
+            assert_eq!(
+                service.handle(&Command::CrcIntFlash {
+                    address: BASE,
+                    length: CAPACITY as u32,
+                }),
+                Some(Response::CrcIntFlash { crc })
+            );
+        }
+
+        #[test]
+        fn bad_addresses() {
+            let mut service = NorFlashService::new(MockNor::<512>::new(), BASE);
+            let bad = [
+                Command::ErasePage { address: 0 },
+                Command::ErasePage {
+                    address: BASE + CAPACITY as u32,
+                },
+                Command::ReadRange {
+                    address: BASE - 1,
+                    length: 2,
+                },
+                Command::ReadRange {
+                    address: BASE + CAPACITY as u32 - 1,
+                    length: 2,
+                },
+                Command::CrcIntFlash {
+                    address: BASE,
+                    length: u32::MAX,
+                },
+                Command::CrcIntFlash {
+                    address: u32::MAX,
+                    length: 1,
+                },
+            ];
+            for command in bad.iter() {
+                assert_eq!(
+                    service.handle(command),
+                    Some(Response::BadAddress),
+                    "{:?}",
+                    command
+                );
+            }
+            assert_eq!(
+                service.handle(&Command::ReadRange {
+                    address: BASE,
+                    length: BUFFER_LEN as u16,
+                }),
+                Some(Response::BadArguments)
+            );
+        }
+
+        #[test]
+        fn big_sectors() {
+            // Erasing a 512 byte page would take out its neighbour too
+            let mut service = NorFlashService::new(MockNor::<4096>::new(), BASE);
+            let page = [0u8; INT_PAGE_SIZE];
+            assert_eq!(
+                service.handle(&Command::ErasePage { address: BASE }),
+                Some(Response::BadAddress)
+            );
+            assert_eq!(
+                service.handle(&Command::WritePage {
+                    address: BASE,
+                    data: &page,
+                }),
+                Some(Response::BadAddress)
+            );
+            assert_eq!(service.release().data[1], 1);
+        }
+
+        #[test]
+        fn big_reads() {
+            // The flash can't read less than our whole buffer
+            let mut service = NorFlashService::new(MockNor::<512, 1024>::new(), BASE);
+            assert_eq!(
+                service.handle(&Command::CrcIntFlash {
+                    address: BASE,
+                    length: 1024,
+                }),
+                Some(Response::BadAddress)
+            );
+            assert_eq!(
+                service.handle(&Command::ReadRange {
+                    address: BASE,
+                    length: 16,
+                }),
+                Some(Response::BadAddress)
+            );
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,245 @@
+//! Feeding the `CommandDecoder` from a circular DMA buffer.
+//!
+//! With circular DMA the UART writes into a ring buffer forever, and an
+//! idle-line interrupt tells you how far it has got. The new bytes run from
+//! where you last stopped reading to where the DMA is now, which may wrap
+//! around the end of the ring and so come in two pieces. `feed_wrapped`
+//! decodes two such pieces as if they were one, and `RingReader` keeps
+//! track of where you are in the ring.
+//!
+//! ```
+//! use tockloader_proto::dma::RingReader;
+//! use tockloader_proto::{Command, CommandDecoder};
+//!
+//! // The DMA has wrapped, splitting the escape from the command byte
+//! let ring = [0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFC];
+//! let mut reader = RingReader::new(7);
+//! let mut decoder = CommandDecoder::new();
+//! let (position, result) = reader.decode(&ring, 1, &mut decoder).unwrap();
+//! assert_eq!(result, Ok(Command::Ping));
+//! assert_eq!(position, 1);
+//! assert!(reader.decode(&ring, 1, &mut decoder).is_none());
+//! ```
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::{Command, CommandDecoder, Error};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Tracks how far through a DMA ring buffer we've read.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct RingReader {
+    position: usize,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Split the region of `ring` from `start` up to (but not including) `end`
+/// into its two contiguous pieces. If the region doesn't wrap, the second
+/// piece is empty. If `start == end` the region is empty.
+///
+/// Panics if `start` or `end` are outside the ring.
+pub fn split(ring: &[u8], start: usize, end: usize) -> (&[u8], &[u8]) {
+    if end >= start {
+        (&ring[start..end], &[])
+    } else {
+        (&ring[start..], &ring[..end])
+    }
+}
+
+/// Decode bytes from `first` and then `second`, stopping at the end of the
+/// first frame.
+///
+/// This works like `CommandDecoder::feed`, with the two slices treated as
+/// one, so a frame (or an escape sequence) can start in `first` and finish
+/// in `second`. Returns the number of bytes consumed across both slices,
+/// along with the decoded `Command` or `Error` if a frame ended.
+pub fn feed_wrapped<'d>(
+    decoder: &'d mut CommandDecoder,
+    first: &[u8],
+    second: &[u8],
+) -> (usize, Option<Result<Command<'d>, Error>>) {
+    for (idx, ch) in first.iter().chain(second).enumerate() {
+        if let Some(cmd) = decoder.step(*ch) {
+            return (idx + 1, decoder.finish(cmd).transpose());
+        }
+    }
+    (first.len() + second.len(), None)
+}
+
+impl RingReader {
+    /// Start reading a ring buffer at `position`.
+    pub fn new(position: usize) -> RingReader {
+        RingReader { position }
+    }
+
+    /// Where in the ring we'll read from next.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Decode the bytes from `position` up to `end`, which is where the DMA
+    /// will write next, stopping at the end of the first frame.
+    ///
+    /// If a frame ended, returns the position just after it, along with
+    /// the decoded `Command` or `Error`, and the next call carries on from
+    /// there. Otherwise all the bytes up to `end` are consumed and `None`
+    /// is returned.
+    ///
+    /// Panics if `end` or `position` are outside the ring.
+    pub fn decode<'d>(
+        &mut self,
+        ring: &[u8],
+        end: usize,
+        decoder: &'d mut CommandDecoder,
+    ) -> Option<(usize, Result<Command<'d>, Error>)> {
+        let (first, second) = split(ring, self.position, end);
+        let (used, result) = feed_wrapped(decoder, first, second);
+        self.position = (self.position + used) % ring.len();
+        result.map(|r| (self.position, r))
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use self::std::vec::Vec;
+    use super::*;
+    use {CommandEncoder, ESCAPE_CHAR};
+
+    #[test]
+    fn split_regions() {
+        let ring = [0u8, 1, 2, 3, 4, 5];
+        assert_eq!(split(&ring, 1, 4), (&ring[1..4], &[][..]));
+        assert_eq!(split(&ring, 4, 2), (&ring[4..], &ring[..2]));
+        assert_eq!(split(&ring, 4, 0), (&ring[4..], &[][..]));
+        assert_eq!(split(&ring, 3, 3), (&[][..], &[][..]));
+    }
+
+    #[test]
+    fn feed_across_the_wrap() {
+        let mut decoder = CommandDecoder::new();
+        // An escape sequence split across the two halves
+        assert_eq!(
+            feed_wrapped(&mut decoder, &[0x00, ESCAPE_CHAR], &[0x01, 0x02]),
+            (3, Some(Ok(Command::Ping)))
+        );
+        // A double escape split across the two halves is a payload byte
+        let (used, result) = feed_wrapped(
+            &mut decoder,
+            &[0x00, 0x02, ESCAPE_CHAR],
+            &[ESCAPE_CHAR, 0x00, ESCAPE_CHAR, 0x06],
+        );
+        assert_eq!(used, 7);
+        assert_eq!(
+            result,
+            Some(Ok(Command::ErasePage {
+                address: 0x00FC_0200
+            }))
+        );
+        // A frame left part way through carries on next time
+        assert_eq!(
+            feed_wrapped(&mut decoder, &[0x05], &[ESCAPE_CHAR]),
+            (2, None)
+        );
+        assert_eq!(
+            feed_wrapped(&mut decoder, &[], &[0x22]),
+            (1, Some(Ok(Command::Exit)))
+        );
+        assert_eq!(feed_wrapped(&mut decoder, &[], &[]), (0, None));
+    }
+
+    #[test]
+    fn every_rotation() {
+        let page = [ESCAPE_CHAR; 512];
+        let commands = [
+            Command::Ping,
+            Command::WritePage {
+                address: 0x1_0000,
+                data: &page,
+            },
+            Command::GetAttr { index: 3 },
+            Command::ErasePage {
+                address: 0xFCFC_FC00,
+            },
+        ];
+        let mut stream = Vec::new();
+        for cmd in commands.iter() {
+            stream.extend(CommandEncoder::new(cmd).unwrap());
+        }
+        let mut ring = [0u8; 1200];
+        assert!(stream.len() < ring.len());
+        for start in 0..ring.len() {
+            for (idx, byte) in stream.iter().enumerate() {
+                ring[(start + idx) % ring.len()] = *byte;
+            }
+            let end = (start + stream.len()) % ring.len();
+            let mut reader = RingReader::new(start);
+            let mut decoder = CommandDecoder::new();
+            let mut ends = Vec::new();
+            for cmd in commands.iter() {
+                let (position, result) = reader.decode(&ring, end, &mut decoder).unwrap();
+                assert_eq!(result.as_ref(), Ok(cmd));
+                ends.push(position);
+            }
+            assert_eq!(reader.decode(&ring, end, &mut decoder), None);
+            assert_eq!(reader.position(), end);
+            assert_eq!(*ends.last().unwrap(), end);
+            assert_eq!(ends[0], (start + 2) % ring.len());
+        }
+    }
+
+    #[test]
+    fn arriving_in_dribs_and_drabs() {
+        let mut ring = [0u8; 5];
+        let mut reader = RingReader::new(0);
+        let mut decoder = CommandDecoder::new();
+        let stream = [0x00, 0x02, 0x00, 0x00, ESCAPE_CHAR, 0x06, ESCAPE_CHAR, 0x01];
+        let mut end = 0;
+        let mut seen = Vec::new();
+        for chunk in stream.chunks(3) {
+            for byte in chunk {
+                ring[end] = *byte;
+                end = (end + 1) % ring.len();
+            }
+            while let Some((position, result)) = reader.decode(&ring, end, &mut decoder) {
+                seen.push((position, result.map(|c| c == Command::Ping)));
+            }
+        }
+        assert_eq!(seen, [(1, Ok(false)), (3, Ok(true))]);
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
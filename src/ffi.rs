@@ -0,0 +1,609 @@
+//! A C API, for bootloaders written in C.
+//!
+//! The C code provides the memory for a `TlDecoder`, sets it up with
+//! `tl_decoder_init`, and then passes each byte it receives to
+//! `tl_decoder_feed`. When a command has been decoded it is written out as
+//! a `TlCommand`, which is a tagged union. Responses are described with a
+//! `TlResponse` and encoded into a buffer with `tl_response_encode`.
+//!
+//! The header `include/tockloader_proto.h` is generated from this file with
+//! `cbindgen`, and `tests/ffi` has a C program which exercises it.
+//!
+//! Pointers inside a `TlCommand` point into the `TlDecoder`, and are only
+//! valid until the decoder is next fed or reset.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::{mem, ptr, slice};
+
+use super::{BaudMode, Command, CommandDecoder, Error, Response, ResponseEncoder};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Memory for a command decoder. Only touch it through the `tl_decoder_*`
+/// functions.
+#[repr(C)]
+pub struct TlDecoder {
+    storage: [u64; TL_DECODER_WORDS],
+}
+
+/// The outcome of a call.
+#[repr(C)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TlStatus {
+    /// It worked. For `tl_decoder_feed`, a command has been decoded.
+    Ok = 0,
+    /// More bytes are needed before there's a command.
+    Pending,
+    /// A pointer argument was null.
+    NullPointer,
+    /// See `Error::UnknownCommand`.
+    UnknownCommand,
+    /// See `Error::BadArguments`.
+    BadArguments,
+    /// See `Error::BadMode`.
+    BadMode,
+    /// See `Error::BadIndex`.
+    BadIndex,
+    /// See `Error::BadLength`.
+    BadLength,
+    /// See `Error::UnalignedAddress`.
+    UnalignedAddress,
+    /// See `Error::UnsetLength`.
+    UnsetLength,
+    /// See `Error::SetLength`.
+    SetLength,
+    /// See `Error::UnexpectedResponse`.
+    UnexpectedResponse,
+    /// See `Error::BufferTooSmall`.
+    BufferTooSmall,
+    /// See `Error::BadKey`.
+    BadKey,
+}
+
+/// The sub-command of `TlCommand::ChangeBaud`.
+#[repr(C)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TlBaudMode {
+    /// Switch to the new baud rate.
+    Set = 1,
+    /// Confirm the new baud rate works.
+    Verify = 2,
+}
+
+/// A decoded command. See `Command` for what each one means.
+///
+/// `key` always points to 8 bytes.
+#[repr(C, u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TlCommand {
+    Ping,
+    Info,
+    Id,
+    Reset,
+    ErasePage {
+        address: u32,
+    },
+    WritePage {
+        address: u32,
+        data: *const u8,
+        data_len: usize,
+    },
+    EraseExBlock {
+        address: u32,
+    },
+    WriteExPage {
+        address: u32,
+        data: *const u8,
+        data_len: usize,
+    },
+    CrcRxBuffer,
+    ReadRange {
+        address: u32,
+        length: u16,
+    },
+    ExReadRange {
+        address: u32,
+        length: u16,
+    },
+    SetAttr {
+        index: u8,
+        key: *const u8,
+        value: *const u8,
+        value_len: usize,
+    },
+    GetAttr {
+        index: u8,
+    },
+    CrcIntFlash {
+        address: u32,
+        length: u32,
+    },
+    CrcExtFlash {
+        address: u32,
+        length: u32,
+    },
+    EraseExPage {
+        address: u32,
+    },
+    ExtFlashInit,
+    ClockOut,
+    WriteFlashUserPages {
+        page1: u32,
+        page2: u32,
+    },
+    ChangeBaud {
+        mode: TlBaudMode,
+        baud: u32,
+    },
+    Exit,
+    SetStartAddress {
+        address: u32,
+    },
+    Vendor {
+        code: u8,
+        payload: *const u8,
+        payload_len: usize,
+    },
+}
+
+/// A response to encode. See `Response` for what each one means.
+///
+/// A pointer may be null if its length is zero. `key` must point to 8
+/// bytes.
+#[repr(C, u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TlResponse {
+    Overflow,
+    Pong,
+    BadAddress,
+    InternalError,
+    BadArguments,
+    Ok,
+    Unknown,
+    ExtFlashTimeout,
+    ExtFlashPageError,
+    CrcRxBuffer {
+        length: u16,
+        crc: u32,
+    },
+    ReadRange {
+        data: *const u8,
+        data_len: usize,
+    },
+    ExReadRange {
+        data: *const u8,
+        data_len: usize,
+    },
+    GetAttr {
+        key: *const u8,
+        value: *const u8,
+        value_len: usize,
+    },
+    CrcIntFlash {
+        crc: u32,
+    },
+    CrcExtFlash {
+        crc: u32,
+    },
+    Info {
+        info: *const u8,
+        info_len: usize,
+    },
+    ChangeBaudFail,
+    Id {
+        id: *const u8,
+        id_len: usize,
+    },
+    Vendor {
+        code: u8,
+        payload: *const u8,
+        payload_len: usize,
+    },
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The size of `TlDecoder`, in 64-bit words.
+pub const TL_DECODER_WORDS: usize = 128;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Set up a decoder in memory provided by the caller.
+///
+/// # Safety
+///
+/// `decoder` must be null or point to a `TlDecoder`.
+#[no_mangle]
+pub unsafe extern "C" fn tl_decoder_init(decoder: *mut TlDecoder) -> TlStatus {
+    if decoder.is_null() {
+        return TlStatus::NullPointer;
+    }
+    ptr::write(decoder as *mut CommandDecoder, CommandDecoder::new());
+    TlStatus::Ok
+}
+
+/// Empty the decoder's buffer, as for `CommandDecoder::reset`.
+///
+/// # Safety
+///
+/// `decoder` must be null or point to a `TlDecoder` set up with
+/// `tl_decoder_init`.
+#[no_mangle]
+pub unsafe extern "C" fn tl_decoder_reset(decoder: *mut TlDecoder) -> TlStatus {
+    match as_decoder(decoder) {
+        Some(decoder) => {
+            decoder.reset();
+            TlStatus::Ok
+        }
+        None => TlStatus::NullPointer,
+    }
+}
+
+/// Pass a received byte to the decoder.
+///
+/// Returns `TlStatus::Ok` and fills in `command` if the byte finished a
+/// command, `TlStatus::Pending` if more bytes are needed, or the error if
+/// the command was bad. The decoder is ready for more bytes either way.
+///
+/// # Safety
+///
+/// `decoder` must be null or point to a `TlDecoder` set up with
+/// `tl_decoder_init`, and `command` must be null or point to a `TlCommand`.
+#[no_mangle]
+pub unsafe extern "C" fn tl_decoder_feed(
+    decoder: *mut TlDecoder,
+    byte: u8,
+    command: *mut TlCommand,
+) -> TlStatus {
+    let decoder = match as_decoder(decoder) {
+        Some(decoder) if !command.is_null() => decoder,
+        _ => return TlStatus::NullPointer,
+    };
+    match decoder.receive(byte) {
+        Ok(Some(ref cmd)) => {
+            ptr::write(command, TlCommand::from(cmd));
+            TlStatus::Ok
+        }
+        Ok(None) => TlStatus::Pending,
+        Err(ref e) => TlStatus::from(e),
+    }
+}
+
+/// Encode `response` into `buffer`, which is `buffer_len` bytes long, and
+/// set `written` to the number of bytes used.
+///
+/// # Safety
+///
+/// Each argument must be null or valid: `response` must point to a
+/// `TlResponse` whose pointers are valid for their lengths, `buffer` must
+/// point to `buffer_len` bytes, and `written` must point to a `size_t`.
+#[no_mangle]
+pub unsafe extern "C" fn tl_response_encode(
+    response: *const TlResponse,
+    buffer: *mut u8,
+    buffer_len: usize,
+    written: *mut usize,
+) -> TlStatus {
+    if response.is_null() || buffer.is_null() || written.is_null() {
+        return TlStatus::NullPointer;
+    }
+    let response = match (*response).as_response() {
+        Some(response) => response,
+        None => return TlStatus::NullPointer,
+    };
+    let buffer = slice::from_raw_parts_mut(buffer, buffer_len);
+    match ResponseEncoder::new(&response).and_then(|encoder| encoder.encode_into(buffer)) {
+        Ok(used) => {
+            *written = used;
+            TlStatus::Ok
+        }
+        Err(ref e) => TlStatus::from(e),
+    }
+}
+
+impl<'a, 'b> From<&'b Command<'a>> for TlCommand {
+    fn from(command: &'b Command<'a>) -> TlCommand {
+        match *command {
+            Command::Ping => TlCommand::Ping,
+            Command::Info => TlCommand::Info,
+            Command::Id => TlCommand::Id,
+            Command::Reset => TlCommand::Reset,
+            Command::ErasePage { address } => TlCommand::ErasePage { address },
+            Command::WritePage { address, data } => TlCommand::WritePage {
+                address,
+                data: data.as_ptr(),
+                data_len: data.len(),
+            },
+            Command::EraseExBlock { address } => TlCommand::EraseExBlock { address },
+            Command::WriteExPage { address, data } => TlCommand::WriteExPage {
+                address,
+                data: data.as_ptr(),
+                data_len: data.len(),
+            },
+            Command::CrcRxBuffer => TlCommand::CrcRxBuffer,
+            Command::ReadRange { address, length } => TlCommand::ReadRange { address, length },
+            Command::ExReadRange { address, length } => TlCommand::ExReadRange { address, length },
+            Command::SetAttr { index, key, value } => TlCommand::SetAttr {
+                index,
+                key: key.as_ptr(),
+                value: value.as_ptr(),
+                value_len: value.len(),
+            },
+            Command::GetAttr { index } => TlCommand::GetAttr { index },
+            Command::CrcIntFlash { address, length } => TlCommand::CrcIntFlash { address, length },
+            Command::CrcExtFlash { address, length } => TlCommand::CrcExtFlash { address, length },
+            Command::EraseExPage { address } => TlCommand::EraseExPage { address },
+            Command::ExtFlashInit => TlCommand::ExtFlashInit,
+            Command::ClockOut => TlCommand::ClockOut,
+            Command::WriteFlashUserPages { page1, page2 } => {
+                TlCommand::WriteFlashUserPages { page1, page2 }
+            }
+            Command::ChangeBaud { mode, baud } => TlCommand::ChangeBaud {
+                mode: match mode {
+                    BaudMode::Set => TlBaudMode::Set,
+                    BaudMode::Verify => TlBaudMode::Verify,
+                },
+                baud,
+            },
+            Command::Exit => TlCommand::Exit,
+            Command::SetStartAddress { address } => TlCommand::SetStartAddress { address },
+            Command::Vendor { code, payload } => TlCommand::Vendor {
+                code,
+                payload: payload.as_ptr(),
+                payload_len: payload.len(),
+            },
+        }
+    }
+}
+
+impl<'a> From<&'a Error> for TlStatus {
+    fn from(error: &'a Error) -> TlStatus {
+        match *error {
+            Error::UnknownCommand { .. } => TlStatus::UnknownCommand,
+            Error::BadArguments { .. } => TlStatus::BadArguments,
+            Error::BadMode { .. } => TlStatus::BadMode,
+            Error::BadIndex { .. } => TlStatus::BadIndex,
+            Error::BadLength { .. } => TlStatus::BadLength,
+            Error::UnalignedAddress { .. } => TlStatus::UnalignedAddress,
+            Error::UnsetLength { .. } => TlStatus::UnsetLength,
+            Error::SetLength => TlStatus::SetLength,
+            Error::UnexpectedResponse { .. } => TlStatus::UnexpectedResponse,
+            Error::BufferTooSmall { .. } => TlStatus::BufferTooSmall,
+            Error::BadKey { .. } => TlStatus::BadKey,
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+// The C side only knows how big `TlDecoder` is, so it had better be big
+// enough.
+const _: () = assert!(mem::size_of::<CommandDecoder>() <= mem::size_of::<TlDecoder>());
+const _: () = assert!(mem::align_of::<CommandDecoder>() <= mem::align_of::<TlDecoder>());
+
+impl TlResponse {
+    /// Borrow the buffers this response points to. Returns `None` if one of
+    /// them is null but has a length.
+    unsafe fn as_response(&self) -> Option<Response<'_>> {
+        Some(match *self {
+            TlResponse::Overflow => Response::Overflow,
+            TlResponse::Pong => Response::Pong,
+            TlResponse::BadAddress => Response::BadAddress,
+            TlResponse::InternalError => Response::InternalError,
+            TlResponse::BadArguments => Response::BadArguments,
+            TlResponse::Ok => Response::Ok,
+            TlResponse::Unknown => Response::Unknown,
+            TlResponse::ExtFlashTimeout => Response::ExtFlashTimeout,
+            TlResponse::ExtFlashPageError => Response::ExtFlashPageError,
+            TlResponse::CrcRxBuffer { length, crc } => Response::CrcRxBuffer { length, crc },
+            TlResponse::ReadRange { data, data_len } => Response::ReadRange {
+                data: as_slice(data, data_len)?,
+            },
+            TlResponse::ExReadRange { data, data_len } => Response::ExReadRange {
+                data: as_slice(data, data_len)?,
+            },
+            TlResponse::GetAttr {
+                key,
+                value,
+                value_len,
+            } => Response::GetAttr {
+                key: as_slice(key, super::KEY_LEN)?,
+                value: as_slice(value, value_len)?,
+            },
+            TlResponse::CrcIntFlash { crc } => Response::CrcIntFlash { crc },
+            TlResponse::CrcExtFlash { crc } => Response::CrcExtFlash { crc },
+            TlResponse::Info { info, info_len } => Response::Info {
+                info: as_slice(info, info_len)?,
+            },
+            TlResponse::ChangeBaudFail => Response::ChangeBaudFail,
+            TlResponse::Id { id, id_len } => Response::Id {
+                id: as_slice(id, id_len)?,
+            },
+            TlResponse::Vendor {
+                code,
+                payload,
+                payload_len,
+            } => Response::Vendor {
+                code,
+                payload: as_slice(payload, payload_len)?,
+            },
+        })
+    }
+}
+
+unsafe fn as_decoder<'a>(decoder: *mut TlDecoder) -> Option<&'a mut CommandDecoder> {
+    (decoder as *mut CommandDecoder).as_mut()
+}
+
+unsafe fn as_slice<'a>(data: *const u8, len: usize) -> Option<&'a [u8]> {
+    if len == 0 {
+        Some(&[])
+    } else if data.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(data, len))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {CommandEncoder, ESCAPE_CHAR};
+
+    fn new_decoder() -> TlDecoder {
+        let mut decoder = TlDecoder {
+            storage: [0xDEAD_BEEF; TL_DECODER_WORDS],
+        };
+        assert_eq!(unsafe { tl_decoder_init(&mut decoder) }, TlStatus::Ok);
+        decoder
+    }
+
+    fn feed(decoder: &mut TlDecoder, bytes: &[u8]) -> (TlStatus, TlCommand) {
+        let mut command = TlCommand::Ping;
+        let mut status = TlStatus::Pending;
+        for byte in bytes {
+            status = unsafe { tl_decoder_feed(decoder, *byte, &mut command) };
+        }
+        (status, command)
+    }
+
+    #[test]
+    fn decode() {
+        let mut decoder = new_decoder();
+        assert_eq!(
+            feed(&mut decoder, &[ESCAPE_CHAR, 0x03]),
+            (TlStatus::Ok, TlCommand::Info)
+        );
+        let page = [ESCAPE_CHAR; 512];
+        let cmd = Command::WritePage {
+            address: 0x4_0000,
+            data: &page,
+        };
+        let bytes: heapless::Vec<u8, 1100> = CommandEncoder::new(&cmd).unwrap().collect();
+        let (status, decoded) = feed(&mut decoder, &bytes);
+        assert_eq!(status, TlStatus::Ok);
+        match decoded {
+            TlCommand::WritePage {
+                address,
+                data,
+                data_len,
+            } => {
+                assert_eq!(address, 0x4_0000);
+                assert_eq!(unsafe { slice::from_raw_parts(data, data_len) }, &page[..]);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            feed(
+                &mut decoder,
+                &[0x01, 0x00, 0x00, 0x80, 0x25, ESCAPE_CHAR, 0x21]
+            ),
+            (
+                TlStatus::Ok,
+                TlCommand::ChangeBaud {
+                    mode: TlBaudMode::Set,
+                    baud: 0x2580_0000,
+                }
+            )
+        );
+    }
+
+    #[test]
+    fn decode_errors() {
+        let mut decoder = new_decoder();
+        assert_eq!(
+            feed(&mut decoder, &[0x01, ESCAPE_CHAR, 0x06]).0,
+            TlStatus::BadArguments
+        );
+        assert_eq!(
+            feed(&mut decoder, &[0x01, 0x00, 0x00, 0x00, ESCAPE_CHAR, 0x06]).0,
+            TlStatus::UnalignedAddress
+        );
+        // Half a command, thrown away
+        assert_eq!(feed(&mut decoder, &[0x01, 0x02]).0, TlStatus::Pending);
+        assert_eq!(unsafe { tl_decoder_reset(&mut decoder) }, TlStatus::Ok);
+        assert_eq!(
+            feed(&mut decoder, &[ESCAPE_CHAR, 0x05]),
+            (TlStatus::Ok, TlCommand::Reset)
+        );
+        unsafe {
+            let mut command = TlCommand::Ping;
+            assert_eq!(tl_decoder_init(ptr::null_mut()), TlStatus::NullPointer);
+            assert_eq!(tl_decoder_reset(ptr::null_mut()), TlStatus::NullPointer);
+            assert_eq!(
+                tl_decoder_feed(ptr::null_mut(), 0, &mut command),
+                TlStatus::NullPointer
+            );
+            assert_eq!(
+                tl_decoder_feed(&mut decoder, 0, ptr::null_mut()),
+                TlStatus::NullPointer
+            );
+        }
+    }
+
+    #[test]
+    fn encode() {
+        let mut buffer = [0u8; 16];
+        let mut written = 0;
+        let data = [0x01, ESCAPE_CHAR];
+        let response = TlResponse::ReadRange {
+            data: data.as_ptr(),
+            data_len: data.len(),
+        };
+        unsafe {
+            assert_eq!(
+                tl_response_encode(&response, buffer.as_mut_ptr(), buffer.len(), &mut written),
+                TlStatus::Ok
+            );
+            assert_eq!(
+                &buffer[..written],
+                &[ESCAPE_CHAR, 0x20, 0x01, ESCAPE_CHAR, ESCAPE_CHAR]
+            );
+            assert_eq!(
+                tl_response_encode(&response, buffer.as_mut_ptr(), 4, &mut written),
+                TlStatus::BufferTooSmall
+            );
+            let response = TlResponse::Pong;
+            assert_eq!(
+                tl_response_encode(&response, buffer.as_mut_ptr(), buffer.len(), &mut written),
+                TlStatus::Ok
+            );
+            assert_eq!(&buffer[..written], &[ESCAPE_CHAR, 0x11]);
+            let response = TlResponse::Info {
+                info: ptr::null(),
+                info_len: 3,
+            };
+            assert_eq!(
+                tl_response_encode(&response, buffer.as_mut_ptr(), buffer.len(), &mut written),
+                TlStatus::NullPointer
+            );
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,425 @@
+//! Helpers for flash tools which talk to a bootloader.
+//!
+//! You supply a `Channel` (typically a serial port) and these functions
+//! take care of encoding the command, decoding the response and checking
+//! the response is the one we wanted.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::fmt;
+
+use super::attr::{self, Attribute, AttributeTable, Change};
+use super::{
+    Command, CommandEncoder, Encoder, Error, Response, ResponseDecoder, ID_LEN, MAX_INDEX,
+};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A byte pipe to a bootloader, such as a serial port.
+pub trait Channel {
+    /// The error the underlying transport produces, e.g. on a timeout.
+    type Error;
+
+    /// Send all of the given bytes.
+    fn send(&mut self, bytes: &[u8]) -> Result<(), Self::Error>;
+
+    /// Receive at least one byte into `buffer`, returning how many bytes
+    /// were received. Returning zero means the channel has closed.
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, Self::Error>;
+}
+
+/// Something went wrong talking to the bootloader.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum HostError<E> {
+    /// The `Channel` failed.
+    Channel(E),
+    /// We couldn't encode the command or decode the response.
+    Protocol(Error),
+    /// The bootloader told us it failed, e.g. with `Response::BadAddress`.
+    Failed(Response<'static>),
+    /// The `Channel` closed before we got a whole response.
+    Closed,
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Ask the bootloader for the board's unique ID.
+pub fn get_id<C: Channel>(channel: &mut C) -> Result<[u8; ID_LEN], HostError<C::Error>> {
+    transact(channel, &Command::Id, |response| match *response {
+        Response::Id { id } => {
+            let mut result = [0u8; ID_LEN];
+            result.copy_from_slice(id);
+            Some(result)
+        }
+        _ => None,
+    })
+}
+
+/// Read attribute slot `index`. Returns `None` if the slot is empty.
+pub fn get_attr<C: Channel>(
+    channel: &mut C,
+    index: u8,
+) -> Result<Option<Attribute>, HostError<C::Error>> {
+    let result = transact(
+        channel,
+        &Command::GetAttr { index },
+        |response| match *response {
+            Response::GetAttr { key, value } => Some(attr::from_slot(key, value)),
+            _ => None,
+        },
+    )?;
+    Ok(result?)
+}
+
+/// Write an attribute to slot `index`.
+pub fn set_attr<C: Channel>(
+    channel: &mut C,
+    index: u8,
+    attribute: &Attribute,
+) -> Result<(), HostError<C::Error>> {
+    transact(channel, &attribute.to_command(index), ok)
+}
+
+/// Empty attribute slot `index`.
+pub fn clear_attr<C: Channel>(channel: &mut C, index: u8) -> Result<(), HostError<C::Error>> {
+    apply_change(channel, &Change::Clear { index })
+}
+
+/// Read every attribute slot.
+pub fn read_attributes<C: Channel>(channel: &mut C) -> Result<AttributeTable, HostError<C::Error>> {
+    let mut table = AttributeTable::new();
+    for index in 0..MAX_INDEX {
+        if let Some(attribute) = get_attr(channel, index)? {
+            table.set(index, attribute)?;
+        }
+    }
+    Ok(table)
+}
+
+/// Make the bootloader's attributes match `desired`, only writing the
+/// slots which differ. Slots which are empty in `desired` are cleared.
+/// Returns the number of slots written.
+pub fn apply_attributes<C: Channel>(
+    channel: &mut C,
+    desired: &AttributeTable,
+) -> Result<usize, HostError<C::Error>> {
+    let current = read_attributes(channel)?;
+    let mut written = 0;
+    for change in current.diff(desired) {
+        apply_change(channel, &change)?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// Write one slot, e.g. from `AttributeTable::diff`.
+pub fn apply_change<C: Channel>(
+    channel: &mut C,
+    change: &Change,
+) -> Result<(), HostError<C::Error>> {
+    transact(channel, &change.to_command(), ok)
+}
+
+impl<E> From<Error> for HostError<E> {
+    fn from(error: Error) -> HostError<E> {
+        HostError::Protocol(error)
+    }
+}
+
+impl<E> fmt::Display for HostError<E>
+where
+    E: fmt::Display,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HostError::Channel(ref e) => write!(f, "channel error: {}", e),
+            HostError::Protocol(ref e) => write!(f, "protocol error: {}", e),
+            HostError::Failed(ref r) => write!(f, "bootloader failed: {:?}", r),
+            HostError::Closed => write!(f, "channel closed"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E> ::std::error::Error for HostError<E> where E: fmt::Debug + fmt::Display {}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Send `command` then wait for the response, which `f` turns into the
+/// result. `f` only sees successful responses of the expected kind.
+pub(crate) fn transact<C, F, T>(
+    channel: &mut C,
+    command: &Command,
+    f: F,
+) -> Result<T, HostError<C::Error>>
+where
+    C: Channel,
+    F: FnOnce(&Response) -> Option<T>,
+{
+    let mut encoder = CommandEncoder::new(command)?;
+    let mut chunk = [0u8; 64];
+    loop {
+        let len = encoder.write(&mut chunk);
+        if len == 0 {
+            break;
+        }
+        channel.send(&chunk[..len]).map_err(HostError::Channel)?;
+    }
+
+    let mut decoder = ResponseDecoder::new();
+    decoder.expect_response_to(command)?;
+    loop {
+        let len = channel.receive(&mut chunk).map_err(HostError::Channel)?;
+        if len == 0 {
+            return Err(HostError::Closed);
+        }
+        let mut input = &chunk[..len];
+        while !input.is_empty() {
+            let (used, result) = decoder.feed(input);
+            input = &input[used..];
+            match result {
+                None => {}
+                Some(Err(e)) => return Err(HostError::Protocol(e)),
+                Some(Ok(response)) => {
+                    if let Some(failure) = failure(&response) {
+                        return Err(HostError::Failed(failure));
+                    }
+                    let code = response.code();
+                    // `expect_response_to` has already checked the code, so
+                    // this only fails for commands with no known response
+                    return f(&response).ok_or(HostError::Protocol(Error::UnexpectedResponse {
+                        code,
+                        expected: command.response_code().unwrap_or(code),
+                    }));
+                }
+            }
+        }
+    }
+}
+
+/// For commands which just expect `Response::Ok`.
+fn ok(response: &Response) -> Option<()> {
+    match *response {
+        Response::Ok => Some(()),
+        _ => None,
+    }
+}
+
+/// If this is a response reporting failure, return it.
+fn failure(response: &Response) -> Option<Response<'static>> {
+    match *response {
+        Response::Overflow => Some(Response::Overflow),
+        Response::BadAddress => Some(Response::BadAddress),
+        Response::InternalError => Some(Response::InternalError),
+        Response::BadArguments => Some(Response::BadArguments),
+        Response::Unknown => Some(Response::Unknown),
+        Response::ExtFlashTimeout => Some(Response::ExtFlashTimeout),
+        Response::ExtFlashPageError => Some(Response::ExtFlashPageError),
+        Response::ChangeBaudFail => Some(Response::ChangeBaudFail),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::super::attr::{AttributeKey, WellKnown};
+    use super::super::{CommandDecoder, OwnedResponse, Payload, ResponseEncoder};
+    use super::*;
+
+    /// Pretends to be a bootloader. Each command we send is decoded and
+    /// passed to the handler, and the encoded response is what we receive.
+    pub(crate) struct FakeBootloader<F> {
+        decoder: CommandDecoder,
+        handler: F,
+        reply: [u8; 1100],
+        reply_len: usize,
+        reply_pos: usize,
+    }
+
+    impl<F> FakeBootloader<F>
+    where
+        F: FnMut(&Command) -> Option<OwnedResponse>,
+    {
+        pub(crate) fn new(handler: F) -> FakeBootloader<F> {
+            FakeBootloader {
+                decoder: CommandDecoder::new(),
+                handler,
+                reply: [0u8; 1100],
+                reply_len: 0,
+                reply_pos: 0,
+            }
+        }
+    }
+
+    impl<F> Channel for FakeBootloader<F>
+    where
+        F: FnMut(&Command) -> Option<OwnedResponse>,
+    {
+        type Error = ();
+
+        fn send(&mut self, mut bytes: &[u8]) -> Result<(), ()> {
+            while !bytes.is_empty() {
+                let (used, result) = self.decoder.feed(bytes);
+                bytes = &bytes[used..];
+                if let Some(command) = result {
+                    let command = command.map_err(|_| ())?;
+                    if let Some(response) = (self.handler)(&command) {
+                        let response = response.as_response();
+                        let encoder = ResponseEncoder::new(&response).map_err(|_| ())?;
+                        self.reply_len = encoder.encode_into(&mut self.reply).map_err(|_| ())?;
+                        self.reply_pos = 0;
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        fn receive(&mut self, buffer: &mut [u8]) -> Result<usize, ()> {
+            // Dribble the reply out a few bytes at a time
+            let pending = &self.reply[self.reply_pos..self.reply_len];
+            let len = pending.len().min(buffer.len()).min(3);
+            buffer[..len].copy_from_slice(&pending[..len]);
+            self.reply_pos += len;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn get_id_works() {
+        let mut bootloader = FakeBootloader::new(|command: &Command| match *command {
+            Command::Id => Some(OwnedResponse::Id {
+                id: Payload::from_slice(&[0xFC, 1, 2, 3, 4, 5, 6, 0xFC]).unwrap(),
+            }),
+            _ => None,
+        });
+        assert_eq!(get_id(&mut bootloader), Ok([0xFC, 1, 2, 3, 4, 5, 6, 0xFC]));
+    }
+
+    #[test]
+    fn get_id_failures() {
+        let mut bootloader = FakeBootloader::new(|_: &Command| Some(OwnedResponse::Unknown));
+        assert_eq!(
+            get_id(&mut bootloader),
+            Err(HostError::Failed(Response::Unknown))
+        );
+        let mut bootloader = FakeBootloader::new(|_: &Command| Some(OwnedResponse::Pong));
+        assert_eq!(
+            get_id(&mut bootloader),
+            Err(HostError::Protocol(Error::UnexpectedResponse {
+                code: 0x11,
+                expected: 0x27,
+            }))
+        );
+        let mut bootloader = FakeBootloader::new(|_: &Command| None);
+        assert_eq!(get_id(&mut bootloader), Err(HostError::Closed));
+    }
+
+    /// A bootloader with attribute slots, counting the writes it sees.
+    fn attribute_handler<'a>(
+        slots: &'a mut AttributeTable,
+        writes: &'a mut usize,
+    ) -> impl FnMut(&Command) -> Option<OwnedResponse> + 'a {
+        move |command: &Command| match *command {
+            Command::GetAttr { index } => {
+                let (key, value): (&[u8], &[u8]) = match slots.get(index) {
+                    Some(attribute) => (attribute.key().as_bytes(), attribute.value()),
+                    None => (&[0xFF; 8], &[]),
+                };
+                Some(OwnedResponse::GetAttr {
+                    key: Payload::from_slice(key).unwrap(),
+                    value: Payload::from_slice(value).unwrap(),
+                })
+            }
+            Command::SetAttr { index, key, value } => {
+                *writes += 1;
+                match attr::from_slot(key, value).unwrap() {
+                    Some(attribute) => slots.set(index, attribute).unwrap(),
+                    None => slots.clear(index).unwrap(),
+                }
+                Some(OwnedResponse::Ok)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn attributes() {
+        let mut slots = AttributeTable::new();
+        let mut writes = 0;
+        let board = Attribute::from_strs("board", "hail").unwrap();
+        {
+            let mut bootloader = FakeBootloader::new(attribute_handler(&mut slots, &mut writes));
+            assert_eq!(get_attr(&mut bootloader, 0), Ok(None));
+            assert_eq!(set_attr(&mut bootloader, 0, &board), Ok(()));
+            assert_eq!(get_attr(&mut bootloader, 0), Ok(Some(board.clone())));
+            assert_eq!(clear_attr(&mut bootloader, 0), Ok(()));
+            assert_eq!(get_attr(&mut bootloader, 0), Ok(None));
+            assert_eq!(
+                get_attr(&mut bootloader, MAX_INDEX),
+                Err(HostError::Protocol(Error::BadIndex {
+                    code: 0x14,
+                    index: MAX_INDEX,
+                }))
+            );
+        }
+        assert_eq!(writes, 2);
+    }
+
+    #[test]
+    fn apply_only_changes() {
+        let mut slots = AttributeTable::new();
+        slots
+            .set(0, Attribute::from_strs("board", "hail").unwrap())
+            .unwrap();
+        slots
+            .set(1, Attribute::from_strs("arch", "cortex-m4").unwrap())
+            .unwrap();
+        slots
+            .set(7, Attribute::from_strs("stale", "1").unwrap())
+            .unwrap();
+        let mut desired = AttributeTable::new();
+        desired
+            .set(0, Attribute::from_strs("board", "hail").unwrap())
+            .unwrap();
+        desired
+            .set(1, Attribute::from_strs("arch", "cortex-m0").unwrap())
+            .unwrap();
+        desired
+            .set(2, Attribute::address(WellKnown::AppAddr, 0x30000))
+            .unwrap();
+
+        let mut writes = 0;
+        {
+            let mut bootloader = FakeBootloader::new(attribute_handler(&mut slots, &mut writes));
+            assert_eq!(read_attributes(&mut bootloader).unwrap().iter().count(), 3);
+            assert_eq!(apply_attributes(&mut bootloader, &desired), Ok(3));
+            assert_eq!(read_attributes(&mut bootloader), Ok(desired.clone()));
+            assert_eq!(apply_attributes(&mut bootloader, &desired), Ok(0));
+        }
+        assert_eq!(writes, 3);
+        assert_eq!(slots, desired);
+        assert!(slots.find(&AttributeKey::new("stale").unwrap()).is_none());
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,464 @@
+//! Structured contents for the `Info` response.
+//!
+//! Newer Tock bootloaders put a JSON object in the info string, like
+//! `{"version":"1.1.0","name":"Tock Bootloader"}`. `parse_info` picks this
+//! apart without allocating, and `InfoBuilder` produces one on the device
+//! side.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::str;
+
+use super::{Error, Field, Response, MAX_INFO_LEN, RES_INFO};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// The result of parsing an info string.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ParsedInfo<'a> {
+    /// The bootloader sent a JSON object.
+    Json(BootloaderInfo<'a>),
+    /// The bootloader sent something else, e.g. plain text from an older
+    /// bootloader.
+    Raw(&'a [u8]),
+}
+
+/// The fields of a JSON info string.
+///
+/// String values are given without their quotes, but escape sequences are
+/// left as they are. Other values (numbers, objects, etc) are given as the
+/// raw JSON text.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BootloaderInfo<'a> {
+    /// The `version` field, if present.
+    pub version: Option<&'a str>,
+    /// The `name` field, if present.
+    pub name: Option<&'a str>,
+    json: &'a str,
+}
+
+/// Iterates through the `(key, value)` pairs in a `BootloaderInfo`.
+pub struct Fields<'a> {
+    json: &'a str,
+    pos: usize,
+    first: bool,
+}
+
+/// Builds a JSON info string for `Response::Info`.
+///
+/// The string is always a complete JSON object, and is never longer than
+/// the 192 bytes the protocol allows for it.
+pub struct InfoBuilder {
+    buffer: [u8; MAX_INFO_LEN],
+    used: usize,
+}
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Parse the contents of a `Response::Info`.
+pub fn parse_info(info: &[u8]) -> ParsedInfo<'_> {
+    match BootloaderInfo::parse(info) {
+        Some(parsed) => ParsedInfo::Json(parsed),
+        None => ParsedInfo::Raw(info),
+    }
+}
+
+impl<'a> BootloaderInfo<'a> {
+    /// Parse an info string, returning `None` if it isn't a JSON object.
+    /// Trailing nulls are ignored.
+    pub fn parse(info: &'a [u8]) -> Option<BootloaderInfo<'a>> {
+        let end = info.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        let json = str::from_utf8(&info[..end]).ok()?.trim();
+        let body_start = match json.as_bytes().first() {
+            Some(b'{') => 1,
+            _ => return None,
+        };
+        let mut fields = Fields {
+            json,
+            pos: body_start,
+            first: true,
+        };
+        let mut result = BootloaderInfo {
+            version: None,
+            name: None,
+            json,
+        };
+        while let Some((key, value)) = fields.step().ok()? {
+            match key {
+                "version" => result.version = Some(value),
+                "name" => result.name = Some(value),
+                _ => {}
+            }
+        }
+        // Nothing is allowed after the closing brace
+        if fields.pos == json.len() {
+            Some(result)
+        } else {
+            None
+        }
+    }
+
+    /// Look up a field by key.
+    pub fn get(&self, key: &str) -> Option<&'a str> {
+        self.fields().find(|&(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// All the fields, including `version` and `name`, in the order the
+    /// bootloader sent them.
+    pub fn fields(&self) -> Fields<'a> {
+        Fields {
+            json: self.json,
+            pos: 1,
+            first: true,
+        }
+    }
+
+    /// The whole JSON object.
+    pub fn as_str(&self) -> &'a str {
+        self.json
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = (&'a str, &'a str);
+
+    fn next(&mut self) -> Option<(&'a str, &'a str)> {
+        // The JSON was checked when it was parsed, so this can't fail
+        self.step().unwrap_or(None)
+    }
+}
+
+impl InfoBuilder {
+    /// Create a new, empty, info string.
+    pub fn new() -> InfoBuilder {
+        let mut buffer = [0u8; MAX_INFO_LEN];
+        buffer[0] = b'{';
+        buffer[1] = b'}';
+        InfoBuilder { buffer, used: 2 }
+    }
+
+    /// Set the `version` field.
+    pub fn version(&mut self, version: &str) -> Result<&mut InfoBuilder, Error> {
+        self.field("version", version)
+    }
+
+    /// Set the `name` field.
+    pub fn name(&mut self, name: &str) -> Result<&mut InfoBuilder, Error> {
+        self.field("name", name)
+    }
+
+    /// Add a field with a string value. Fails with `Error::BadLength` if
+    /// the info string would become too long, in which case the builder is
+    /// unchanged.
+    pub fn field(&mut self, key: &str, value: &str) -> Result<&mut InfoBuilder, Error> {
+        // Separator, two quoted strings and the colon
+        let separator = if self.used > 2 { 1 } else { 0 };
+        let extra = separator + escaped_len(key) + escaped_len(value) + 5;
+        let total = self.used + extra;
+        if total > MAX_INFO_LEN {
+            return Err(Error::BadLength {
+                code: RES_INFO,
+                field: Field::Info,
+                limit: MAX_INFO_LEN,
+                actual: total,
+            });
+        }
+        // Overwrite the closing brace
+        self.used -= 1;
+        if separator != 0 {
+            self.push(b',');
+        }
+        self.push_str(key);
+        self.push(b':');
+        self.push_str(value);
+        self.push(b'}');
+        Ok(self)
+    }
+
+    /// The info string built so far.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer[..self.used]
+    }
+
+    /// A `Response::Info` holding the info string.
+    pub fn as_response(&self) -> Response<'_> {
+        Response::Info {
+            info: self.as_bytes(),
+        }
+    }
+}
+
+impl Default for InfoBuilder {
+    fn default() -> InfoBuilder {
+        InfoBuilder::new()
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<'a> Fields<'a> {
+    /// Parse the next field. `Err` means the JSON is malformed, and
+    /// `Ok(None)` means we've reached the closing brace.
+    fn step(&mut self) -> Result<Option<(&'a str, &'a str)>, ()> {
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(None);
+        }
+        if !self.first {
+            self.expect(b',')?;
+            self.skip_whitespace();
+        }
+        self.first = false;
+        let key = self.string()?;
+        self.skip_whitespace();
+        self.expect(b':')?;
+        self.skip_whitespace();
+        let value = self.value()?;
+        Ok(Some((key, value)))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.json.as_bytes().get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, ch: u8) -> Result<(), ()> {
+        if self.peek() == Some(ch) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    /// A quoted string. Returns the contents, without the quotes.
+    fn string(&mut self) -> Result<&'a str, ()> {
+        self.expect(b'"')?;
+        let start = self.pos;
+        loop {
+            match self.peek() {
+                Some(b'"') => break,
+                Some(b'\\') => self.pos += 2,
+                Some(_) => self.pos += 1,
+                None => return Err(()),
+            }
+        }
+        let contents = self.json.get(start..self.pos).ok_or(())?;
+        self.pos += 1;
+        Ok(contents)
+    }
+
+    /// Any JSON value.
+    fn value(&mut self) -> Result<&'a str, ()> {
+        let start = self.pos;
+        match self.peek() {
+            Some(b'"') => return self.string(),
+            Some(b'{') | Some(b'[') => {
+                // Skip to the matching bracket
+                let mut depth = 0;
+                loop {
+                    match self.peek() {
+                        Some(b'"') => {
+                            self.string()?;
+                            continue;
+                        }
+                        Some(b'{') | Some(b'[') => depth += 1,
+                        Some(b'}') | Some(b']') => depth -= 1,
+                        Some(_) => {}
+                        None => return Err(()),
+                    }
+                    self.pos += 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            }
+            _ => {
+                // Numbers, true, false and null
+                while let Some(ch) = self.peek() {
+                    match ch {
+                        b'-' | b'+' | b'.' | b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z' => {
+                            self.pos += 1
+                        }
+                        _ => break,
+                    }
+                }
+                if self.pos == start {
+                    return Err(());
+                }
+            }
+        }
+        self.json.get(start..self.pos).ok_or(())
+    }
+}
+
+impl InfoBuilder {
+    fn push(&mut self, ch: u8) {
+        self.buffer[self.used] = ch;
+        self.used += 1;
+    }
+
+    /// Push a quoted, escaped, string.
+    fn push_str(&mut self, s: &str) {
+        self.push(b'"');
+        for &ch in s.as_bytes() {
+            match ch {
+                b'"' | b'\\' => {
+                    self.push(b'\\');
+                    self.push(ch);
+                }
+                0x00..=0x1F => {
+                    for &b in b"\\u00" {
+                        self.push(b);
+                    }
+                    self.push(HEX[(ch >> 4) as usize]);
+                    self.push(HEX[(ch & 0x0F) as usize]);
+                }
+                _ => self.push(ch),
+            }
+        }
+        self.push(b'"');
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+/// How many bytes `InfoBuilder::push_str` needs for the contents of `s`.
+fn escaped_len(s: &str) -> usize {
+    s.bytes()
+        .map(|ch| match ch {
+            b'"' | b'\\' => 2,
+            0x00..=0x1F => 6,
+            _ => 1,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{ResponseDecoder, ResponseEncoder};
+    use super::*;
+
+    #[test]
+    fn parse_tock_bootloader() {
+        let info = br#"{"version":"1.1.0", "name":"Tock Bootloader", "board": "nrf52dk", "attrs": [1, {"a": "]"}], "ok": true}"#;
+        match parse_info(info) {
+            ParsedInfo::Json(parsed) => {
+                assert_eq!(parsed.version, Some("1.1.0"));
+                assert_eq!(parsed.name, Some("Tock Bootloader"));
+                assert_eq!(parsed.get("board"), Some("nrf52dk"));
+                assert_eq!(parsed.get("attrs"), Some(r#"[1, {"a": "]"}]"#));
+                assert_eq!(parsed.get("ok"), Some("true"));
+                assert_eq!(parsed.get("missing"), None);
+                assert_eq!(parsed.fields().count(), 5);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn parse_fallback() {
+        let text: &[&[u8]] = &[
+            b"Tock Bootloader 0.5",
+            b"",
+            br#"{"version":"1.1.0""#,
+            br#"{"version":"1.1.0"} trailing"#,
+            br#"{"version" "1.1.0"}"#,
+            br#"{"version":"1.1.0",}"#,
+            b"{\"name\":\"\xFF\"}",
+        ];
+        for info in text {
+            assert_eq!(parse_info(info), ParsedInfo::Raw(info));
+        }
+        // Padding is fine
+        match parse_info(b"{}\0\0\0") {
+            ParsedInfo::Json(parsed) => {
+                assert_eq!(parsed.version, None);
+                assert_eq!(parsed.as_str(), "{}");
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn builder_round_trip() {
+        let mut builder = InfoBuilder::new();
+        builder
+            .version("1.1.0")
+            .unwrap()
+            .name("Rust \"Bootloader\"\n")
+            .unwrap();
+        assert_eq!(
+            builder.as_bytes(),
+            &br#"{"version":"1.1.0","name":"Rust \"Bootloader\"\u000a"}"#[..]
+        );
+
+        // Send it over the wire and back
+        let response = builder.as_response();
+        let mut buffer = [0u8; 256];
+        let len = ResponseEncoder::new(&response)
+            .unwrap()
+            .encode_into(&mut buffer)
+            .unwrap();
+        let mut p = ResponseDecoder::new();
+        match p.feed(&buffer[..len]).1 {
+            Some(Ok(Response::Info { info })) => match parse_info(info) {
+                ParsedInfo::Json(parsed) => {
+                    assert_eq!(parsed.version, Some("1.1.0"));
+                    assert_eq!(parsed.name, Some(r#"Rust \"Bootloader\"\u000a"#));
+                }
+                e => panic!("Did not expect: {:?}", e),
+            },
+            e => panic!("Did not expect: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn builder_limit() {
+        let mut builder = InfoBuilder::new();
+        let long = [b'x'; MAX_INFO_LEN];
+        let long = str::from_utf8(&long).unwrap();
+        // {"k":"..."} is 8 bytes plus the value
+        assert!(builder.field("k", &long[..MAX_INFO_LEN - 9]).is_ok());
+        assert_eq!(builder.as_bytes().len(), MAX_INFO_LEN - 1);
+        assert_eq!(
+            builder.field("a", "").err(),
+            Some(Error::BadLength {
+                code: RES_INFO,
+                field: Field::Info,
+                limit: MAX_INFO_LEN,
+                actual: MAX_INFO_LEN + 6,
+            })
+        );
+        assert_eq!(builder.as_bytes().len(), MAX_INFO_LEN - 1);
+        assert!(BootloaderInfo::parse(builder.as_bytes()).is_some());
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -7,6 +7,15 @@
 //! in Rust!
 
 #![no_std]
+// The original encoders and decoders are written in this style
+#![allow(ellipsis_inclusive_range_patterns)]
+#![allow(
+    clippy::match_ref_pats,
+    clippy::needless_range_loop,
+    clippy::needless_return,
+    clippy::new_without_default,
+    clippy::redundant_pattern_matching
+)]
 
 // ****************************************************************************
 //
@@ -14,9 +23,44 @@
 //
 // ****************************************************************************
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
 extern crate byteorder;
+#[cfg(feature = "embedded-hal")]
+extern crate embedded_hal;
+#[cfg(feature = "embedded-io")]
+extern crate embedded_io;
+#[cfg(feature = "embedded-storage")]
+extern crate embedded_storage;
+extern crate heapless;
+#[cfg(feature = "embedded-hal")]
+extern crate nb;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "std")]
+extern crate std;
 
 use byteorder::{LittleEndian, ByteOrder};
+use core::cell::Cell;
+use core::fmt;
+
+pub mod attr;
+pub mod device;
+pub mod dma;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod host;
+pub mod info;
+mod owned;
+pub mod queue;
+#[cfg(any(feature = "embedded-hal", feature = "embedded-io"))]
+pub mod serial;
+#[cfg(feature = "stats")]
+pub mod stats;
+mod validate;
+pub mod vendor;
+
+pub use owned::{OwnedCommand, OwnedResponse, Payload};
 
 pub mod prelude {
     pub use super::Encoder;
@@ -30,7 +74,7 @@ pub mod prelude {
 
 /// Commands supported by the protocol. A bootloader will decode these and a
 /// flash tool will encode them.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Command<'a> {
     /// Send a PING to the bootloader. It will drop its hp buffer and send
     /// back a PONG.
@@ -38,8 +82,8 @@ pub enum Command<'a> {
     /// Get info about the bootloader. The result is one byte of length, plus
     /// length bytes of string, followed by 192-length zeroes.
     Info,
-    /// Get the Unique ID. Result is 8 bytes of unique ID (but I'm not sure
-    /// what the result code should be).
+    /// Get the Unique ID. The result is a `Response::Id` holding 8 bytes
+    /// of unique ID.
     Id,
     /// Reset all TX and RX buffers.
     Reset,
@@ -71,9 +115,13 @@ pub enum Command<'a> {
     ExReadRange { address: u32, length: u16 },
     /// Write a payload attribute. The RX buffer should contain a one byte
     /// index, 8 bytes of key (null padded), one byte of value length, and
-    /// valuelength value bytes. valuelength must be less than or equal to 55.
+    /// valuelength value bytes. valuelength must be between 1 and 55.
     /// The value may contain nulls.
     ///
+    /// As an extension to the protocol, an all-zero key with a valuelength
+    /// of 0 clears the slot. This is the only time an empty value is
+    /// accepted.
+    ///
     /// The attribute index must be less than 16.
     SetAttr {
         index: u8,
@@ -111,11 +159,21 @@ pub enum Command<'a> {
     /// the new baud rate. If the next command does not match this, the
     /// bootloader will revert to the old baud rate.
     ChangeBaud { mode: BaudMode, baud: u32 },
+    /// Leave the bootloader and jump to the kernel. The bootloader doesn't
+    /// send a response.
+    Exit,
+    /// Set the address the bootloader jumps to when it starts the kernel.
+    /// The RX buffer should contain the 4 byte address.
+    SetStartAddress { address: u32 },
+    /// A vendor-specific command, with a code between
+    /// `vendor::FIRST_CODE` and `vendor::LAST_CODE`. The payload is
+    /// whatever was in the RX buffer.
+    Vendor { code: u8, payload: &'a [u8] },
 }
 
 /// Reponses supported by the protocol. A bootloader will encode these
 /// and a flash tool will decode them.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Response<'a> {
     Overflow, // RES_OVERFLOW
     Pong, // RES_PONG
@@ -134,37 +192,98 @@ pub enum Response<'a> {
     CrcExtFlash { crc: u32 }, // RES_CRCXF
     Info { info: &'a [u8] }, // RES_INFO
     ChangeBaudFail, // RES_CHANGE_BAUD_FAIL
+    Id { id: &'a [u8] }, // RES_ID
+    Vendor { code: u8, payload: &'a [u8] }, // vendor::FIRST_CODE..=vendor::LAST_CODE
 }
 
-#[derive(Debug, PartialEq)]
+/// Something went wrong encoding or decoding a message. Where it makes
+/// sense, the error carries the command or response code (`code`) it relates
+/// to, plus whatever value upset us.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum Error {
-    /// We got a command we didn't understand.
-    UnknownCommand,
-    /// We didn't like the arguments given with a command.
-    BadArguments,
+    /// We got a command (or response) code we didn't understand.
+    UnknownCommand { code: u8 },
+    /// A command or response had the wrong number of payload bytes.
+    BadArguments {
+        code: u8,
+        expected: usize,
+        actual: usize,
+    },
+    /// A `ChangeBaud` command had a mode byte other than 0x01 or 0x02.
+    BadMode { code: u8, mode: u8 },
+    /// An attribute index was not less than 16.
+    BadIndex { code: u8, index: u8 },
+    /// A field was the wrong length. `limit` is the required length for
+    /// fixed-size fields, or the maximum length for variable-sized ones.
+    BadLength {
+        code: u8,
+        field: Field,
+        limit: usize,
+        actual: usize,
+    },
+    /// An erase or write address wasn't aligned to the page (or block) size.
+    UnalignedAddress {
+        code: u8,
+        address: u32,
+        alignment: usize,
+    },
     /// The user didn't call `set_payload_len` yet we
     /// got a response of unbounded length.
-    UnsetLength,
+    UnsetLength { code: u8 },
     /// The user called `set_payload_len` yet we
     /// got a response of bounded length.
     SetLength,
+    /// We got a response which doesn't answer the command given to
+    /// `expect_response_to`.
+    UnexpectedResponse { code: u8, expected: u8 },
     /// The buffer passed by the user wasn't large enough for the packet.
-    BufferTooSmall,
+    BufferTooSmall { needed: usize, available: usize },
+    /// An attribute key was empty, had a character other than printable
+    /// ASCII, or wasn't null padded. `byte` is the first offending byte, and
+    /// `position` is where it is in the key.
+    BadKey { position: usize, byte: u8 },
+}
+
+/// Identifies the variable-length part of a message in `Error::BadLength`.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum Field {
+    /// An attribute key.
+    Key,
+    /// An attribute value.
+    Value,
+    /// A page of flash.
+    Data,
+    /// The bootloader info string.
+    Info,
+    /// A unique ID.
+    Id,
 }
 
 /// The `ComandDecoder` takes bytes and gives you `Command`s.
 pub struct CommandDecoder {
     state: DecoderState,
-    buffer: [u8; 520],
+    buffer: [u8; BUFFER_LEN],
     count: usize,
+    #[cfg(feature = "stats")]
+    stats: stats::Stats,
 }
 
 /// The `ResponseDecoder` takes bytes and gives you `Responses`s.
 pub struct ResponseDecoder {
     state: DecoderState,
-    buffer: [u8; 520],
+    buffer: [u8; BUFFER_LEN],
     count: usize,
     needed: Option<usize>,
+    /// The response code we're waiting for, if we know.
+    expected: Option<u8>,
+    /// Payload lengths for vendor-specific responses.
+    vendor_lens: [Option<u16>; VENDOR_CODES],
+    /// How many half-received responses we've thrown away.
+    resyncs: u32,
+    #[cfg(feature = "stats")]
+    stats: stats::Stats,
 }
 
 /// The `CommandEncoder` takes a `Command` and gives you bytes.
@@ -172,6 +291,9 @@ pub struct CommandEncoder<'a> {
     command: &'a Command<'a>,
     count: usize,
     sent_escape: bool,
+    sent: usize,
+    /// The encoded length, once something has asked for it.
+    length: Cell<Option<usize>>,
 }
 
 /// The `ResponseEncoder` takes a `Response` and gives you bytes.
@@ -179,9 +301,13 @@ pub struct ResponseEncoder<'a> {
     response: &'a Response<'a>,
     count: usize,
     sent_escape: bool,
+    sent: usize,
+    /// The encoded length, once something has asked for it.
+    length: Cell<Option<usize>>,
 }
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
 pub enum BaudMode {
     Set, // 0x01
     Verify, // 0x02
@@ -201,11 +327,29 @@ pub enum BaudMode {
 //
 // ****************************************************************************
 
+#[derive(PartialEq, Eq)]
 enum DecoderState {
     Loading,
     Escape,
 }
 
+/// The outcome of feeding one byte to a `ResponseDecoder`.
+enum Step {
+    /// We need more bytes.
+    Pending,
+    /// We saw a response which has no payload.
+    Bare(Response<'static>),
+    /// The RX buffer holds a complete response with payload.
+    Payload,
+}
+
+/// Writes whole frames into a caller-supplied buffer.
+struct FrameWriter<'a> {
+    /// Where to write to. If this is `None` we just count bytes.
+    buffer: Option<&'a mut [u8]>,
+    used: usize,
+}
+
 // ****************************************************************************
 //
 // Private Data
@@ -234,6 +378,8 @@ const CMD_XFINIT: u8 = 0x18;
 const CMD_CLKOUT: u8 = 0x19;
 const CMD_WUSER: u8 = 0x20;
 const CMD_CHANGE_BAUD: u8 = 0x21;
+const CMD_EXIT: u8 = 0x22;
+const CMD_SET_START_ADDRESS: u8 = 0x23;
 
 const RES_OVERFLOW: u8 = 0x10;
 const RES_PONG: u8 = 0x11;
@@ -252,13 +398,19 @@ const RES_CRCIF: u8 = 0x23;
 const RES_CRCXF: u8 = 0x24;
 const RES_INFO: u8 = 0x25;
 const RES_CHANGE_BAUD_FAIL: u8 = 0x26;
+const RES_ID: u8 = 0x27;
 
 const MAX_INDEX: u8 = 16;
 const KEY_LEN: usize = 8;
 const MAX_ATTR_LEN: usize = 55;
 const INT_PAGE_SIZE: usize = 512;
 const EXT_PAGE_SIZE: usize = 256;
+const EXT_BLOCK_SIZE: usize = 2048;
 const MAX_INFO_LEN: usize = 192;
+const ID_LEN: usize = 8;
+/// Size of the decoders' RX buffers.
+const BUFFER_LEN: usize = 520;
+const VENDOR_CODES: usize = (vendor::LAST_CODE - vendor::FIRST_CODE) as usize + 1;
 
 // ****************************************************************************
 //
@@ -286,6 +438,84 @@ pub trait Encoder: Iterator<Item = u8> {
     }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::UnknownCommand { code } => write!(f, "unknown code 0x{:02x}", code),
+            Error::BadArguments {
+                code,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "0x{:02x}: expected {} payload bytes, got {}",
+                code, expected, actual
+            ),
+            Error::BadMode { code, mode } => {
+                write!(f, "0x{:02x}: bad baud mode 0x{:02x}", code, mode)
+            }
+            Error::BadIndex { code, index } => write!(
+                f,
+                "0x{:02x}: attribute index {} is not less than {}",
+                code, index, MAX_INDEX
+            ),
+            Error::BadLength {
+                code,
+                field,
+                limit,
+                actual,
+            } => write!(
+                f,
+                "0x{:02x}: {} is {} bytes, limit is {}",
+                code, field, actual, limit
+            ),
+            Error::UnalignedAddress {
+                code,
+                address,
+                alignment,
+            } => write!(
+                f,
+                "0x{:02x}: address 0x{:08x} is not aligned to {} bytes",
+                code, address, alignment
+            ),
+            Error::UnsetLength { code } => {
+                write!(f, "0x{:02x}: payload length was not set", code)
+            }
+            Error::SetLength => write!(f, "payload length was already set"),
+            Error::UnexpectedResponse { code, expected } => write!(
+                f,
+                "got response 0x{:02x}, expected 0x{:02x}",
+                code, expected
+            ),
+            Error::BufferTooSmall { needed, available } => write!(
+                f,
+                "buffer too small: need {} bytes, have {}",
+                needed, available
+            ),
+            Error::BadKey { position, byte } => write!(
+                f,
+                "attribute key has bad byte 0x{:02x} at position {}",
+                byte, position
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Field::Key => "key",
+            Field::Value => "value",
+            Field::Data => "data",
+            Field::Info => "info",
+            Field::Id => "id",
+        })
+    }
+}
+
 impl CommandDecoder {
     /// Create a new `CommandDecoder`.
     ///
@@ -293,8 +523,10 @@ impl CommandDecoder {
     pub fn new() -> CommandDecoder {
         CommandDecoder {
             state: DecoderState::Loading,
-            buffer: [0u8; 520],
+            buffer: [0u8; BUFFER_LEN],
             count: 0,
+            #[cfg(feature = "stats")]
+            stats: stats::Stats::new(),
         }
     }
 
@@ -316,18 +548,59 @@ impl CommandDecoder {
         Ok(())
     }
 
-    /// Empty the RX buffer.
+    /// Decode bytes from a slice, stopping at the end of the first frame.
+    ///
+    /// Returns the number of bytes consumed, along with the decoded
+    /// `Command` (or the `Error` it produced) if a frame ended within those
+    /// bytes. If no frame ended, the whole slice is consumed and `None` is
+    /// returned. Call this again with the unconsumed bytes to carry on
+    /// decoding - the decoder is always ready for more after an error.
+    pub fn feed(&mut self, bytes: &[u8]) -> (usize, Option<Result<Command<'_>, Error>>) {
+        for (idx, ch) in bytes.iter().enumerate() {
+            if let Some(cmd) = self.step(*ch) {
+                return (idx + 1, self.finish(cmd).transpose());
+            }
+        }
+        (bytes.len(), None)
+    }
+
+    /// Empty the RX buffer, and forget any escape character we were part way
+    /// through.
     pub fn reset(&mut self) {
+        #[cfg(feature = "stats")]
+        self.stats.discard(self.count);
+        self.state = DecoderState::Loading;
         self.count = 0;
     }
 
+    /// What the decoder has seen so far.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> &stats::Stats {
+        &self.stats
+    }
+
+    /// Set all the counters in `stats` back to zero.
+    #[cfg(feature = "stats")]
+    pub fn clear_stats(&mut self) {
+        self.stats.clear();
+    }
+
     /// Process incoming bytes.
     ///
     /// The decoder is fed bytes with the `receive` method. If not enough
     /// bytes have been seen, this function returns `None`. Once enough bytes
     /// have been seen, it returns `Ok(Some(Command))` containing the decoded
     /// Command. It returns `Err` if it doesn't like the byte received.
-    pub fn receive(&mut self, ch: u8) -> Result<Option<Command>, Error> {
+    pub fn receive(&mut self, ch: u8) -> Result<Option<Command<'_>>, Error> {
+        match self.step(ch) {
+            Some(cmd) => self.finish(cmd),
+            None => Ok(None),
+        }
+    }
+
+    /// Run the framing state machine over one byte. Returns the command
+    /// byte once an escaped command we understand has been seen.
+    fn step(&mut self, ch: u8) -> Option<u8> {
         match self.state {
             DecoderState::Loading => self.handle_loading(ch),
             DecoderState::Escape => self.handle_escape(ch),
@@ -337,27 +610,51 @@ impl CommandDecoder {
     fn load_char(&mut self, ch: u8) {
         if self.count < self.buffer.len() {
             self.buffer[self.count] = ch;
-            self.count = self.count + 1;
+            self.count += 1;
+        } else {
+            #[cfg(feature = "stats")]
+            self.stats.overflow();
         }
     }
 
-    fn handle_loading(&mut self, ch: u8) -> Result<Option<Command>, Error> {
+    fn handle_loading(&mut self, ch: u8) -> Option<u8> {
         if ch == ESCAPE_CHAR {
+            #[cfg(feature = "stats")]
+            self.stats.escape();
             self.state = DecoderState::Escape;
         } else {
             self.load_char(ch);
         }
-        Ok(None)
+        None
     }
 
-    fn handle_escape(&mut self, ch: u8) -> Result<Option<Command>, Error> {
+    fn handle_escape(&mut self, ch: u8) -> Option<u8> {
         self.state = DecoderState::Loading;
-        let result: Result<Option<Command>, Error> = match ch {
+        match ch {
             ESCAPE_CHAR => {
                 // Double escape means just load an escape
+                #[cfg(feature = "stats")]
+                self.stats.double_escape();
                 self.load_char(ch);
-                Ok(None)
+                None
             }
+            CMD_PING | CMD_INFO | CMD_ID | CMD_RESET | CMD_EPAGE | CMD_WPAGE | CMD_XEBLOCK
+            | CMD_XWPAGE | CMD_CRCRX | CMD_RRANGE | CMD_XRRANGE | CMD_SATTR | CMD_GATTR
+            | CMD_CRCIF | CMD_CRCEF | CMD_XEPAGE | CMD_XFINIT | CMD_CLKOUT | CMD_WUSER
+            | CMD_CHANGE_BAUD | CMD_EXIT | CMD_SET_START_ADDRESS => Some(ch),
+            vendor::FIRST_CODE..=vendor::LAST_CODE => Some(ch),
+            _ => {
+                #[cfg(feature = "stats")]
+                self.stats.unknown_code();
+                None
+            }
+        }
+    }
+
+    /// Decode the RX buffer as the arguments to the given command, then
+    /// empty it ready for the next command.
+    fn finish(&mut self, cmd: u8) -> Result<Option<Command<'_>>, Error> {
+        let result: Result<Option<Command>, Error> = match cmd {
             CMD_PING => Ok(Some(Command::Ping)),
             CMD_INFO => Ok(Some(Command::Info)),
             CMD_ID => Ok(Some(Command::Id)),
@@ -368,7 +665,11 @@ impl CommandDecoder {
                     let address = LittleEndian::read_u32(&self.buffer[0..4]);
                     Ok(Some(Command::ErasePage { address }))
                 } else {
-                    Err(Error::BadArguments)
+                    Err(Error::BadArguments {
+                        code: cmd,
+                        expected: num_expected_bytes,
+                        actual: self.count,
+                    })
                 }
             }
             CMD_WPAGE => {
@@ -381,7 +682,11 @@ impl CommandDecoder {
                         data: &payload[4..num_expected_bytes],
                     }))
                 } else {
-                    Err(Error::BadArguments)
+                    Err(Error::BadArguments {
+                        code: cmd,
+                        expected: num_expected_bytes,
+                        actual: self.count,
+                    })
                 }
             }
             CMD_XEBLOCK => {
@@ -390,7 +695,11 @@ impl CommandDecoder {
                     let address = LittleEndian::read_u32(&self.buffer[0..4]);
                     Ok(Some(Command::EraseExBlock { address }))
                 } else {
-                    Err(Error::BadArguments)
+                    Err(Error::BadArguments {
+                        code: cmd,
+                        expected: num_expected_bytes,
+                        actual: self.count,
+                    })
                 }
             }
             CMD_XWPAGE => {
@@ -403,7 +712,11 @@ impl CommandDecoder {
                         data: &payload[4..num_expected_bytes],
                     }))
                 } else {
-                    Err(Error::BadArguments)
+                    Err(Error::BadArguments {
+                        code: cmd,
+                        expected: num_expected_bytes,
+                        actual: self.count,
+                    })
                 }
             }
             CMD_CRCRX => Ok(Some(Command::CrcRxBuffer)),
@@ -414,7 +727,11 @@ impl CommandDecoder {
                     let length = LittleEndian::read_u16(&self.buffer[4..6]);
                     Ok(Some(Command::ReadRange { address, length }))
                 } else {
-                    Err(Error::BadArguments)
+                    Err(Error::BadArguments {
+                        code: cmd,
+                        expected: num_expected_bytes,
+                        actual: self.count,
+                    })
                 }
             }
             CMD_XRRANGE => {
@@ -424,7 +741,11 @@ impl CommandDecoder {
                     let length = LittleEndian::read_u16(&self.buffer[4..6]);
                     Ok(Some(Command::ExReadRange { address, length }))
                 } else {
-                    Err(Error::BadArguments)
+                    Err(Error::BadArguments {
+                        code: cmd,
+                        expected: num_expected_bytes,
+                        actual: self.count,
+                    })
                 }
             }
             CMD_SATTR => {
@@ -437,10 +758,18 @@ impl CommandDecoder {
                         let value = &self.buffer[10..10 + length];
                         Ok(Some(Command::SetAttr { index, key, value }))
                     } else {
-                        Err(Error::BadArguments)
+                        Err(Error::BadArguments {
+                            code: cmd,
+                            expected: num_expected_bytes + length,
+                            actual: self.count,
+                        })
                     }
                 } else {
-                    Err(Error::BadArguments)
+                    Err(Error::BadArguments {
+                        code: cmd,
+                        expected: num_expected_bytes,
+                        actual: self.count,
+                    })
                 }
             }
             CMD_GATTR => {
@@ -449,7 +778,11 @@ impl CommandDecoder {
                     let index = self.buffer[0];
                     Ok(Some(Command::GetAttr { index }))
                 } else {
-                    Err(Error::BadArguments)
+                    Err(Error::BadArguments {
+                        code: cmd,
+                        expected: num_expected_bytes,
+                        actual: self.count,
+                    })
                 }
             }
             CMD_CRCIF => {
@@ -459,7 +792,11 @@ impl CommandDecoder {
                     let length = LittleEndian::read_u32(&self.buffer[4..8]);
                     Ok(Some(Command::CrcIntFlash { address, length }))
                 } else {
-                    Err(Error::BadArguments)
+                    Err(Error::BadArguments {
+                        code: cmd,
+                        expected: num_expected_bytes,
+                        actual: self.count,
+                    })
                 }
             }
             CMD_CRCEF => {
@@ -469,7 +806,11 @@ impl CommandDecoder {
                     let length = LittleEndian::read_u32(&self.buffer[4..8]);
                     Ok(Some(Command::CrcExtFlash { address, length }))
                 } else {
-                    Err(Error::BadArguments)
+                    Err(Error::BadArguments {
+                        code: cmd,
+                        expected: num_expected_bytes,
+                        actual: self.count,
+                    })
                 }
             }
             CMD_XEPAGE => {
@@ -478,7 +819,11 @@ impl CommandDecoder {
                     let address = LittleEndian::read_u32(&self.buffer[0..4]);
                     Ok(Some(Command::EraseExPage { address }))
                 } else {
-                    Err(Error::BadArguments)
+                    Err(Error::BadArguments {
+                        code: cmd,
+                        expected: num_expected_bytes,
+                        actual: self.count,
+                    })
                 }
             }
             CMD_XFINIT => Ok(Some(Command::ExtFlashInit)),
@@ -490,7 +835,11 @@ impl CommandDecoder {
                     let page2 = LittleEndian::read_u32(&self.buffer[4..8]);
                     Ok(Some(Command::WriteFlashUserPages { page1, page2 }))
                 } else {
-                    Err(Error::BadArguments)
+                    Err(Error::BadArguments {
+                        code: cmd,
+                        expected: num_expected_bytes,
+                        actual: self.count,
+                    })
                 }
             }
             CMD_CHANGE_BAUD => {
@@ -507,15 +856,46 @@ impl CommandDecoder {
                             mode: BaudMode::Verify,
                             baud,
                         })),
-                        _ => Err(Error::BadArguments),
-
+                        _ => Err(Error::BadMode { code: cmd, mode }),
                     }
                 } else {
-                    Err(Error::BadArguments)
+                    Err(Error::BadArguments {
+                        code: cmd,
+                        expected: num_expected_bytes,
+                        actual: self.count,
+                    })
+                }
+            }
+            CMD_EXIT => Ok(Some(Command::Exit)),
+            vendor::FIRST_CODE..=vendor::LAST_CODE => Ok(Some(Command::Vendor {
+                code: cmd,
+                payload: &self.buffer[0..self.count],
+            })),
+            CMD_SET_START_ADDRESS => {
+                let num_expected_bytes: usize = 4;
+                if self.count == num_expected_bytes {
+                    let address = LittleEndian::read_u32(&self.buffer[0..4]);
+                    Ok(Some(Command::SetStartAddress { address }))
+                } else {
+                    Err(Error::BadArguments {
+                        code: cmd,
+                        expected: num_expected_bytes,
+                        actual: self.count,
+                    })
                 }
             }
             _ => Ok(None),
         };
+        let result = match result {
+            Ok(Some(command)) => validate::command(&command).map(|_| Some(command)),
+            other => other,
+        };
+        #[cfg(feature = "stats")]
+        match result {
+            Ok(Some(ref command)) => self.stats.frame(command.code()),
+            Err(ref e) => self.stats.error(e, self.count),
+            Ok(None) => {}
+        }
         // A command or error signifies the end of the buffer
         if let Ok(Some(_)) = result {
             self.count = 0;
@@ -533,9 +913,14 @@ impl ResponseDecoder {
     pub fn new() -> ResponseDecoder {
         ResponseDecoder {
             state: DecoderState::Loading,
-            buffer: [0u8; 520],
+            buffer: [0u8; BUFFER_LEN],
             count: 0,
             needed: None,
+            expected: None,
+            vendor_lens: [None; VENDOR_CODES],
+            resyncs: 0,
+            #[cfg(feature = "stats")]
+            stats: stats::Stats::new(),
         }
     }
 
@@ -557,9 +942,74 @@ impl ResponseDecoder {
         Ok(())
     }
 
+    /// Decode bytes from a slice, stopping at the end of the first frame.
+    ///
+    /// Returns the number of bytes consumed, along with the decoded
+    /// `Response` (or the `Error` it produced) if a frame ended within those
+    /// bytes. If no frame ended, the whole slice is consumed and `None` is
+    /// returned. Call this again with the unconsumed bytes to carry on
+    /// decoding.
+    pub fn feed(&mut self, bytes: &[u8]) -> (usize, Option<Result<Response<'_>, Error>>) {
+        for (idx, ch) in bytes.iter().enumerate() {
+            match self.step(*ch) {
+                Ok(Step::Pending) => {}
+                Ok(step) => return (idx + 1, self.finish(step).transpose()),
+                Err(e) => return (idx + 1, Some(Err(e))),
+            }
+        }
+        (bytes.len(), None)
+    }
+
     /// Empty the RX buffer.
     pub fn reset(&mut self) {
+        #[cfg(feature = "stats")]
+        self.stats.discard(self.count);
+        self.state = DecoderState::Loading;
+        self.count = 0;
+    }
+
+    /// Tell the decoder how long it has been since a byte arrived. If that's
+    /// more than `timeout` (in the same units) and a response was part way
+    /// through, the rest of it isn't coming, so it's thrown away as with
+    /// `resync`. Returns `true` if that happened.
+    pub fn check_idle(&mut self, idle: u32, timeout: u32) -> bool {
+        let in_frame = self.count > 0 || self.state == DecoderState::Escape;
+        if in_frame && idle > timeout {
+            self.resync();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Throw away any half-received response, including the payload length
+    /// and expected response code, ready to start afresh.
+    pub fn resync(&mut self) {
+        #[cfg(feature = "stats")]
+        self.stats.discard(self.count);
+        self.state = DecoderState::Loading;
         self.count = 0;
+        self.needed = None;
+        self.expected = None;
+        self.resyncs = self.resyncs.wrapping_add(1);
+    }
+
+    /// How many times the decoder has been resynchronised, by `resync` or
+    /// `check_idle`.
+    pub fn resyncs(&self) -> u32 {
+        self.resyncs
+    }
+
+    /// What the decoder has seen so far.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> &stats::Stats {
+        &self.stats
+    }
+
+    /// Set all the counters in `stats` back to zero.
+    #[cfg(feature = "stats")]
+    pub fn clear_stats(&mut self) {
+        self.stats.clear();
     }
 
     /// Process incoming bytes.
@@ -568,11 +1018,57 @@ impl ResponseDecoder {
     /// bytes have been seen, this function returns `None`. Once enough bytes
     /// have been seen, it returns `Some(Response)` containing the
     /// decoded Response.
-    pub fn receive(&mut self, ch: u8) -> Result<Option<Response>, Error> {
-        match self.state {
-            DecoderState::Loading => self.handle_loading(ch),
-            DecoderState::Escape => self.handle_escape(ch),
+    pub fn receive(&mut self, ch: u8) -> Result<Option<Response<'_>>, Error> {
+        let step = self.step(ch)?;
+        self.finish(step)
+    }
+
+    /// Prepare to decode the response to `command`, which you have just
+    /// sent.
+    ///
+    /// This sets the payload length for the response to `ReadRange` and
+    /// `ExReadRange`, so you don't need to call `set_payload_len`. Any other
+    /// kind of successful response then gives `Error::UnexpectedResponse`.
+    /// Failure responses like `BadAddress` are always accepted.
+    pub fn expect_response_to(&mut self, command: &Command) -> Result<(), Error> {
+        self.needed = None;
+        self.expected = None;
+        match *command {
+            Command::ReadRange { length, .. } | Command::ExReadRange { length, .. } => {
+                let length = length as usize;
+                if length >= BUFFER_LEN {
+                    return Err(Error::BadLength {
+                        code: command.code(),
+                        field: Field::Data,
+                        limit: BUFFER_LEN - 1,
+                        actual: length,
+                    });
+                }
+                self.needed = Some(length + 1);
+            }
+            _ => {}
+        }
+        self.expected = command.response_code();
+        Ok(())
+    }
+
+    /// Set the payload length of a vendor-specific response. This is used
+    /// whenever that response arrives, unless `set_payload_len` has been
+    /// called.
+    pub fn set_vendor_len(&mut self, code: u8, length: usize) -> Result<(), Error> {
+        if !vendor::is_vendor_code(code) {
+            return Err(Error::UnknownCommand { code });
         }
+        if length >= BUFFER_LEN {
+            return Err(Error::BadLength {
+                code,
+                field: Field::Data,
+                limit: BUFFER_LEN - 1,
+                actual: length,
+            });
+        }
+        self.vendor_lens[(code - vendor::FIRST_CODE) as usize] = Some(length as u16);
+        Ok(())
     }
 
     /// Set the expected length of an unbounded message. This
@@ -587,173 +1083,227 @@ impl ResponseDecoder {
         }
     }
 
-    fn load_char(&mut self, ch: u8) -> Result<Option<Response>, Error> {
-        if self.count < self.buffer.len() {
-            self.buffer[self.count] = ch;
-            self.count = self.count + 1;
+    /// Run the framing state machine over one byte.
+    fn step(&mut self, ch: u8) -> Result<Step, Error> {
+        match self.state {
+            DecoderState::Loading => self.handle_loading(ch),
+            DecoderState::Escape => self.handle_escape(ch),
         }
-        if self.needed == Some(self.count) {
-            let result = match self.buffer[0] {
-                RES_CRCRX => {
-                    let length = LittleEndian::read_u16(&self.buffer[1..3]);
-                    let crc = LittleEndian::read_u32(&self.buffer[3..7]);
-                    Ok(Some(Response::CrcRxBuffer { length, crc }))
-                }
-                RES_RRANGE => {
-                    let data = &self.buffer[1..self.count];
-                    Ok(Some(Response::ReadRange { data }))
-                }
-                RES_XRRANGE => {
-                    let data = &self.buffer[1..self.count];
-                    Ok(Some(Response::ExReadRange { data }))
-                }
-                RES_GATTR => {
-                    let key = &self.buffer[1..9];
-                    let length = self.buffer[9] as usize;
-                    if (9 + length) <= self.count {
-                        let value = &self.buffer[10..(10 + length)];
-                        Ok(Some(Response::GetAttr { key, value }))
-                    } else {
-                        Err(Error::BadArguments)
-                    }
-                }
-                RES_CRCIF => {
-                    let crc = LittleEndian::read_u32(&self.buffer[1..5]);
-                    Ok(Some(Response::CrcIntFlash { crc }))
-                }
-                RES_CRCXF => {
-                    let crc = LittleEndian::read_u32(&self.buffer[1..5]);
-                    Ok(Some(Response::CrcExtFlash { crc }))
-                }
-                RES_INFO => {
-                    let length: usize = self.buffer[1] as usize;
-                    if length + 1 < self.count {
-                        let info = &self.buffer[2..length + 2];
-                        Ok(Some(Response::Info { info }))
-                    } else {
-                        Err(Error::BadArguments)
-                    }
-                }
-                _ => Err(Error::UnknownCommand),
-            };
-            self.needed = None;
-            self.count = 0;
-            result
-        } else {
-            Ok(None)
+    }
+
+    /// Turn the outcome of `step` into a `Response`.
+    fn finish(&mut self, step: Step) -> Result<Option<Response<'_>>, Error> {
+        match step {
+            Step::Pending => Ok(None),
+            Step::Bare(response) => {
+                #[cfg(feature = "stats")]
+                self.stats.frame(response.code());
+                Ok(Some(response))
+            }
+            Step::Payload => self.decode_payload(),
         }
     }
 
-    fn handle_loading(&mut self, ch: u8) -> Result<Option<Response>, Error> {
-        if ch == ESCAPE_CHAR {
-            self.state = DecoderState::Escape;
-            Ok(None)
+    fn load_char(&mut self, ch: u8) -> Result<Step, Error> {
+        if self.count < self.buffer.len() {
+            self.buffer[self.count] = ch;
+            self.count += 1;
         } else {
-            self.load_char(ch)
+            #[cfg(feature = "stats")]
+            self.stats.overflow();
+        }
+        if self.needed == Some(self.count) {
+            Ok(Step::Payload)
+        } else {
+            Ok(Step::Pending)
         }
     }
 
-    fn handle_escape(&mut self, ch: u8) -> Result<Option<Response>, Error> {
-        self.state = DecoderState::Loading;
-        match ch {
-            ESCAPE_CHAR => {
-                // Double escape means just load an escape
-                self.load_char(ch)
+    /// Decode the RX buffer, which holds a response byte followed by
+    /// exactly as much payload as we were expecting.
+    fn decode_payload(&mut self) -> Result<Option<Response<'_>>, Error> {
+        let result = match self.buffer[0] {
+            RES_CRCRX => {
+                let length = LittleEndian::read_u16(&self.buffer[1..3]);
+                let crc = LittleEndian::read_u32(&self.buffer[3..7]);
+                Ok(Some(Response::CrcRxBuffer { length, crc }))
             }
-            RES_PONG => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::Pong))
+            RES_RRANGE => {
+                let data = &self.buffer[1..self.count];
+                Ok(Some(Response::ReadRange { data }))
             }
-            RES_OVERFLOW => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::Overflow))
+            RES_XRRANGE => {
+                let data = &self.buffer[1..self.count];
+                Ok(Some(Response::ExReadRange { data }))
             }
-            RES_BADADDR => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::BadAddress))
+            RES_GATTR => {
+                let key = &self.buffer[1..9];
+                let length = self.buffer[9] as usize;
+                if (9 + length) <= self.count {
+                    let value = &self.buffer[10..(10 + length)];
+                    Ok(Some(Response::GetAttr { key, value }))
+                } else {
+                    Err(Error::BadArguments {
+                        code: RES_GATTR,
+                        expected: 9 + length,
+                        actual: self.count - 1,
+                    })
+                }
             }
-            RES_INTERROR => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::InternalError))
+            RES_CRCIF => {
+                let crc = LittleEndian::read_u32(&self.buffer[1..5]);
+                Ok(Some(Response::CrcIntFlash { crc }))
             }
-            RES_BADARGS => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::BadArguments))
+            RES_CRCXF => {
+                let crc = LittleEndian::read_u32(&self.buffer[1..5]);
+                Ok(Some(Response::CrcExtFlash { crc }))
             }
-            RES_OK => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::Ok))
+            RES_INFO => {
+                let length: usize = self.buffer[1] as usize;
+                if length + 1 < self.count {
+                    let info = &self.buffer[2..length + 2];
+                    Ok(Some(Response::Info { info }))
+                } else {
+                    Err(Error::BadArguments {
+                        code: RES_INFO,
+                        expected: 1 + length,
+                        actual: self.count - 1,
+                    })
+                }
             }
-            RES_UNKNOWN => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::Unknown))
+            RES_ID => {
+                let id = &self.buffer[1..self.count];
+                Ok(Some(Response::Id { id }))
             }
-            RES_XFTIMEOUT => {
-                self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::ExtFlashTimeout))
+            code @ vendor::FIRST_CODE..=vendor::LAST_CODE => {
+                let payload = &self.buffer[1..self.count];
+                Ok(Some(Response::Vendor { code, payload }))
             }
-            RES_XFEPE => {
-                self.count = 0;
+            code => Err(Error::UnknownCommand { code }),
+        };
+        let result = match result {
+            Ok(Some(response)) => validate::response(&response).map(|_| Some(response)),
+            other => other,
+        };
+        #[cfg(feature = "stats")]
+        match result {
+            Ok(Some(ref response)) => self.stats.frame(response.code()),
+            Err(ref e) => self.stats.error(e, self.count),
+            Ok(None) => {}
+        }
+        self.needed = None;
+        self.count = 0;
+        result
+    }
+
+    fn handle_loading(&mut self, ch: u8) -> Result<Step, Error> {
+        if ch == ESCAPE_CHAR {
+            #[cfg(feature = "stats")]
+            self.stats.escape();
+            self.state = DecoderState::Escape;
+            Ok(Step::Pending)
+        } else {
+            self.load_char(ch)
+        }
+    }
+
+    /// Check a response code against the one `expect_response_to` told us
+    /// to wait for.
+    fn check_expected(&mut self, code: u8) -> Result<(), Error> {
+        let expected = match self.expected {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+        match code {
+            c if c == expected => {}
+            // The bootloader can always tell us it failed
+            RES_OVERFLOW | RES_BADADDR | RES_INTERROR | RES_BADARGS | RES_UNKNOWN
+            | RES_XFTIMEOUT | RES_XFEPE | RES_CHANGE_BAUD_FAIL => {}
+            RES_PONG | RES_OK | RES_CRCRX | RES_RRANGE | RES_XRRANGE | RES_GATTR
+            | RES_CRCIF | RES_CRCXF | RES_INFO | RES_ID
+            | vendor::FIRST_CODE..=vendor::LAST_CODE => {
+                #[cfg(feature = "stats")]
+                self.stats.discard(self.count);
+                self.expected = None;
                 self.needed = None;
-                Ok(Some(Response::ExtFlashPageError))
-            }
-            RES_CHANGE_BAUD_FAIL => {
                 self.count = 0;
-                self.needed = None;
-                Ok(Some(Response::ChangeBaudFail))
+                return Err(Error::UnexpectedResponse { code, expected });
             }
+            // Not a response code, so it'll be ignored
+            _ => return Ok(()),
+        }
+        self.expected = None;
+        Ok(())
+    }
+
+    fn handle_escape(&mut self, ch: u8) -> Result<Step, Error> {
+        self.state = DecoderState::Loading;
+        if ch != ESCAPE_CHAR {
+            self.check_expected(ch)?;
+        }
+        let response = match ch {
+            ESCAPE_CHAR => {
+                // Double escape means just load an escape
+                #[cfg(feature = "stats")]
+                self.stats.double_escape();
+                return self.load_char(ch);
+            }
+            RES_PONG => Response::Pong,
+            RES_OVERFLOW => Response::Overflow,
+            RES_BADADDR => Response::BadAddress,
+            RES_INTERROR => Response::InternalError,
+            RES_BADARGS => Response::BadArguments,
+            RES_OK => Response::Ok,
+            RES_UNKNOWN => Response::Unknown,
+            RES_XFTIMEOUT => Response::ExtFlashTimeout,
+            RES_XFEPE => Response::ExtFlashPageError,
+            RES_CHANGE_BAUD_FAIL => Response::ChangeBaudFail,
             RES_CRCRX => {
                 self.set_payload_len(6)?;
-                self.load_char(ch)?;
-                Ok(None)
-            }
-            RES_RRANGE => {
-                if self.needed.is_none() {
-                    Err(Error::UnsetLength)
-                } else {
-                    self.load_char(ch)?;
-                    Ok(None)
-                }
+                return self.load_char(ch);
             }
-            RES_XRRANGE => {
+            RES_RRANGE | RES_XRRANGE => {
                 if self.needed.is_none() {
-                    Err(Error::UnsetLength)
-                } else {
-                    self.load_char(ch)?;
-                    Ok(None)
+                    return Err(Error::UnsetLength { code: ch });
                 }
+                return self.load_char(ch);
             }
             RES_GATTR => {
                 self.set_payload_len(1 + KEY_LEN + MAX_ATTR_LEN)?;
-                self.load_char(ch)?;
-                Ok(None)
+                return self.load_char(ch);
             }
-            RES_CRCIF => {
+            RES_CRCIF | RES_CRCXF => {
                 self.set_payload_len(4)?;
-                self.load_char(ch)?;
-                Ok(None)
+                return self.load_char(ch);
             }
-            RES_CRCXF => {
-                self.set_payload_len(4)?;
-                self.load_char(ch)?;
-                Ok(None)
+            RES_ID => {
+                self.set_payload_len(ID_LEN)?;
+                return self.load_char(ch);
+            }
+            vendor::FIRST_CODE..=vendor::LAST_CODE => {
+                if self.needed.is_none() {
+                    match self.vendor_lens[(ch - vendor::FIRST_CODE) as usize] {
+                        Some(length) => self.set_payload_len(length as usize)?,
+                        None => return Err(Error::UnsetLength { code: ch }),
+                    }
+                }
+                return self.load_char(ch);
             }
             RES_INFO => {
                 // length + data
                 self.set_payload_len(1 + MAX_INFO_LEN)?;
-                self.load_char(ch)?;
-                Ok(None)
+                return self.load_char(ch);
             }
-            _ => Ok(None),
-        }
+            _ => {
+                #[cfg(feature = "stats")]
+                self.stats.unknown_code();
+                return Ok(Step::Pending);
+            }
+        };
+        // Responses without a payload end the frame here
+        self.count = 0;
+        self.needed = None;
+        Ok(Step::Bare(response))
     }
 }
 
@@ -765,34 +1315,13 @@ impl<'a> CommandEncoder<'a> {
     pub fn new(command: &'a Command) -> Result<CommandEncoder<'a>, Error> {
         // We have to accept slices rather than arrays, so bounds check them
         // all now to save surprises later.
-        match command {
-            &Command::WritePage { address: _, data } => {
-                if data.len() != INT_PAGE_SIZE {
-                    return Err(Error::BadArguments);
-                }
-            }
-            &Command::WriteExPage { address: _, data } => {
-                if data.len() != EXT_PAGE_SIZE {
-                    return Err(Error::BadArguments);
-                }
-            }
-            &Command::SetAttr { index, key, value } => {
-                if index > MAX_INDEX {
-                    return Err(Error::BadArguments);
-                }
-                if key.len() != KEY_LEN {
-                    return Err(Error::BadArguments);
-                }
-                if value.len() > MAX_ATTR_LEN {
-                    return Err(Error::BadArguments);
-                }
-            }
-            _ => {}
-        };
+        validate::command(command)?;
         Ok(CommandEncoder {
-            command: command,
+            command,
             count: 0,
             sent_escape: false,
+            sent: 0,
+            length: Cell::new(None),
         })
     }
 
@@ -801,6 +1330,42 @@ impl<'a> CommandEncoder<'a> {
     pub fn reset(&mut self) {
         self.count = 0;
         self.sent_escape = false;
+        self.sent = 0;
+    }
+
+    /// Encode the whole `Command` into `buffer` in one go.
+    ///
+    /// This produces exactly the same bytes as the `Iterator`
+    /// implementation, but copies fields and payloads in bulk so it is much
+    /// quicker for large commands like `WritePage`. It always encodes from
+    /// the start of the command, regardless of how far the iterator has got.
+    /// Returns the number of bytes written, or `Error::BufferTooSmall` if
+    /// the command doesn't fit, in which case the contents of `buffer` are
+    /// unspecified.
+    pub fn encode_into(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let mut w = FrameWriter::new(buffer);
+        match encode_command(self.command, &mut w) {
+            Ok(()) => Ok(w.used),
+            Err(Error::BufferTooSmall { available, .. }) => Err(Error::BufferTooSmall {
+                needed: self.encoded_len(),
+                available,
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The number of bytes this `Command` occupies on the wire, including
+    /// any escaping and padding. The first call has to look through the
+    /// whole command, escaping included, and the answer is kept for later.
+    pub fn encoded_len(&self) -> usize {
+        if let Some(length) = self.length.get() {
+            return length;
+        }
+        let mut counter = FrameWriter::counter();
+        // Counting can't run out of space
+        let _ = encode_command(self.command, &mut counter);
+        self.length.set(Some(counter.used));
+        counter.used
     }
 
     fn render_byte(&mut self, byte: u8) -> (usize, Option<u8>) {
@@ -980,6 +1545,22 @@ impl<'a> CommandEncoder<'a> {
             _ => self.render_basic_cmd(count - 5, CMD_CHANGE_BAUD),
         }
     }
+
+    fn render_vendor(&mut self, code: u8, payload: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            x if x < payload.len() => self.render_byte(payload[x]),
+            _ => self.render_basic_cmd(count - payload.len(), code),
+        }
+    }
+
+    fn render_setstartaddress(&mut self, address: u32) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0...3 => self.render_u32(count, address),
+            _ => self.render_basic_cmd(count - 4, CMD_SET_START_ADDRESS),
+        }
+    }
 }
 
 impl<'a> Iterator for CommandEncoder<'a> {
@@ -1012,18 +1593,32 @@ impl<'a> Iterator for CommandEncoder<'a> {
                 self.render_writeflashuserpages(page1, page2)
             }
             &Command::ChangeBaud { mode, baud } => self.render_changebaud(mode, baud),
+            &Command::Exit => self.render_basic_cmd(count, CMD_EXIT),
+            &Command::SetStartAddress { address } => self.render_setstartaddress(address),
+            &Command::Vendor { code, payload } => self.render_vendor(code, payload),
         };
-        self.count = self.count + inc;
+        self.count += inc;
+        if result.is_some() {
+            self.sent += 1;
+        }
         result
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.encoded_len() - self.sent;
+        (remaining, Some(remaining))
+    }
 }
 
+impl<'a> ExactSizeIterator for CommandEncoder<'a> {}
+
 impl<'a> Encoder for CommandEncoder<'a> {
     /// Reset the `Encoder`, so that next time you call `self.next()`
     /// you get the first byte again.
     fn reset(&mut self) {
         self.count = 0;
         self.sent_escape = false;
+        self.sent = 0;
     }
 }
 
@@ -1033,29 +1628,51 @@ impl<'a> ResponseEncoder<'a> {
     /// The encoder takes a reference to a `Command` to encode. The `next` method
     /// will then supply the encoded bytes one at a time.
     pub fn new(response: &'a Response) -> Result<ResponseEncoder<'a>, Error> {
-        match response {
-            &Response::GetAttr { key, value } => {
-                if key.len() != KEY_LEN {
-                    return Err(Error::BadArguments);
-                }
-                if value.len() > MAX_ATTR_LEN {
-                    return Err(Error::BadArguments);
-                }
-            }
-            &Response::Info { info } => {
-                if info.len() > MAX_INFO_LEN {
-                    return Err(Error::BadArguments);
-                }
-            }
-            _ => {}
-        }
+        validate::response(response)?;
         Ok(ResponseEncoder {
-            response: response,
+            response,
             count: 0,
             sent_escape: false,
+            sent: 0,
+            length: Cell::new(None),
         })
     }
 
+    /// Encode the whole `Response` into `buffer` in one go.
+    ///
+    /// This produces exactly the same bytes as the `Iterator`
+    /// implementation, but copies fields and payloads in bulk so it is much
+    /// quicker for large responses like `ReadRange`. It always encodes from
+    /// the start of the response, regardless of how far the iterator has
+    /// got. Returns the number of bytes written, or `Error::BufferTooSmall`
+    /// if the response doesn't fit, in which case the contents of `buffer`
+    /// are unspecified.
+    pub fn encode_into(&self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let mut w = FrameWriter::new(buffer);
+        match encode_response(self.response, &mut w) {
+            Ok(()) => Ok(w.used),
+            Err(Error::BufferTooSmall { available, .. }) => Err(Error::BufferTooSmall {
+                needed: self.encoded_len(),
+                available,
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The number of bytes this `Response` occupies on the wire, including
+    /// any escaping and padding. The first call has to look through the
+    /// whole response, escaping included, and the answer is kept for later.
+    pub fn encoded_len(&self) -> usize {
+        if let Some(length) = self.length.get() {
+            return length;
+        }
+        let mut counter = FrameWriter::counter();
+        // Counting can't run out of space
+        let _ = encode_response(self.response, &mut counter);
+        self.length.set(Some(counter.used));
+        counter.used
+    }
+
     fn render_byte(&mut self, byte: u8) -> (usize, Option<u8>) {
         if byte == ESCAPE_CHAR {
             if self.sent_escape {
@@ -1133,6 +1750,23 @@ impl<'a> ResponseEncoder<'a> {
         }
     }
 
+    fn render_vendor(&mut self, code: u8, payload: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0...1 => self.render_header(count, code),
+            x if x - 2 < payload.len() => self.render_byte(payload[x - 2]),
+            _ => (0, None),
+        }
+    }
+
+    fn render_id(&mut self, id: &[u8]) -> (usize, Option<u8>) {
+        let count = self.count;
+        match count {
+            0...1 => self.render_header(count, RES_ID),
+            _ => self.render_buffer(count - 2, ID_LEN, id),
+        }
+    }
+
     fn render_u16(&mut self, idx: usize, value: u16) -> (usize, Option<u8>) {
         match idx {
             0 => self.render_byte(value as u8),
@@ -1170,10 +1804,13 @@ impl<'a> ResponseEncoder<'a> {
     }
 }
 
+impl<'a> ExactSizeIterator for ResponseEncoder<'a> {}
+
 impl<'a> Encoder for ResponseEncoder<'a> {
     fn reset(&mut self) {
         self.count = 0;
         self.sent_escape = false;
+        self.sent = 0;
     }
 }
 
@@ -1202,10 +1839,20 @@ impl<'a> Iterator for ResponseEncoder<'a> {
             &Response::CrcExtFlash { crc } => self.render_crc_ex_flash(crc),
             &Response::Info { info } => self.render_info(info),
             &Response::ChangeBaudFail => self.render_header(count, RES_CHANGE_BAUD_FAIL),
+            &Response::Id { id } => self.render_id(id),
+            &Response::Vendor { code, payload } => self.render_vendor(code, payload),
         };
-        self.count = self.count + inc;
+        self.count += inc;
+        if result.is_some() {
+            self.sent += 1;
+        }
         result
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.encoded_len() - self.sent;
+        (remaining, Some(remaining))
+    }
 }
 
 // ****************************************************************************
@@ -1214,7 +1861,331 @@ impl<'a> Iterator for ResponseEncoder<'a> {
 //
 // ****************************************************************************
 
+impl<'a> FrameWriter<'a> {
+    fn new(buffer: &'a mut [u8]) -> FrameWriter<'a> {
+        FrameWriter {
+            buffer: Some(buffer),
+            used: 0,
+        }
+    }
+
+    /// A `FrameWriter` which only counts how many bytes would be written.
+    fn counter() -> FrameWriter<'a> {
+        FrameWriter {
+            buffer: None,
+            used: 0,
+        }
+    }
+
+    /// Get the next `len` bytes of the output buffer, if we have one.
+    fn reserve(&mut self, len: usize) -> Result<Option<&mut [u8]>, Error> {
+        let start = self.used;
+        let end = start + len;
+        self.used = end;
+        match self.buffer {
+            Some(ref mut buffer) if end > buffer.len() => {
+                self.used = start;
+                Err(Error::BufferTooSmall {
+                    needed: end,
+                    available: buffer.len(),
+                })
+            }
+            Some(ref mut buffer) => Ok(Some(&mut buffer[start..end])),
+            None => Ok(None),
+        }
+    }
+
+    /// Copy bytes in without escaping them.
+    fn raw(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if let Some(out) = self.reserve(bytes.len())? {
+            out.copy_from_slice(bytes);
+        }
+        Ok(())
+    }
+
+    /// Write an escape character followed by a command or response byte.
+    fn marker(&mut self, code: u8) -> Result<(), Error> {
+        self.raw(&[ESCAPE_CHAR, code])
+    }
+
+    /// Copy bytes in, doubling up any escape characters. Runs of ordinary
+    /// bytes between escape characters are copied in bulk.
+    fn escaped(&mut self, mut bytes: &[u8]) -> Result<(), Error> {
+        while let Some(idx) = bytes.iter().position(|&b| b == ESCAPE_CHAR) {
+            self.raw(&bytes[..=idx])?;
+            self.raw(&[ESCAPE_CHAR])?;
+            bytes = &bytes[idx + 1..];
+        }
+        self.raw(bytes)
+    }
+
+    /// Copy in exactly `len` bytes of `data`, truncating it or padding it
+    /// with nulls as required.
+    fn padded(&mut self, data: &[u8], len: usize) -> Result<(), Error> {
+        let data = &data[..data.len().min(len)];
+        self.escaped(data)?;
+        if let Some(out) = self.reserve(len - data.len())? {
+            out.fill(0x00);
+        }
+        Ok(())
+    }
+
+    fn u8(&mut self, value: u8) -> Result<(), Error> {
+        self.escaped(&[value])
+    }
+
+    fn u16(&mut self, value: u16) -> Result<(), Error> {
+        let mut bytes = [0u8; 2];
+        LittleEndian::write_u16(&mut bytes, value);
+        self.escaped(&bytes)
+    }
+
+    fn u32(&mut self, value: u32) -> Result<(), Error> {
+        let mut bytes = [0u8; 4];
+        LittleEndian::write_u32(&mut bytes, value);
+        self.escaped(&bytes)
+    }
+}
+
+impl<'a> Command<'a> {
+    /// The byte which follows `ESCAPE_CHAR` for this command.
+    pub(crate) fn code(&self) -> u8 {
+        match *self {
+            Command::Ping => CMD_PING,
+            Command::Info => CMD_INFO,
+            Command::Id => CMD_ID,
+            Command::Reset => CMD_RESET,
+            Command::ErasePage { .. } => CMD_EPAGE,
+            Command::WritePage { .. } => CMD_WPAGE,
+            Command::EraseExBlock { .. } => CMD_XEBLOCK,
+            Command::WriteExPage { .. } => CMD_XWPAGE,
+            Command::CrcRxBuffer => CMD_CRCRX,
+            Command::ReadRange { .. } => CMD_RRANGE,
+            Command::ExReadRange { .. } => CMD_XRRANGE,
+            Command::SetAttr { .. } => CMD_SATTR,
+            Command::GetAttr { .. } => CMD_GATTR,
+            Command::CrcIntFlash { .. } => CMD_CRCIF,
+            Command::CrcExtFlash { .. } => CMD_CRCEF,
+            Command::EraseExPage { .. } => CMD_XEPAGE,
+            Command::ExtFlashInit => CMD_XFINIT,
+            Command::ClockOut => CMD_CLKOUT,
+            Command::WriteFlashUserPages { .. } => CMD_WUSER,
+            Command::ChangeBaud { .. } => CMD_CHANGE_BAUD,
+            Command::Exit => CMD_EXIT,
+            Command::SetStartAddress { .. } => CMD_SET_START_ADDRESS,
+            Command::Vendor { code, .. } => code,
+        }
+    }
+
+    /// The code of the successful response to this command, if it has one.
+    pub(crate) fn response_code(&self) -> Option<u8> {
+        match *self {
+            Command::Ping => Some(RES_PONG),
+            Command::Info => Some(RES_INFO),
+            Command::Id => Some(RES_ID),
+            Command::Reset
+            | Command::ErasePage { .. }
+            | Command::WritePage { .. }
+            | Command::EraseExBlock { .. }
+            | Command::WriteExPage { .. }
+            | Command::SetAttr { .. }
+            | Command::EraseExPage { .. }
+            | Command::ExtFlashInit
+            | Command::WriteFlashUserPages { .. }
+            | Command::ChangeBaud { .. }
+            | Command::SetStartAddress { .. } => Some(RES_OK),
+            Command::CrcRxBuffer => Some(RES_CRCRX),
+            Command::ReadRange { .. } => Some(RES_RRANGE),
+            Command::ExReadRange { .. } => Some(RES_XRRANGE),
+            Command::GetAttr { .. } => Some(RES_GATTR),
+            Command::CrcIntFlash { .. } => Some(RES_CRCIF),
+            Command::CrcExtFlash { .. } => Some(RES_CRCXF),
+            // We don't know what the bootloader sends back for these
+            Command::ClockOut | Command::Exit | Command::Vendor { .. } => None,
+        }
+    }
+}
+
+impl<'a> Response<'a> {
+    /// The byte which follows `ESCAPE_CHAR` for this response.
+    pub(crate) fn code(&self) -> u8 {
+        match *self {
+            Response::Overflow => RES_OVERFLOW,
+            Response::Pong => RES_PONG,
+            Response::BadAddress => RES_BADADDR,
+            Response::InternalError => RES_INTERROR,
+            Response::BadArguments => RES_BADARGS,
+            Response::Ok => RES_OK,
+            Response::Unknown => RES_UNKNOWN,
+            Response::ExtFlashTimeout => RES_XFTIMEOUT,
+            Response::ExtFlashPageError => RES_XFEPE,
+            Response::CrcRxBuffer { .. } => RES_CRCRX,
+            Response::ReadRange { .. } => RES_RRANGE,
+            Response::ExReadRange { .. } => RES_XRRANGE,
+            Response::GetAttr { .. } => RES_GATTR,
+            Response::CrcIntFlash { .. } => RES_CRCIF,
+            Response::CrcExtFlash { .. } => RES_CRCXF,
+            Response::Info { .. } => RES_INFO,
+            Response::ChangeBaudFail => RES_CHANGE_BAUD_FAIL,
+            Response::Id { .. } => RES_ID,
+            Response::Vendor { code, .. } => code,
+        }
+    }
+}
+
+/// Encode a whole `Command` into `w`. This is used by
+/// `CommandEncoder::encode_into`, and with a counting `FrameWriter` by
+/// `CommandEncoder::encoded_len`.
+fn encode_command(command: &Command, w: &mut FrameWriter) -> Result<(), Error> {
+    let cmd = match *command {
+        Command::Ping => CMD_PING,
+        Command::Info => CMD_INFO,
+        Command::Id => CMD_ID,
+        Command::Reset => CMD_RESET,
+        Command::ErasePage { address } => {
+            w.u32(address)?;
+            CMD_EPAGE
+        }
+        Command::WritePage { address, data } => {
+            w.u32(address)?;
+            w.padded(data, INT_PAGE_SIZE)?;
+            CMD_WPAGE
+        }
+        Command::EraseExBlock { address } => {
+            w.u32(address)?;
+            CMD_XEBLOCK
+        }
+        Command::WriteExPage { address, data } => {
+            w.u32(address)?;
+            w.padded(data, EXT_PAGE_SIZE)?;
+            CMD_XWPAGE
+        }
+        Command::CrcRxBuffer => CMD_CRCRX,
+        Command::ReadRange { address, length } => {
+            w.u32(address)?;
+            w.u16(length)?;
+            CMD_RRANGE
+        }
+        Command::ExReadRange { address, length } => {
+            w.u32(address)?;
+            w.u16(length)?;
+            CMD_XRRANGE
+        }
+        Command::SetAttr { index, key, value } => {
+            let value = &value[..value.len().min(MAX_ATTR_LEN)];
+            w.u8(index)?;
+            w.padded(key, KEY_LEN)?;
+            w.u8(value.len() as u8)?;
+            w.escaped(value)?;
+            CMD_SATTR
+        }
+        Command::GetAttr { index } => {
+            w.u8(index)?;
+            CMD_GATTR
+        }
+        Command::CrcIntFlash { address, length } => {
+            w.u32(address)?;
+            w.u32(length)?;
+            CMD_CRCIF
+        }
+        Command::CrcExtFlash { address, length } => {
+            w.u32(address)?;
+            w.u32(length)?;
+            CMD_CRCEF
+        }
+        Command::EraseExPage { address } => {
+            w.u32(address)?;
+            CMD_XEPAGE
+        }
+        Command::ExtFlashInit => CMD_XFINIT,
+        Command::ClockOut => CMD_CLKOUT,
+        Command::WriteFlashUserPages { page1, page2 } => {
+            w.u32(page1)?;
+            w.u32(page2)?;
+            CMD_WUSER
+        }
+        Command::ChangeBaud { mode, baud } => {
+            w.u8(match mode {
+                BaudMode::Set => 0x01,
+                BaudMode::Verify => 0x02,
+            })?;
+            w.u32(baud)?;
+            CMD_CHANGE_BAUD
+        }
+        Command::Exit => CMD_EXIT,
+        Command::SetStartAddress { address } => {
+            w.u32(address)?;
+            CMD_SET_START_ADDRESS
+        }
+        Command::Vendor { code, payload } => {
+            w.escaped(payload)?;
+            code
+        }
+    };
+    w.marker(cmd)
+}
+
+/// Encode a whole `Response` into `w`. This is used by
+/// `ResponseEncoder::encode_into`, and with a counting `FrameWriter` by
+/// `ResponseEncoder::encoded_len`.
+fn encode_response(response: &Response, w: &mut FrameWriter) -> Result<(), Error> {
+    match *response {
+        Response::Overflow => w.marker(RES_OVERFLOW)?,
+        Response::Pong => w.marker(RES_PONG)?,
+        Response::BadAddress => w.marker(RES_BADADDR)?,
+        Response::InternalError => w.marker(RES_INTERROR)?,
+        Response::BadArguments => w.marker(RES_BADARGS)?,
+        Response::Ok => w.marker(RES_OK)?,
+        Response::Unknown => w.marker(RES_UNKNOWN)?,
+        Response::ExtFlashTimeout => w.marker(RES_XFTIMEOUT)?,
+        Response::ExtFlashPageError => w.marker(RES_XFEPE)?,
+        Response::CrcRxBuffer { length, crc } => {
+            w.marker(RES_CRCRX)?;
+            w.u16(length)?;
+            w.u32(crc)?;
+        }
+        Response::ReadRange { data } => {
+            w.marker(RES_RRANGE)?;
+            w.escaped(data)?;
+        }
+        Response::ExReadRange { data } => {
+            w.marker(RES_XRRANGE)?;
+            w.escaped(data)?;
+        }
+        Response::GetAttr { key, value } => {
+            w.marker(RES_GATTR)?;
+            w.padded(key, KEY_LEN)?;
+            w.u8(value.len() as u8)?;
+            w.padded(value, MAX_ATTR_LEN)?;
+        }
+        Response::CrcIntFlash { crc } => {
+            w.marker(RES_CRCIF)?;
+            w.u32(crc)?;
+        }
+        Response::CrcExtFlash { crc } => {
+            w.marker(RES_CRCXF)?;
+            w.u32(crc)?;
+        }
+        Response::Info { info } => {
+            w.marker(RES_INFO)?;
+            w.u8(info.len() as u8)?;
+            w.padded(info, MAX_INFO_LEN)?;
+        }
+        Response::ChangeBaudFail => w.marker(RES_CHANGE_BAUD_FAIL)?,
+        Response::Id { id } => {
+            w.marker(RES_ID)?;
+            w.padded(id, ID_LEN)?;
+        }
+        Response::Vendor { code, payload } => {
+            w.marker(code)?;
+            w.escaped(payload)?;
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
+#[allow(clippy::needless_borrow, clippy::unnecessary_cast)]
 mod tests {
     use super::*;
 
@@ -1301,14 +2272,14 @@ mod tests {
     #[test]
     fn decode_cmd_erase_page() {
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0x00), Ok(None));
         assert_eq!(p.receive(0xBE), Ok(None));
         assert_eq!(p.receive(0xAD), Ok(None));
         assert_eq!(p.receive(0xDE), Ok(None));
         assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
         match p.receive(CMD_EPAGE) {
             Ok(Some(Command::ErasePage { address })) => {
-                assert_eq!(address, 0xDEADBEEF);
+                assert_eq!(address, 0xDEADBE00);
             }
             e => panic!("Did not expect: {:?}", e),
         }
@@ -1316,10 +2287,10 @@ mod tests {
 
     #[test]
     fn encode_cmd_erase_page() {
-        let cmd = Command::ErasePage { address: 0xDEADBEEF };
+        let cmd = Command::ErasePage { address: 0xDEADBE00 };
         let mut e = CommandEncoder::new(&cmd).unwrap();
         // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0x00));
         assert_eq!(e.next(), Some(0xBE));
         assert_eq!(e.next(), Some(0xAD));
         assert_eq!(e.next(), Some(0xDE));
@@ -1332,7 +2303,7 @@ mod tests {
     #[test]
     fn decode_cmd_write_page() {
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0x00), Ok(None));
         assert_eq!(p.receive(0xBE), Ok(None));
         assert_eq!(p.receive(0xAD), Ok(None));
         assert_eq!(p.receive(0xDE), Ok(None));
@@ -1349,7 +2320,7 @@ mod tests {
                         address,
                         data: ref page,
                     })) => {
-                assert_eq!(address, 0xDEADBEEF);
+                assert_eq!(address, 0xDEADBE00);
                 assert_eq!(page.len(), INT_PAGE_SIZE);
                 for i in 0..INT_PAGE_SIZE {
                     let datum = i as u8;
@@ -1366,12 +2337,12 @@ mod tests {
         buffer[0] = 0xAA;
         buffer[INT_PAGE_SIZE - 1] = 0xCC;
         let cmd = Command::WritePage {
-            address: 0xDEADBEEF,
+            address: 0xDEADBE00,
             data: &buffer,
         };
         let mut e = CommandEncoder::new(&cmd).unwrap();
         // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0x00));
         assert_eq!(e.next(), Some(0xBE));
         assert_eq!(e.next(), Some(0xAD));
         assert_eq!(e.next(), Some(0xDE));
@@ -1391,14 +2362,14 @@ mod tests {
     #[test]
     fn decode_cmd_erase_block() {
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
-        assert_eq!(p.receive(0xBE), Ok(None));
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert_eq!(p.receive(0xB8), Ok(None));
         assert_eq!(p.receive(0xAD), Ok(None));
         assert_eq!(p.receive(0xDE), Ok(None));
         assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
         match p.receive(CMD_XEBLOCK) {
             Ok(Some(Command::EraseExBlock { address })) => {
-                assert_eq!(address, 0xDEADBEEF);
+                assert_eq!(address, 0xDEADB800);
             }
             e => panic!("Did not expect: {:?}", e),
         }
@@ -1406,11 +2377,11 @@ mod tests {
 
     #[test]
     fn encode_cmd_erase_block() {
-        let cmd = Command::EraseExBlock { address: 0xDEADBEEF };
+        let cmd = Command::EraseExBlock { address: 0xDEADB800 };
         let mut e = CommandEncoder::new(&cmd).unwrap();
         // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
-        assert_eq!(e.next(), Some(0xBE));
+        assert_eq!(e.next(), Some(0x00));
+        assert_eq!(e.next(), Some(0xB8));
         assert_eq!(e.next(), Some(0xAD));
         assert_eq!(e.next(), Some(0xDE));
         assert_eq!(e.next(), Some(ESCAPE_CHAR));
@@ -1422,7 +2393,7 @@ mod tests {
     #[test]
     fn decode_cmd_write_ex_page() {
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0x00), Ok(None));
         assert_eq!(p.receive(0xBE), Ok(None));
         assert_eq!(p.receive(0xAD), Ok(None));
         assert_eq!(p.receive(0xDE), Ok(None));
@@ -1439,7 +2410,7 @@ mod tests {
                         address,
                         data: ref page,
                     })) => {
-                assert_eq!(address, 0xDEADBEEF);
+                assert_eq!(address, 0xDEADBE00);
                 assert_eq!(page.len(), EXT_PAGE_SIZE);
                 for i in 0..EXT_PAGE_SIZE {
                     let datum = i as u8;
@@ -1456,12 +2427,12 @@ mod tests {
         buffer[0] = 0xAA;
         buffer[EXT_PAGE_SIZE - 1] = 0xCC;
         let cmd = Command::WriteExPage {
-            address: 0xDEADBEEF,
+            address: 0xDEADBE00,
             data: &buffer,
         };
         let mut e = CommandEncoder::new(&cmd).unwrap();
         // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0x00));
         assert_eq!(e.next(), Some(0xBE));
         assert_eq!(e.next(), Some(0xAD));
         assert_eq!(e.next(), Some(0xDE));
@@ -1581,12 +2552,12 @@ mod tests {
     #[test]
     fn encode_cmd_sattr() {
         let r = Command::SetAttr {
-            index: MAX_INDEX,
+            index: MAX_INDEX - 1,
             key: &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77],
             value: &[0xAA, 0xBB, 0xCC, 0xDD],
         };
         let mut e = CommandEncoder::new(&r).unwrap();
-        assert_eq!(e.next(), Some(MAX_INDEX));
+        assert_eq!(e.next(), Some(MAX_INDEX - 1));
         assert_eq!(e.next(), Some(0x00));
         assert_eq!(e.next(), Some(0x11));
         assert_eq!(e.next(), Some(0x22));
@@ -1610,7 +2581,7 @@ mod tests {
     #[test]
     fn decode_cmd_sattr() {
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(MAX_INDEX), Ok(None));
+        assert_eq!(p.receive(MAX_INDEX - 1), Ok(None));
         assert_eq!(p.receive(0x00), Ok(None));
         assert_eq!(p.receive(0x11), Ok(None));
         assert_eq!(p.receive(0x22), Ok(None));
@@ -1627,7 +2598,7 @@ mod tests {
         assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
         match p.receive(CMD_SATTR) {
             Ok(Some(Command::SetAttr { index, key, value })) => {
-                assert_eq!(index, MAX_INDEX);
+                assert_eq!(index, MAX_INDEX - 1);
                 assert_eq!(key, [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77]);
                 assert_eq!(value, [0xAA, 0xBB, 0xCC, 0xDD]);
             }
@@ -1637,9 +2608,9 @@ mod tests {
 
     #[test]
     fn encode_cmd_gattr() {
-        let r = Command::GetAttr { index: MAX_INDEX };
+        let r = Command::GetAttr { index: MAX_INDEX - 1 };
         let mut e = CommandEncoder::new(&r).unwrap();
-        assert_eq!(e.next(), Some(MAX_INDEX));
+        assert_eq!(e.next(), Some(MAX_INDEX - 1));
         assert_eq!(e.next(), Some(ESCAPE_CHAR));
         assert_eq!(e.next(), Some(CMD_GATTR));
         assert_eq!(e.next(), None);
@@ -1649,11 +2620,11 @@ mod tests {
     #[test]
     fn decode_cmd_gattr() {
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(MAX_INDEX), Ok(None));
+        assert_eq!(p.receive(MAX_INDEX - 1), Ok(None));
         assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
         match p.receive(CMD_GATTR) {
             Ok(Some(Command::GetAttr { index })) => {
-                assert_eq!(index, MAX_INDEX);
+                assert_eq!(index, MAX_INDEX - 1);
             }
             e => panic!("Did not expect: {:?}", e),
         }
@@ -1749,10 +2720,10 @@ mod tests {
 
     #[test]
     fn encode_cmd_xepage() {
-        let cmd = Command::EraseExPage { address: 0xDEADBEEF };
+        let cmd = Command::EraseExPage { address: 0xDEADBE00 };
         let mut e = CommandEncoder::new(&cmd).unwrap();
         // 4 byte address, little-endian
-        assert_eq!(e.next(), Some(0xEF));
+        assert_eq!(e.next(), Some(0x00));
         assert_eq!(e.next(), Some(0xBE));
         assert_eq!(e.next(), Some(0xAD));
         assert_eq!(e.next(), Some(0xDE));
@@ -1765,14 +2736,14 @@ mod tests {
     #[test]
     fn decode_cmd_xepage() {
         let mut p = CommandDecoder::new();
-        assert_eq!(p.receive(0xEF), Ok(None));
+        assert_eq!(p.receive(0x00), Ok(None));
         assert_eq!(p.receive(0xBE), Ok(None));
         assert_eq!(p.receive(0xAD), Ok(None));
         assert_eq!(p.receive(0xDE), Ok(None));
         assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
         match p.receive(CMD_XEPAGE) {
             Ok(Some(Command::EraseExPage { address })) => {
-                assert_eq!(address, 0xDEADBEEF);
+                assert_eq!(address, 0xDEADBE00);
             }
             e => panic!("Did not expect: {:?}", e),
         }
@@ -1913,6 +2884,65 @@ mod tests {
         }
     }
 
+    #[test]
+    fn encode_cmd_exit() {
+        let cmd = Command::Exit;
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_EXIT));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_exit() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        assert_eq!(p.receive(CMD_EXIT), Ok(Some(Command::Exit)));
+    }
+
+    #[test]
+    fn encode_cmd_set_start_address() {
+        let cmd = Command::SetStartAddress { address: 0x00010000 };
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        // 4 byte address, little-endian
+        assert_eq!(e.next(), Some(0x00));
+        assert_eq!(e.next(), Some(0x00));
+        assert_eq!(e.next(), Some(0x01));
+        assert_eq!(e.next(), Some(0x00));
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(CMD_SET_START_ADDRESS));
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+    }
+
+    #[test]
+    fn decode_cmd_set_start_address() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert_eq!(p.receive(0x01), Ok(None));
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        match p.receive(CMD_SET_START_ADDRESS) {
+            Ok(Some(Command::SetStartAddress { address })) => {
+                assert_eq!(address, 0x00010000);
+            }
+            e => panic!("Did not expect: {:?}", e),
+        }
+        // A short address is rejected
+        assert_eq!(p.receive(0x00), Ok(None));
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None)); // Escape
+        assert_eq!(
+            p.receive(CMD_SET_START_ADDRESS),
+            Err(Error::BadArguments {
+                code: CMD_SET_START_ADDRESS,
+                expected: 4,
+                actual: 1,
+            })
+        );
+    }
+
     // Responses
 
     fn check_rsp_generic(response: Response, cmd: u8) {
@@ -2187,6 +3217,136 @@ mod tests {
         assert_eq!(e.next(), None);
     }
 
+    #[test]
+    fn check_rsp_id() {
+        let mut p = ResponseDecoder::new();
+        assert_eq!(p.receive(ESCAPE_CHAR), Ok(None));
+        assert_eq!(p.receive(RES_ID), Ok(None));
+        for i in 1..ID_LEN {
+            assert_eq!(p.receive(i as u8), Ok(None));
+        }
+        assert_eq!(
+            p.receive(0x08),
+            Ok(Some(Response::Id {
+                id: &[1, 2, 3, 4, 5, 6, 7, 8],
+            }))
+        );
+
+        let r = Response::Id {
+            id: &[1, 2, 3, 4, 5, 6, 7, 8],
+        };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        assert_eq!(e.next(), Some(ESCAPE_CHAR));
+        assert_eq!(e.next(), Some(RES_ID));
+        for i in 1..=ID_LEN {
+            assert_eq!(e.next(), Some(i as u8));
+        }
+        assert_eq!(e.next(), None);
+        assert_eq!(e.next(), None);
+
+        let r = Response::Id { id: &[1, 2, 3] };
+        assert_eq!(
+            ResponseEncoder::new(&r).err(),
+            Some(Error::BadLength {
+                code: RES_ID,
+                field: Field::Id,
+                limit: ID_LEN,
+                actual: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn check_cmd_vendor() {
+        let bytes = [0x01, ESCAPE_CHAR, ESCAPE_CHAR, 0x02, ESCAPE_CHAR, 0xE3];
+        let mut p = CommandDecoder::new();
+        assert_eq!(
+            p.feed(&bytes),
+            (
+                bytes.len(),
+                Some(Ok(Command::Vendor {
+                    code: 0xE3,
+                    payload: &[0x01, ESCAPE_CHAR, 0x02],
+                }))
+            )
+        );
+        // An empty payload is fine
+        assert_eq!(
+            p.feed(&[ESCAPE_CHAR, vendor::LAST_CODE]).1,
+            Some(Ok(Command::Vendor {
+                code: vendor::LAST_CODE,
+                payload: &[],
+            }))
+        );
+
+        let cmd = Command::Vendor {
+            code: 0xE3,
+            payload: &[0x01, ESCAPE_CHAR, 0x02],
+        };
+        let e = CommandEncoder::new(&cmd).unwrap();
+        assert!(e.eq(bytes.iter().cloned()));
+
+        let cmd = Command::Vendor {
+            code: CMD_PING,
+            payload: &[],
+        };
+        assert_eq!(
+            CommandEncoder::new(&cmd).err(),
+            Some(Error::UnknownCommand { code: CMD_PING })
+        );
+    }
+
+    #[test]
+    fn check_rsp_reset_after_escape() {
+        let mut p = ResponseDecoder::new();
+        assert_eq!(p.feed(&[ESCAPE_CHAR]), (1, None));
+        p.reset();
+        // Not an escape code any more, just a byte with nowhere to go
+        assert_eq!(p.feed(&[RES_PONG]), (1, None));
+        assert_eq!(p.feed(&[ESCAPE_CHAR, RES_OK]), (2, Some(Ok(Response::Ok))));
+    }
+
+    #[test]
+    fn check_rsp_vendor() {
+        let bytes = [ESCAPE_CHAR, 0xE1, ESCAPE_CHAR, ESCAPE_CHAR, 0x02];
+        let mut p = ResponseDecoder::new();
+        assert_eq!(
+            p.feed(&bytes).1,
+            Some(Err(Error::UnsetLength { code: 0xE1 }))
+        );
+        p.reset();
+        p.set_vendor_len(0xE1, 2).unwrap();
+        p.set_vendor_len(0xE2, 0).unwrap();
+        assert_eq!(
+            p.feed(&bytes),
+            (
+                bytes.len(),
+                Some(Ok(Response::Vendor {
+                    code: 0xE1,
+                    payload: &[ESCAPE_CHAR, 0x02],
+                }))
+            )
+        );
+        assert_eq!(
+            p.feed(&[ESCAPE_CHAR, 0xE2]).1,
+            Some(Ok(Response::Vendor {
+                code: 0xE2,
+                payload: &[],
+            }))
+        );
+        assert_eq!(
+            p.set_vendor_len(RES_OK, 2),
+            Err(Error::UnknownCommand { code: RES_OK })
+        );
+
+        let rsp = Response::Vendor {
+            code: 0xE1,
+            payload: &[ESCAPE_CHAR, 0x02],
+        };
+        let e = ResponseEncoder::new(&rsp).unwrap();
+        assert!(e.eq(bytes.iter().cloned()));
+    }
+
     #[test]
     fn check_rsp_info() {
         let mut p = ResponseDecoder::new();
@@ -2342,6 +3502,600 @@ mod tests {
         }
     }
 
+    /// Check `encode_into` produces exactly what the iterator does, and
+    /// that it fails cleanly when the buffer is one byte short.
+    fn check_cmd_encode_into(cmd: &Command) {
+        let mut expected = [0u8; 1100];
+        let mut actual = [0u8; 1100];
+        let mut e = CommandEncoder::new(cmd).unwrap();
+        assert_eq!(e.len(), e.encoded_len());
+        let len = e.write(&mut expected);
+        assert_eq!(e.encoded_len(), len);
+        assert_eq!(e.size_hint(), (0, Some(0)));
+        assert_eq!(e.encode_into(&mut actual), Ok(len));
+        assert_eq!(&actual[0..len], &expected[0..len]);
+        assert_eq!(
+            e.encode_into(&mut actual[0..len - 1]),
+            Err(Error::BufferTooSmall {
+                needed: len,
+                available: len - 1,
+            })
+        );
+    }
+
+    fn check_rsp_encode_into(rsp: &Response) {
+        let mut expected = [0u8; 1100];
+        let mut actual = [0u8; 1100];
+        let mut e = ResponseEncoder::new(rsp).unwrap();
+        assert_eq!(e.len(), e.encoded_len());
+        let len = e.write(&mut expected);
+        assert_eq!(e.encoded_len(), len);
+        assert_eq!(e.size_hint(), (0, Some(0)));
+        assert_eq!(e.encode_into(&mut actual), Ok(len));
+        assert_eq!(&actual[0..len], &expected[0..len]);
+        assert_eq!(
+            e.encode_into(&mut actual[0..len - 1]),
+            Err(Error::BufferTooSmall {
+                needed: len,
+                available: len - 1,
+            })
+        );
+    }
+
+    #[test]
+    fn check_encode_into_matches_iterator() {
+        let mut page = [0u8; INT_PAGE_SIZE];
+        for (i, b) in page.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let escapes = [ESCAPE_CHAR; MAX_ATTR_LEN];
+        check_cmd_encode_into(&Command::Ping);
+        check_cmd_encode_into(&Command::Info);
+        check_cmd_encode_into(&Command::Id);
+        check_cmd_encode_into(&Command::Reset);
+        check_cmd_encode_into(&Command::ErasePage { address: 0xFCFC_0200 });
+        check_cmd_encode_into(&Command::WritePage {
+            address: 0x0001_FC00,
+            data: &page,
+        });
+        check_cmd_encode_into(&Command::EraseExBlock { address: 0x0000_0800 });
+        check_cmd_encode_into(&Command::WriteExPage {
+            address: 0x0000_FC00,
+            data: &page[256..],
+        });
+        check_cmd_encode_into(&Command::CrcRxBuffer);
+        check_cmd_encode_into(&Command::ReadRange {
+            address: 0xDEADBEEF,
+            length: 0x00FC,
+        });
+        check_cmd_encode_into(&Command::ExReadRange {
+            address: 0xDEADBEEF,
+            length: 0xFC00,
+        });
+        check_cmd_encode_into(&Command::SetAttr {
+            index: 0x0C,
+            key: b"board\0\0\0",
+            value: &escapes,
+        });
+        check_cmd_encode_into(&Command::GetAttr { index: 3 });
+        check_cmd_encode_into(&Command::CrcIntFlash {
+            address: 0x0001_0000,
+            length: 0xFCFC_FCFC,
+        });
+        check_cmd_encode_into(&Command::CrcExtFlash {
+            address: 0x0001_0000,
+            length: 0x0000_FC00,
+        });
+        check_cmd_encode_into(&Command::EraseExPage { address: 0x0000_FC00 });
+        check_cmd_encode_into(&Command::ExtFlashInit);
+        check_cmd_encode_into(&Command::ClockOut);
+        check_cmd_encode_into(&Command::WriteFlashUserPages {
+            page1: 0xFC,
+            page2: 0xFC00,
+        });
+        check_cmd_encode_into(&Command::ChangeBaud {
+            mode: BaudMode::Verify,
+            baud: 0x00FC_0000,
+        });
+        check_cmd_encode_into(&Command::Exit);
+        check_cmd_encode_into(&Command::Vendor {
+            code: vendor::FIRST_CODE,
+            payload: &escapes,
+        });
+        check_cmd_encode_into(&Command::SetStartAddress {
+            address: 0x0000_FC00,
+        });
+
+        check_rsp_encode_into(&Response::Overflow);
+        check_rsp_encode_into(&Response::Pong);
+        check_rsp_encode_into(&Response::BadAddress);
+        check_rsp_encode_into(&Response::InternalError);
+        check_rsp_encode_into(&Response::BadArguments);
+        check_rsp_encode_into(&Response::Ok);
+        check_rsp_encode_into(&Response::Unknown);
+        check_rsp_encode_into(&Response::ExtFlashTimeout);
+        check_rsp_encode_into(&Response::ExtFlashPageError);
+        check_rsp_encode_into(&Response::CrcRxBuffer {
+            length: 0x00FC,
+            crc: 0xFCFC_0000,
+        });
+        check_rsp_encode_into(&Response::ReadRange { data: &page });
+        check_rsp_encode_into(&Response::ExReadRange { data: &page[..300] });
+        check_rsp_encode_into(&Response::GetAttr {
+            key: b"app\xFC\0\0\0\0",
+            value: &escapes[..10],
+        });
+        check_rsp_encode_into(&Response::CrcIntFlash { crc: 0x0000_00FC });
+        check_rsp_encode_into(&Response::CrcExtFlash { crc: 0xFC00_0000 });
+        check_rsp_encode_into(&Response::Info { info: &page[200..392] });
+        check_rsp_encode_into(&Response::ChangeBaudFail);
+        check_rsp_encode_into(&Response::Id {
+            id: &[ESCAPE_CHAR; ID_LEN],
+        });
+        check_rsp_encode_into(&Response::Vendor {
+            code: vendor::FIRST_CODE,
+            payload: &escapes,
+        });
+    }
+
+    #[test]
+    fn check_encoded_len() {
+        let key = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77];
+        let r = Response::GetAttr {
+            key: &key,
+            value: &[0xAA, ESCAPE_CHAR],
+        };
+        let mut e = ResponseEncoder::new(&r).unwrap();
+        // Header, key, length, 55 bytes of value and one extra escape
+        assert_eq!(e.encoded_len(), 2 + KEY_LEN + 1 + MAX_ATTR_LEN + 1);
+        assert_eq!(e.len(), 67);
+        for _ in 0..12 {
+            e.next();
+        }
+        // Part way through the doubled escape
+        assert_eq!(e.len(), 55);
+        e.next();
+        assert_eq!(e.len(), 54);
+        e.reset();
+        assert_eq!(e.len(), 67);
+
+        let r = Response::Info { info: b"Tock" };
+        let e = ResponseEncoder::new(&r).unwrap();
+        assert_eq!(e.encoded_len(), 2 + 1 + MAX_INFO_LEN);
+
+        let cmd = Command::SetAttr {
+            index: 1,
+            key: &key,
+            value: &[ESCAPE_CHAR, ESCAPE_CHAR],
+        };
+        let e = CommandEncoder::new(&cmd).unwrap();
+        assert_eq!(e.encoded_len(), 1 + KEY_LEN + 1 + 4 + 2);
+        assert_eq!(e.count(), 16);
+    }
+
+    #[test]
+    fn check_command_feed() {
+        let mut p = CommandDecoder::new();
+        let buffer = [
+            ESCAPE_CHAR,
+            CMD_PING,
+            // Erase page with a short address
+            0xEF,
+            0xBE,
+            ESCAPE_CHAR,
+            CMD_EPAGE,
+            // Unknown commands are skipped
+            ESCAPE_CHAR,
+            0xAA,
+            0x00,
+            0x02,
+            0x00,
+            0x00,
+            ESCAPE_CHAR,
+            CMD_EPAGE,
+            0x00,
+        ];
+        let mut input = &buffer[..];
+        let mut pings = 0;
+        let mut addresses = [0u32; 2];
+        let mut num_addresses = 0;
+        let mut errors = 0;
+        while !input.is_empty() {
+            let (used, result) = p.feed(input);
+            match result {
+                None => assert_eq!(used, input.len()),
+                Some(Ok(Command::Ping)) => pings += 1,
+                Some(Ok(Command::ErasePage { address })) => {
+                    addresses[num_addresses] = address;
+                    num_addresses += 1;
+                }
+                Some(Err(Error::BadArguments {
+                    code: CMD_EPAGE,
+                    expected: 4,
+                    actual: 2,
+                })) => errors += 1,
+                e => panic!("Did not expect: {:?}", e),
+            }
+            input = &input[used..];
+        }
+        assert_eq!(pings, 1);
+        assert_eq!(errors, 1);
+        assert_eq!(num_addresses, 1);
+        assert_eq!(addresses[0], 0x200);
+        // The trailing byte is held for the next frame
+        assert_eq!(p.feed(&[0x04, 0x00, 0x00]), (3, None));
+        assert_eq!(
+            p.feed(&[ESCAPE_CHAR, CMD_EPAGE, ESCAPE_CHAR]),
+            (2, Some(Ok(Command::ErasePage { address: 0x400 })))
+        );
+    }
+
+    #[test]
+    fn check_response_feed() {
+        let mut p = ResponseDecoder::new();
+        let buffer = [
+            ESCAPE_CHAR,
+            RES_PONG,
+            ESCAPE_CHAR,
+            RES_CRCIF,
+            0xEF,
+            0xBE,
+            0xAD,
+            0xDE,
+            // No length set
+            ESCAPE_CHAR,
+            RES_RRANGE,
+            ESCAPE_CHAR,
+            RES_OK,
+        ];
+        let (used, result) = p.feed(&buffer);
+        assert_eq!(used, 2);
+        assert_eq!(result, Some(Ok(Response::Pong)));
+        let (used, result) = p.feed(&buffer[2..]);
+        assert_eq!(used, 6);
+        assert_eq!(result, Some(Ok(Response::CrcIntFlash { crc: 0xDEADBEEF })));
+        let (used, result) = p.feed(&buffer[8..]);
+        assert_eq!(used, 2);
+        assert_eq!(result, Some(Err(Error::UnsetLength { code: RES_RRANGE })));
+        let (used, result) = p.feed(&buffer[10..]);
+        assert_eq!(used, 2);
+        assert_eq!(result, Some(Ok(Response::Ok)));
+
+        // Frames can be split across calls
+        p.set_payload_len(2).unwrap();
+        assert_eq!(p.feed(&[ESCAPE_CHAR, RES_RRANGE, ESCAPE_CHAR]), (3, None));
+        assert_eq!(
+            p.feed(&[ESCAPE_CHAR, 0x01, 0xFF]),
+            (2, Some(Ok(Response::ReadRange { data: &[ESCAPE_CHAR, 0x01] })))
+        );
+    }
+
+    #[test]
+    fn check_expect_response_to() {
+        let mut p = ResponseDecoder::new();
+        let cmd = Command::ReadRange {
+            address: 0,
+            length: 3,
+        };
+        p.expect_response_to(&cmd).unwrap();
+        assert_eq!(
+            p.feed(&[ESCAPE_CHAR, RES_RRANGE, 0x01, 0x02, 0x03]),
+            (5, Some(Ok(Response::ReadRange { data: &[0x01, 0x02, 0x03] })))
+        );
+
+        // The expected length doesn't leak into the next response, and
+        // setting a new expectation replaces the old one
+        p.expect_response_to(&cmd).unwrap();
+        p.expect_response_to(&Command::Ping).unwrap();
+        assert_eq!(p.feed(&[ESCAPE_CHAR, RES_PONG]).1, Some(Ok(Response::Pong)));
+
+        // Failures are always acceptable
+        p.expect_response_to(&cmd).unwrap();
+        assert_eq!(
+            p.feed(&[ESCAPE_CHAR, RES_BADADDR]).1,
+            Some(Ok(Response::BadAddress))
+        );
+
+        // The wrong kind of success is not
+        p.expect_response_to(&Command::GetAttr { index: 0 }).unwrap();
+        assert_eq!(
+            p.feed(&[ESCAPE_CHAR, RES_OK]).1,
+            Some(Err(Error::UnexpectedResponse {
+                code: RES_OK,
+                expected: RES_GATTR,
+            }))
+        );
+        // ... and the expectation is dropped afterwards
+        assert_eq!(p.feed(&[ESCAPE_CHAR, RES_OK]).1, Some(Ok(Response::Ok)));
+
+        let cmd = Command::ExReadRange {
+            address: 0,
+            length: BUFFER_LEN as u16,
+        };
+        assert_eq!(
+            p.expect_response_to(&cmd),
+            Err(Error::BadLength {
+                code: CMD_XRRANGE,
+                field: Field::Data,
+                limit: BUFFER_LEN - 1,
+                actual: BUFFER_LEN,
+            })
+        );
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn check_command_stats() {
+        let mut p = CommandDecoder::new();
+        for _ in 0..3 {
+            p.feed(&[ESCAPE_CHAR, CMD_PING]);
+        }
+        // A payload with an escaped escape in it
+        p.feed(&[0x00, ESCAPE_CHAR, ESCAPE_CHAR, 0x00, 0x00, ESCAPE_CHAR, CMD_EPAGE]);
+        // Too short, and then a code nobody knows
+        assert!(p.feed(&[0x01, 0x02, ESCAPE_CHAR, CMD_EPAGE]).1.unwrap().is_err());
+        p.feed(&[ESCAPE_CHAR, 0x02, 0x03]);
+        p.reset();
+        let mut big = [0u8; BUFFER_LEN + 3];
+        *big.last_mut().unwrap() = 0x04;
+        p.feed(&big);
+        p.reset();
+        let stats = *p.stats();
+        assert_eq!(stats.frames(CMD_PING), 3);
+        assert_eq!(stats.frames(CMD_EPAGE), 1);
+        assert_eq!(stats.total_frames(), 4);
+        assert_eq!(stats.escapes, 7);
+        assert_eq!(stats.double_escapes, 1);
+        assert_eq!(stats.unknown_codes, 1);
+        assert_eq!(stats.bad_arguments, 1);
+        assert_eq!(stats.overflows, 3);
+        assert_eq!(stats.discarded, 2 + 1 + BUFFER_LEN as u32);
+        p.clear_stats();
+        assert_eq!(p.stats().total_frames(), 0);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn check_response_stats() {
+        let mut p = ResponseDecoder::new();
+        p.feed(&[ESCAPE_CHAR, RES_PONG]);
+        p.feed(&[ESCAPE_CHAR, RES_OK]);
+        p.feed(&[ESCAPE_CHAR, RES_CRCIF, 0x01, ESCAPE_CHAR, ESCAPE_CHAR, 0x03, 0x04]);
+        p.feed(&[ESCAPE_CHAR, 0x01]);
+        // Half a response, then a timeout
+        p.expect_response_to(&Command::ReadRange {
+            address: 0,
+            length: 8,
+        })
+        .unwrap();
+        p.feed(&[ESCAPE_CHAR, RES_RRANGE, 0x01, 0x02]);
+        assert!(p.check_idle(100, 10));
+        // The wrong response
+        p.expect_response_to(&Command::Ping).unwrap();
+        assert_eq!(
+            p.feed(&[ESCAPE_CHAR, RES_OK]).1,
+            Some(Err(Error::UnexpectedResponse {
+                code: RES_OK,
+                expected: RES_PONG,
+            }))
+        );
+        let stats = *p.stats();
+        assert_eq!(stats.frames(RES_PONG), 1);
+        assert_eq!(stats.frames(RES_OK), 1);
+        assert_eq!(stats.frames(RES_CRCIF), 1);
+        assert_eq!(stats.escapes, 7);
+        assert_eq!(stats.double_escapes, 1);
+        assert_eq!(stats.unknown_codes, 1);
+        assert_eq!(stats.discarded, 3);
+    }
+
+    #[test]
+    fn check_resync_after_truncated_info() {
+        let mut p = ResponseDecoder::new();
+        p.expect_response_to(&Command::Info).unwrap();
+        let mut frame = [0u8; 2 + 1 + MAX_INFO_LEN];
+        frame[0] = ESCAPE_CHAR;
+        frame[1] = RES_INFO;
+        frame[2] = 5;
+        frame[3..8].copy_from_slice(b"hello");
+        // Only the first part arrives
+        assert_eq!(p.feed(&frame[..50]), (50, None));
+        assert!(!p.check_idle(5, 10));
+        assert_eq!(p.resyncs(), 0);
+
+        // Without a resync, the next response is corrupted
+        let mut stale = ResponseDecoder::new();
+        stale.expect_response_to(&Command::Info).unwrap();
+        stale.feed(&frame[..50]);
+        assert_eq!(stale.feed(&frame).1, Some(Err(Error::SetLength)));
+
+        assert!(p.check_idle(11, 10));
+        assert_eq!(p.resyncs(), 1);
+        // Nothing left to throw away
+        assert!(!p.check_idle(100, 10));
+        assert_eq!(p.resyncs(), 1);
+        p.expect_response_to(&Command::Info).unwrap();
+        assert_eq!(
+            p.feed(&frame),
+            (frame.len(), Some(Ok(Response::Info { info: b"hello" })))
+        );
+    }
+
+    #[test]
+    fn check_resync_after_truncated_read_range() {
+        let mut p = ResponseDecoder::new();
+        let cmd = Command::ReadRange {
+            address: 0,
+            length: 4,
+        };
+        p.expect_response_to(&cmd).unwrap();
+        // A length set before the response starts survives an idle check
+        assert!(!p.check_idle(1000, 10));
+        assert_eq!(p.feed(&[ESCAPE_CHAR, RES_RRANGE, 0x01, 0x02]), (4, None));
+        assert!(p.check_idle(1000, 10));
+
+        // The stale length is gone, so the next response can be anything
+        assert_eq!(
+            p.feed(&[ESCAPE_CHAR, RES_GATTR]),
+            (2, None),
+            "set_payload_len must not fail with SetLength"
+        );
+        p.resync();
+        assert_eq!(p.resyncs(), 2);
+
+        // A dangling escape is thrown away too
+        assert_eq!(p.feed(&[ESCAPE_CHAR]), (1, None));
+        assert!(p.check_idle(11, 10));
+        p.expect_response_to(&cmd).unwrap();
+        assert_eq!(
+            p.feed(&[
+                ESCAPE_CHAR,
+                RES_RRANGE,
+                0x01,
+                0x02,
+                ESCAPE_CHAR,
+                ESCAPE_CHAR,
+                0x04,
+            ]),
+            (
+                7,
+                Some(Ok(Response::ReadRange {
+                    data: &[0x01, 0x02, ESCAPE_CHAR, 0x04],
+                }))
+            )
+        );
+        assert_eq!(p.resyncs(), 3);
+    }
+
+    #[test]
+    fn check_decoders_validate() {
+        let mut p = CommandDecoder::new();
+        assert_eq!(
+            p.feed(&[0x10, ESCAPE_CHAR, CMD_GATTR]).1,
+            Some(Err(Error::BadIndex {
+                code: CMD_GATTR,
+                index: 0x10,
+            }))
+        );
+        assert_eq!(
+            p.feed(&[0x00, 0x01, 0x00, 0x00, ESCAPE_CHAR, CMD_EPAGE]).1,
+            Some(Err(Error::UnalignedAddress {
+                code: CMD_EPAGE,
+                address: 0x100,
+                alignment: INT_PAGE_SIZE,
+            }))
+        );
+        assert_eq!(
+            p.feed(&[0x00, 0x09, 0x00, 0x00, ESCAPE_CHAR, CMD_XEBLOCK]).1,
+            Some(Err(Error::UnalignedAddress {
+                code: CMD_XEBLOCK,
+                address: 0x900,
+                alignment: EXT_BLOCK_SIZE,
+            }))
+        );
+        let mut sattr = [0u8; 1 + KEY_LEN + 1 + MAX_ATTR_LEN + 1 + 2];
+        sattr[1] = b'k';
+        sattr[1 + KEY_LEN] = (MAX_ATTR_LEN + 1) as u8;
+        sattr[sattr.len() - 2] = ESCAPE_CHAR;
+        sattr[sattr.len() - 1] = CMD_SATTR;
+        assert_eq!(
+            p.feed(&sattr).1,
+            Some(Err(Error::BadLength {
+                code: CMD_SATTR,
+                field: Field::Value,
+                limit: MAX_ATTR_LEN,
+                actual: MAX_ATTR_LEN + 1,
+            }))
+        );
+        // The decoder has been reset and carries on
+        assert_eq!(p.feed(&[ESCAPE_CHAR, CMD_PING]).1, Some(Ok(Command::Ping)));
+
+        let mut p = ResponseDecoder::new();
+        let mut gattr = [0u8; 2 + KEY_LEN + 1 + MAX_ATTR_LEN];
+        gattr[0] = ESCAPE_CHAR;
+        gattr[1] = RES_GATTR;
+        gattr[2 + KEY_LEN] = (MAX_ATTR_LEN + 1) as u8;
+        assert_eq!(
+            p.feed(&gattr).1,
+            Some(Err(Error::BadLength {
+                code: RES_GATTR,
+                field: Field::Value,
+                limit: MAX_ATTR_LEN,
+                actual: MAX_ATTR_LEN + 1,
+            }))
+        );
+        assert_eq!(p.feed(&[ESCAPE_CHAR, RES_PONG]).1, Some(Ok(Response::Pong)));
+    }
+
+    #[test]
+    fn check_encoders_validate() {
+        let cmd = Command::GetAttr { index: MAX_INDEX };
+        assert_eq!(
+            CommandEncoder::new(&cmd).err(),
+            Some(Error::BadIndex {
+                code: CMD_GATTR,
+                index: MAX_INDEX,
+            })
+        );
+        let cmd = Command::ErasePage { address: 0x100 };
+        assert_eq!(
+            CommandEncoder::new(&cmd).err(),
+            Some(Error::UnalignedAddress {
+                code: CMD_EPAGE,
+                address: 0x100,
+                alignment: INT_PAGE_SIZE,
+            })
+        );
+        let info = [b'x'; MAX_INFO_LEN + 1];
+        let rsp = Response::Info { info: &info };
+        assert_eq!(
+            ResponseEncoder::new(&rsp).err(),
+            Some(Error::BadLength {
+                code: RES_INFO,
+                field: Field::Info,
+                limit: MAX_INFO_LEN,
+                actual: MAX_INFO_LEN + 1,
+            })
+        );
+    }
+
+    #[test]
+    fn check_error_display() {
+        use core::fmt::Write;
+        let mut text: heapless::String<80> = heapless::String::new();
+        let err = Error::BadArguments {
+            code: CMD_EPAGE,
+            expected: 4,
+            actual: 2,
+        };
+        write!(text, "{}", err).unwrap();
+        assert_eq!(text, "0x06: expected 4 payload bytes, got 2");
+        text.clear();
+        let err = Error::BadLength {
+            code: CMD_SATTR,
+            field: Field::Value,
+            limit: MAX_ATTR_LEN,
+            actual: 60,
+        };
+        write!(text, "{}", err).unwrap();
+        assert_eq!(text, "0x13: value is 60 bytes, limit is 55");
+        text.clear();
+        let err = Error::UnalignedAddress {
+            code: CMD_EPAGE,
+            address: 0x100,
+            alignment: INT_PAGE_SIZE,
+        };
+        write!(text, "{}", err).unwrap();
+        assert_eq!(text, "0x06: address 0x00000100 is not aligned to 512 bytes");
+        text.clear();
+        let err = Error::BadKey {
+            position: 3,
+            byte: 0x7F,
+        };
+        write!(text, "{}", err).unwrap();
+        assert_eq!(text, "attribute key has bad byte 0x7f at position 3");
+    }
+
 }
 
 // ****************************************************************************
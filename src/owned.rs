@@ -0,0 +1,622 @@
+//! Owned versions of `Command` and `Response`.
+//!
+//! The decoders hand out `Command`s and `Response`s which borrow from their
+//! internal buffer, so they only live until the next byte is received. The
+//! types in this module own their payloads, so they can be queued, sent to
+//! another task or kept in a log.
+//!
+//! By default payloads are stored in fixed-capacity `heapless` buffers sized
+//! for the largest payload the protocol allows. With the `alloc` feature they
+//! are stored in a `Vec` instead.
+//!
+//! With the `serde` feature, the owned types can be serialised, with
+//! payloads written as hex strings.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::convert::TryFrom;
+use core::fmt;
+use core::ops::Deref;
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+
+use super::{
+    BaudMode, Command, Error, Response, BUFFER_LEN, EXT_PAGE_SIZE, ID_LEN, INT_PAGE_SIZE,
+    KEY_LEN, MAX_ATTR_LEN, MAX_INFO_LEN,
+};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// An owned byte payload of at most `N` bytes.
+///
+/// With the `alloc` feature, the length isn't limited.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Default)]
+pub struct Payload<const N: usize> {
+    #[cfg(not(feature = "alloc"))]
+    data: heapless::Vec<u8, N>,
+    #[cfg(feature = "alloc")]
+    data: Vec<u8>,
+}
+
+/// An owned version of `Command`.
+///
+/// Without `alloc` this is as large as its largest payload, so you may not
+/// want too many of them on the stack.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum OwnedCommand {
+    Ping,
+    Info,
+    Id,
+    Reset,
+    ErasePage { address: u32 },
+    WritePage {
+        address: u32,
+        data: Payload<INT_PAGE_SIZE>,
+    },
+    EraseExBlock { address: u32 },
+    WriteExPage {
+        address: u32,
+        data: Payload<EXT_PAGE_SIZE>,
+    },
+    CrcRxBuffer,
+    ReadRange { address: u32, length: u16 },
+    ExReadRange { address: u32, length: u16 },
+    SetAttr {
+        index: u8,
+        key: Payload<KEY_LEN>,
+        value: Payload<MAX_ATTR_LEN>,
+    },
+    GetAttr { index: u8 },
+    CrcIntFlash { address: u32, length: u32 },
+    CrcExtFlash { address: u32, length: u32 },
+    EraseExPage { address: u32 },
+    ExtFlashInit,
+    ClockOut,
+    WriteFlashUserPages { page1: u32, page2: u32 },
+    ChangeBaud { mode: BaudMode, baud: u32 },
+    Exit,
+    SetStartAddress { address: u32 },
+    Vendor {
+        code: u8,
+        payload: Payload<BUFFER_LEN>,
+    },
+}
+
+/// An owned version of `Response`.
+///
+/// Without `alloc` this is as large as its largest payload, so you may not
+/// want too many of them on the stack.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum OwnedResponse {
+    Overflow,
+    Pong,
+    BadAddress,
+    InternalError,
+    BadArguments,
+    Ok,
+    Unknown,
+    ExtFlashTimeout,
+    ExtFlashPageError,
+    CrcRxBuffer { length: u16, crc: u32 },
+    ReadRange { data: Payload<MAX_RANGE_LEN> },
+    ExReadRange { data: Payload<MAX_RANGE_LEN> },
+    GetAttr {
+        key: Payload<KEY_LEN>,
+        value: Payload<MAX_ATTR_LEN>,
+    },
+    CrcIntFlash { crc: u32 },
+    CrcExtFlash { crc: u32 },
+    Info { info: Payload<MAX_INFO_LEN> },
+    ChangeBaudFail,
+    Id { id: Payload<ID_LEN> },
+    Vendor {
+        code: u8,
+        payload: Payload<MAX_RANGE_LEN>,
+    },
+}
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// The largest range `ResponseDecoder` can hold (everything in the RX buffer
+/// but the response byte).
+const MAX_RANGE_LEN: usize = BUFFER_LEN - 1;
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+/// Parses a hex string into a `Payload`.
+#[cfg(feature = "serde")]
+struct HexVisitor<const N: usize>;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<const N: usize> Payload<N> {
+    /// Copy a slice into a new `Payload`. Fails with `Error::BufferTooSmall`
+    /// if the slice is longer than `N` bytes (unless we have `alloc`).
+    pub fn from_slice(data: &[u8]) -> Result<Payload<N>, Error> {
+        #[cfg(not(feature = "alloc"))]
+        let data = heapless::Vec::from_slice(data).map_err(|_| Error::BufferTooSmall {
+            needed: data.len(),
+            available: N,
+        })?;
+        #[cfg(feature = "alloc")]
+        let data = data.to_vec();
+        Ok(Payload { data })
+    }
+
+    /// Borrow the payload as a slice.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl<const N: usize> Deref for Payload<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl<const N: usize> fmt::LowerHex for Payload<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.as_slice() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<const N: usize> ::serde::Serialize for Payload<N> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ::serde::Serializer,
+    {
+        serializer.collect_str(&format_args!("{:x}", self))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, const N: usize> ::serde::Deserialize<'de> for Payload<N> {
+    fn deserialize<D>(deserializer: D) -> Result<Payload<N>, D::Error>
+    where
+        D: ::serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_str(HexVisitor::<N>)
+    }
+}
+
+impl OwnedCommand {
+    /// Borrow this as a `Command`, e.g. to pass to `CommandEncoder`.
+    pub fn as_command(&self) -> Command<'_> {
+        match *self {
+            OwnedCommand::Ping => Command::Ping,
+            OwnedCommand::Info => Command::Info,
+            OwnedCommand::Id => Command::Id,
+            OwnedCommand::Reset => Command::Reset,
+            OwnedCommand::ErasePage { address } => Command::ErasePage { address },
+            OwnedCommand::WritePage { address, ref data } => Command::WritePage {
+                address,
+                data: data.as_slice(),
+            },
+            OwnedCommand::EraseExBlock { address } => Command::EraseExBlock { address },
+            OwnedCommand::WriteExPage { address, ref data } => Command::WriteExPage {
+                address,
+                data: data.as_slice(),
+            },
+            OwnedCommand::CrcRxBuffer => Command::CrcRxBuffer,
+            OwnedCommand::ReadRange { address, length } => Command::ReadRange { address, length },
+            OwnedCommand::ExReadRange { address, length } => {
+                Command::ExReadRange { address, length }
+            }
+            OwnedCommand::SetAttr {
+                index,
+                ref key,
+                ref value,
+            } => Command::SetAttr {
+                index,
+                key: key.as_slice(),
+                value: value.as_slice(),
+            },
+            OwnedCommand::GetAttr { index } => Command::GetAttr { index },
+            OwnedCommand::CrcIntFlash { address, length } => {
+                Command::CrcIntFlash { address, length }
+            }
+            OwnedCommand::CrcExtFlash { address, length } => {
+                Command::CrcExtFlash { address, length }
+            }
+            OwnedCommand::EraseExPage { address } => Command::EraseExPage { address },
+            OwnedCommand::ExtFlashInit => Command::ExtFlashInit,
+            OwnedCommand::ClockOut => Command::ClockOut,
+            OwnedCommand::WriteFlashUserPages { page1, page2 } => {
+                Command::WriteFlashUserPages { page1, page2 }
+            }
+            OwnedCommand::ChangeBaud { mode, baud } => Command::ChangeBaud { mode, baud },
+            OwnedCommand::Exit => Command::Exit,
+            OwnedCommand::SetStartAddress { address } => Command::SetStartAddress { address },
+            OwnedCommand::Vendor { code, ref payload } => Command::Vendor {
+                code,
+                payload: payload.as_slice(),
+            },
+        }
+    }
+}
+
+impl<'a, 'b> TryFrom<&'b Command<'a>> for OwnedCommand {
+    type Error = Error;
+
+    /// Copy a `Command` out of the decoder. Fails with
+    /// `Error::BufferTooSmall` if a payload is too large to store.
+    fn try_from(command: &'b Command<'a>) -> Result<OwnedCommand, Error> {
+        Ok(match *command {
+            Command::Ping => OwnedCommand::Ping,
+            Command::Info => OwnedCommand::Info,
+            Command::Id => OwnedCommand::Id,
+            Command::Reset => OwnedCommand::Reset,
+            Command::ErasePage { address } => OwnedCommand::ErasePage { address },
+            Command::WritePage { address, data } => OwnedCommand::WritePage {
+                address,
+                data: Payload::from_slice(data)?,
+            },
+            Command::EraseExBlock { address } => OwnedCommand::EraseExBlock { address },
+            Command::WriteExPage { address, data } => OwnedCommand::WriteExPage {
+                address,
+                data: Payload::from_slice(data)?,
+            },
+            Command::CrcRxBuffer => OwnedCommand::CrcRxBuffer,
+            Command::ReadRange { address, length } => OwnedCommand::ReadRange { address, length },
+            Command::ExReadRange { address, length } => {
+                OwnedCommand::ExReadRange { address, length }
+            }
+            Command::SetAttr { index, key, value } => OwnedCommand::SetAttr {
+                index,
+                key: Payload::from_slice(key)?,
+                value: Payload::from_slice(value)?,
+            },
+            Command::GetAttr { index } => OwnedCommand::GetAttr { index },
+            Command::CrcIntFlash { address, length } => {
+                OwnedCommand::CrcIntFlash { address, length }
+            }
+            Command::CrcExtFlash { address, length } => {
+                OwnedCommand::CrcExtFlash { address, length }
+            }
+            Command::EraseExPage { address } => OwnedCommand::EraseExPage { address },
+            Command::ExtFlashInit => OwnedCommand::ExtFlashInit,
+            Command::ClockOut => OwnedCommand::ClockOut,
+            Command::WriteFlashUserPages { page1, page2 } => {
+                OwnedCommand::WriteFlashUserPages { page1, page2 }
+            }
+            Command::ChangeBaud { mode, baud } => OwnedCommand::ChangeBaud { mode, baud },
+            Command::Exit => OwnedCommand::Exit,
+            Command::SetStartAddress { address } => OwnedCommand::SetStartAddress { address },
+            Command::Vendor { code, payload } => OwnedCommand::Vendor {
+                code,
+                payload: Payload::from_slice(payload)?,
+            },
+        })
+    }
+}
+
+impl OwnedResponse {
+    /// Borrow this as a `Response`, e.g. to pass to `ResponseEncoder`.
+    pub fn as_response(&self) -> Response<'_> {
+        match *self {
+            OwnedResponse::Overflow => Response::Overflow,
+            OwnedResponse::Pong => Response::Pong,
+            OwnedResponse::BadAddress => Response::BadAddress,
+            OwnedResponse::InternalError => Response::InternalError,
+            OwnedResponse::BadArguments => Response::BadArguments,
+            OwnedResponse::Ok => Response::Ok,
+            OwnedResponse::Unknown => Response::Unknown,
+            OwnedResponse::ExtFlashTimeout => Response::ExtFlashTimeout,
+            OwnedResponse::ExtFlashPageError => Response::ExtFlashPageError,
+            OwnedResponse::CrcRxBuffer { length, crc } => Response::CrcRxBuffer { length, crc },
+            OwnedResponse::ReadRange { ref data } => Response::ReadRange {
+                data: data.as_slice(),
+            },
+            OwnedResponse::ExReadRange { ref data } => Response::ExReadRange {
+                data: data.as_slice(),
+            },
+            OwnedResponse::GetAttr { ref key, ref value } => Response::GetAttr {
+                key: key.as_slice(),
+                value: value.as_slice(),
+            },
+            OwnedResponse::CrcIntFlash { crc } => Response::CrcIntFlash { crc },
+            OwnedResponse::CrcExtFlash { crc } => Response::CrcExtFlash { crc },
+            OwnedResponse::Info { ref info } => Response::Info {
+                info: info.as_slice(),
+            },
+            OwnedResponse::ChangeBaudFail => Response::ChangeBaudFail,
+            OwnedResponse::Id { ref id } => Response::Id { id: id.as_slice() },
+            OwnedResponse::Vendor { code, ref payload } => Response::Vendor {
+                code,
+                payload: payload.as_slice(),
+            },
+        }
+    }
+}
+
+impl<'a, 'b> TryFrom<&'b Response<'a>> for OwnedResponse {
+    type Error = Error;
+
+    /// Copy a `Response` out of the decoder. Fails with
+    /// `Error::BufferTooSmall` if a payload is too large to store.
+    fn try_from(response: &'b Response<'a>) -> Result<OwnedResponse, Error> {
+        Ok(match *response {
+            Response::Overflow => OwnedResponse::Overflow,
+            Response::Pong => OwnedResponse::Pong,
+            Response::BadAddress => OwnedResponse::BadAddress,
+            Response::InternalError => OwnedResponse::InternalError,
+            Response::BadArguments => OwnedResponse::BadArguments,
+            Response::Ok => OwnedResponse::Ok,
+            Response::Unknown => OwnedResponse::Unknown,
+            Response::ExtFlashTimeout => OwnedResponse::ExtFlashTimeout,
+            Response::ExtFlashPageError => OwnedResponse::ExtFlashPageError,
+            Response::CrcRxBuffer { length, crc } => OwnedResponse::CrcRxBuffer { length, crc },
+            Response::ReadRange { data } => OwnedResponse::ReadRange {
+                data: Payload::from_slice(data)?,
+            },
+            Response::ExReadRange { data } => OwnedResponse::ExReadRange {
+                data: Payload::from_slice(data)?,
+            },
+            Response::GetAttr { key, value } => OwnedResponse::GetAttr {
+                key: Payload::from_slice(key)?,
+                value: Payload::from_slice(value)?,
+            },
+            Response::CrcIntFlash { crc } => OwnedResponse::CrcIntFlash { crc },
+            Response::CrcExtFlash { crc } => OwnedResponse::CrcExtFlash { crc },
+            Response::Info { info } => OwnedResponse::Info {
+                info: Payload::from_slice(info)?,
+            },
+            Response::ChangeBaudFail => OwnedResponse::ChangeBaudFail,
+            Response::Id { id } => OwnedResponse::Id {
+                id: Payload::from_slice(id)?,
+            },
+            Response::Vendor { code, payload } => OwnedResponse::Vendor {
+                code,
+                payload: Payload::from_slice(payload)?,
+            },
+        })
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+#[cfg(feature = "serde")]
+impl<'de, const N: usize> ::serde::de::Visitor<'de> for HexVisitor<N> {
+    type Value = Payload<N>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a hex string of at most {} bytes", N)
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Payload<N>, E>
+    where
+        E: ::serde::de::Error,
+    {
+        let digits = value.as_bytes();
+        if digits.len() % 2 != 0 {
+            return Err(E::invalid_length(digits.len(), &self));
+        }
+        let mut payload = Payload::default();
+        for pair in digits.chunks(2) {
+            let byte = match (hex_digit(pair[0]), hex_digit(pair[1])) {
+                (Some(hi), Some(lo)) => (hi << 4) | lo,
+                _ => return Err(E::invalid_value(::serde::de::Unexpected::Str(value), &self)),
+            };
+            #[cfg(not(feature = "alloc"))]
+            payload
+                .data
+                .push(byte)
+                .map_err(|_| E::invalid_length(digits.len() / 2, &self))?;
+            #[cfg(feature = "alloc")]
+            payload.data.push(byte);
+        }
+        Ok(payload)
+    }
+}
+
+#[cfg(feature = "serde")]
+fn hex_digit(ch: u8) -> Option<u8> {
+    match ch {
+        b'0'..=b'9' => Some(ch - b'0'),
+        b'a'..=b'f' => Some(ch - b'a' + 10),
+        b'A'..=b'F' => Some(ch - b'A' + 10),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use self::std::collections::hash_map::DefaultHasher;
+    use super::*;
+    use core::hash::{Hash, Hasher};
+    use {CommandDecoder, CommandEncoder, Encoder, ResponseDecoder, ESCAPE_CHAR};
+
+    fn hash_of<T: Hash>(value: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn command_outlives_decoder() {
+        let mut queue: [Option<OwnedCommand>; 2] = [None, None];
+        let mut p = CommandDecoder::new();
+        let buffer = [
+            0x00, 0x02, 0x00, 0x00, ESCAPE_CHAR, 0x06, // ErasePage
+            0x07, b'b', b'o', b'a', b'r', b'd', 0, 0, 0, 0x02, 0xAA, 0xBB, ESCAPE_CHAR,
+            0x13, // SetAttr
+        ];
+        let mut input = &buffer[..];
+        let mut idx = 0;
+        while !input.is_empty() {
+            let (used, result) = p.feed(input);
+            if let Some(result) = result {
+                queue[idx] = Some(OwnedCommand::try_from(&result.unwrap()).unwrap());
+                idx += 1;
+            }
+            input = &input[used..];
+        }
+        // Reuse the decoder - the queued commands are unaffected
+        assert_eq!(p.feed(&[ESCAPE_CHAR, 0x01]).1, Some(Ok(Command::Ping)));
+
+        let first = queue[0].clone().unwrap();
+        assert_eq!(first, OwnedCommand::ErasePage { address: 0x200 });
+        assert_eq!(first.as_command(), Command::ErasePage { address: 0x200 });
+        let second = queue[1].as_ref().unwrap();
+        assert_eq!(
+            second.as_command(),
+            Command::SetAttr {
+                index: 7,
+                key: b"board\0\0\0",
+                value: &[0xAA, 0xBB],
+            }
+        );
+        assert_eq!(hash_of(second), hash_of(&second.clone()));
+        assert_ne!(hash_of(second), hash_of(&first));
+
+        let cmd = second.as_command();
+        let mut e = CommandEncoder::new(&cmd).unwrap();
+        let mut out = [0u8; 32];
+        assert_eq!(e.write(&mut out), 14);
+        assert_eq!(&out[0..14], &buffer[6..]);
+    }
+
+    #[test]
+    fn response_outlives_decoder() {
+        let mut p = ResponseDecoder::new();
+        p.set_payload_len(3).unwrap();
+        let (_, result) = p.feed(&[ESCAPE_CHAR, 0x20, 0x01, ESCAPE_CHAR, ESCAPE_CHAR, 0x03]);
+        let owned = OwnedResponse::try_from(&result.unwrap().unwrap()).unwrap();
+        assert_eq!(p.feed(&[ESCAPE_CHAR, 0x11]).1, Some(Ok(Response::Pong)));
+        assert_eq!(
+            owned.as_response(),
+            Response::ReadRange {
+                data: &[0x01, ESCAPE_CHAR, 0x03],
+            }
+        );
+        assert_eq!(owned, owned.clone());
+        assert_eq!(hash_of(&owned), hash_of(&owned.clone()));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        extern crate serde_json;
+
+        let json = r#"[
+            "Ping",
+            {"ErasePage": {"address": 512}},
+            {"SetAttr": {"index": 3, "key": "626f617264000000", "value": "fc01FC"}},
+            {"ChangeBaud": {"mode": "Verify", "baud": 115200}}
+        ]"#;
+        let commands: std::vec::Vec<OwnedCommand> = serde_json::from_str(json).unwrap();
+        assert_eq!(commands.len(), 4);
+        let mut p = CommandDecoder::new();
+        for cmd in commands.iter() {
+            let borrowed = cmd.as_command();
+            let e = CommandEncoder::new(&borrowed).unwrap();
+            let mut buffer = [0u8; 64];
+            let len = e.encode_into(&mut buffer).unwrap();
+            let (used, result) = p.feed(&buffer[..len]);
+            assert_eq!(used, len);
+            let decoded = OwnedCommand::try_from(&result.unwrap().unwrap()).unwrap();
+            assert_eq!(&decoded, cmd);
+        }
+        let text = serde_json::to_string(&commands).unwrap();
+        assert_eq!(
+            text,
+            concat!(
+                r#"["Ping",{"ErasePage":{"address":512}},"#,
+                r#"{"SetAttr":{"index":3,"key":"626f617264000000","value":"fc01fc"}},"#,
+                r#"{"ChangeBaud":{"mode":"Verify","baud":115200}}]"#
+            )
+        );
+
+        let rsp = OwnedResponse::GetAttr {
+            key: Payload::from_slice(b"board\0\0\0").unwrap(),
+            value: Payload::from_slice(b"nrf52").unwrap(),
+        };
+        let text = serde_json::to_string(&rsp).unwrap();
+        assert_eq!(
+            text,
+            r#"{"GetAttr":{"key":"626f617264000000","value":"6e72663532"}}"#
+        );
+        assert_eq!(serde_json::from_str::<OwnedResponse>(&text).unwrap(), rsp);
+
+        let err: Error =
+            serde_json::from_str(r#"{"BufferTooSmall":{"needed":12,"available":10}}"#).unwrap();
+        assert_eq!(
+            err,
+            Error::BufferTooSmall {
+                needed: 12,
+                available: 10
+            }
+        );
+        assert!(serde_json::from_str::<OwnedResponse>(r#"{"Info":{"info":"abc"}}"#).is_err());
+        assert!(serde_json::from_str::<OwnedResponse>(r#"{"Info":{"info":"zz"}}"#).is_err());
+    }
+
+    #[cfg(not(feature = "alloc"))]
+    #[test]
+    fn oversized_payloads_are_rejected() {
+        let data = [0u8; INT_PAGE_SIZE + 1];
+        let cmd = Command::WritePage {
+            address: 0,
+            data: &data,
+        };
+        assert_eq!(
+            OwnedCommand::try_from(&cmd),
+            Err(Error::BufferTooSmall {
+                needed: INT_PAGE_SIZE + 1,
+                available: INT_PAGE_SIZE,
+            })
+        );
+        let rsp = Response::Info { info: &data };
+        assert_eq!(
+            OwnedResponse::try_from(&rsp),
+            Err(Error::BufferTooSmall {
+                needed: INT_PAGE_SIZE + 1,
+                available: MAX_INFO_LEN,
+            })
+        );
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,368 @@
+//! A lock-free queue for getting bytes from an interrupt handler to the
+//! `CommandDecoder`.
+//!
+//! A `Command` borrows the decoder which produced it, so the decoder can't
+//! live in the UART interrupt handler if the commands are to be carried out
+//! in thread mode. Instead the interrupt handler puts bytes into a
+//! `ByteQueue` through its `Producer`, and thread mode takes them out
+//! through the `Consumer` and feeds them to the decoder with `decode`.
+//!
+//! Only atomic loads and stores are used, so this works on cores without
+//! compare-and-swap, such as the Cortex-M0.
+//!
+//! ```
+//! use tockloader_proto::queue::ByteQueue;
+//! use tockloader_proto::{Command, CommandDecoder};
+//!
+//! let mut queue: ByteQueue<64> = ByteQueue::new();
+//! let (mut producer, mut consumer) = queue.split();
+//! let mut decoder = CommandDecoder::new();
+//!
+//! // In the interrupt handler
+//! for byte in &[0xFC, 0x01] {
+//!     producer.enqueue(*byte).unwrap();
+//! }
+//!
+//! // In thread mode
+//! assert_eq!(consumer.decode(&mut decoder), Some(Ok(Command::Ping)));
+//! ```
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{Command, CommandDecoder, Error};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A single-producer, single-consumer queue of bytes, which holds up to
+/// `N - 1` bytes.
+///
+/// One slot is always left empty, so `N` must be at least 2. Anything
+/// smaller fails to build:
+///
+/// ```compile_fail
+/// use tockloader_proto::queue::ByteQueue;
+///
+/// let queue: ByteQueue<1> = ByteQueue::new();
+/// ```
+///
+/// Nothing can be queued until the queue is split into a `Producer` and a
+/// `Consumer` with `split`.
+pub struct ByteQueue<const N: usize> {
+    buffer: UnsafeCell<[u8; N]>,
+    /// Where the consumer reads next. Only written by the consumer.
+    head: AtomicUsize,
+    /// Where the producer writes next. Only written by the producer.
+    tail: AtomicUsize,
+}
+
+/// Puts bytes into a `ByteQueue`. Typically lives in an interrupt handler.
+pub struct Producer<'a, const N: usize> {
+    queue: &'a ByteQueue<N>,
+}
+
+/// Takes bytes out of a `ByteQueue`.
+pub struct Consumer<'a, const N: usize> {
+    queue: &'a ByteQueue<N>,
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl<const N: usize> ByteQueue<N> {
+    /// Stops a queue which could never hold anything from being built.
+    const CHECK_SIZE: () = assert!(N >= 2, "a ByteQueue needs N of at least 2");
+
+    /// Create an empty queue. This is a `const fn` so the queue can be a
+    /// `static`.
+    pub const fn new() -> ByteQueue<N> {
+        let () = Self::CHECK_SIZE;
+        ByteQueue {
+            buffer: UnsafeCell::new([0u8; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// The most bytes the queue can hold.
+    pub const fn capacity(&self) -> usize {
+        N - 1
+    }
+
+    /// Split the queue into its two ends. As this needs a unique borrow,
+    /// there can only ever be one of each.
+    pub fn split(&mut self) -> (Producer<'_, N>, Consumer<'_, N>) {
+        (Producer { queue: self }, Consumer { queue: self })
+    }
+}
+
+impl<const N: usize> Default for ByteQueue<N> {
+    fn default() -> ByteQueue<N> {
+        ByteQueue::new()
+    }
+}
+
+// The buffer is only touched through a `Producer` and a `Consumer`, and
+// `split` makes sure there's only one of each. The producer only writes
+// slots the consumer has finished with, and the consumer only reads slots
+// the producer has finished with.
+unsafe impl<const N: usize> Sync for ByteQueue<N> {}
+
+impl<'a, const N: usize> Producer<'a, N> {
+    /// Add a byte to the back of the queue. If the queue is full, the byte
+    /// is handed back.
+    pub fn enqueue(&mut self, byte: u8) -> Result<(), u8> {
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        let next = increment::<N>(tail);
+        if next == self.queue.head.load(Ordering::Acquire) {
+            return Err(byte);
+        }
+        // The consumer won't look at this slot until we move `tail` past it
+        unsafe {
+            (*self.queue.buffer.get())[tail] = byte;
+        }
+        self.queue.tail.store(next, Ordering::Release);
+        Ok(())
+    }
+
+    /// Is there no room for another byte?
+    pub fn is_full(&self) -> bool {
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        increment::<N>(tail) == self.queue.head.load(Ordering::Acquire)
+    }
+}
+
+impl<'a, const N: usize> Consumer<'a, N> {
+    /// Take a byte from the front of the queue, if there is one.
+    pub fn dequeue(&mut self) -> Option<u8> {
+        let head = self.queue.head.load(Ordering::Relaxed);
+        if head == self.queue.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        // The producer won't touch this slot until we move `head` past it
+        let byte = unsafe { (*self.queue.buffer.get())[head] };
+        self.queue
+            .head
+            .store(increment::<N>(head), Ordering::Release);
+        Some(byte)
+    }
+
+    /// How many bytes are waiting. The producer may add more at any time.
+    pub fn len(&self) -> usize {
+        let head = self.queue.head.load(Ordering::Relaxed);
+        let tail = self.queue.tail.load(Ordering::Acquire);
+        if tail >= head {
+            tail - head
+        } else {
+            N - head + tail
+        }
+    }
+
+    /// Are there no bytes waiting?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Feed queued bytes to `decoder` until a frame ends or the queue is
+    /// empty.
+    ///
+    /// Returns the decoded `Command` (or the `Error` it produced) if a frame
+    /// ended, leaving any bytes after it in the queue. Returns `None` once
+    /// the queue is empty.
+    pub fn decode<'d>(
+        &mut self,
+        decoder: &'d mut CommandDecoder,
+    ) -> Option<Result<Command<'d>, Error>> {
+        while let Some(byte) = self.dequeue() {
+            if let Some(cmd) = decoder.step(byte) {
+                return decoder.finish(cmd).transpose();
+            }
+        }
+        None
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+fn increment<const N: usize>(index: usize) -> usize {
+    if index + 1 == N {
+        0
+    } else {
+        index + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use self::std::thread;
+    use self::std::vec::Vec;
+    use super::*;
+    use {CommandEncoder, ESCAPE_CHAR};
+
+    #[test]
+    fn fill_and_empty() {
+        let mut queue: ByteQueue<4> = ByteQueue::new();
+        assert_eq!(queue.capacity(), 3);
+        let (mut producer, mut consumer) = queue.split();
+        // Go round a few times so the indices wrap
+        for round in 0..5u8 {
+            for i in 0..3 {
+                assert!(!producer.is_full());
+                producer.enqueue(round + i).unwrap();
+            }
+            assert!(producer.is_full());
+            assert_eq!(producer.enqueue(0xAA), Err(0xAA));
+            assert_eq!(consumer.len(), 3);
+            for i in 0..3 {
+                assert_eq!(consumer.dequeue(), Some(round + i));
+            }
+            assert!(consumer.is_empty());
+            assert_eq!(consumer.dequeue(), None);
+        }
+    }
+
+    #[test]
+    fn decode_leaves_the_rest() {
+        let mut queue: ByteQueue<16> = ByteQueue::new();
+        let (mut producer, mut consumer) = queue.split();
+        let mut decoder = CommandDecoder::new();
+        for byte in &[ESCAPE_CHAR, 0x01, 0x01, 0x02, ESCAPE_CHAR, 0x06, 0x00] {
+            producer.enqueue(*byte).unwrap();
+        }
+        assert_eq!(consumer.decode(&mut decoder), Some(Ok(Command::Ping)));
+        assert_eq!(consumer.len(), 5);
+        assert!(consumer.decode(&mut decoder).unwrap().is_err());
+        assert_eq!(consumer.len(), 1);
+        assert_eq!(consumer.decode(&mut decoder), None);
+        assert!(consumer.is_empty());
+    }
+
+    #[test]
+    fn threaded_bytes() {
+        const COUNT: usize = 200_000;
+        let mut queue: ByteQueue<7> = ByteQueue::new();
+        let (mut producer, mut consumer) = queue.split();
+        thread::scope(|s| {
+            s.spawn(move || {
+                for i in 0..COUNT {
+                    let mut byte = i as u8;
+                    while let Err(b) = producer.enqueue(byte) {
+                        byte = b;
+                        thread::yield_now();
+                    }
+                }
+            });
+            let mut expected = 0usize;
+            while expected < COUNT {
+                match consumer.dequeue() {
+                    Some(byte) => {
+                        assert_eq!(byte, expected as u8);
+                        expected += 1;
+                    }
+                    None => thread::yield_now(),
+                }
+            }
+            assert_eq!(consumer.dequeue(), None);
+        });
+    }
+
+    #[test]
+    fn threaded_commands() {
+        const COUNT: u32 = 2_000;
+        let mut queue: ByteQueue<32> = ByteQueue::new();
+        let (mut producer, mut consumer) = queue.split();
+        let mut decoder = CommandDecoder::new();
+        thread::scope(|s| {
+            s.spawn(move || {
+                let mut page = [0u8; 512];
+                for i in 0..COUNT {
+                    // Plenty of escape characters, to split them across
+                    // the end of the queue
+                    for (idx, b) in page.iter_mut().enumerate() {
+                        *b = if idx % 3 == 0 { ESCAPE_CHAR } else { i as u8 };
+                    }
+                    let cmd = if i % 2 == 0 {
+                        Command::ErasePage { address: i * 512 }
+                    } else {
+                        Command::WritePage {
+                            address: i * 512,
+                            data: &page,
+                        }
+                    };
+                    for byte in CommandEncoder::new(&cmd).unwrap() {
+                        while producer.enqueue(byte).is_err() {
+                            thread::yield_now();
+                        }
+                    }
+                }
+            });
+            let mut seen = Vec::new();
+            while seen.len() < COUNT as usize {
+                match consumer.decode(&mut decoder) {
+                    Some(Ok(Command::ErasePage { address })) => seen.push(address),
+                    Some(Ok(Command::WritePage { address, data })) => {
+                        let fill = (address / 512) as u8;
+                        assert!(data
+                            .iter()
+                            .enumerate()
+                            .all(|(idx, b)| *b == if idx % 3 == 0 { ESCAPE_CHAR } else { fill }));
+                        seen.push(address);
+                    }
+                    Some(other) => panic!("unexpected {:?}", other),
+                    None => thread::yield_now(),
+                }
+            }
+            let expected: Vec<u32> = (0..COUNT).map(|i| i * 512).collect();
+            assert_eq!(seen, expected);
+        });
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,533 @@
+//! Runs the bootloader's command loop over a serial port.
+//!
+//! `Server` reads bytes from the port, decodes them with a
+//! `CommandDecoder`, carries out each command with a `Dispatcher`, and
+//! writes the encoded response back. It never blocks: when the port can't
+//! take or give a byte right now, `poll` returns so the caller can sleep
+//! until the next interrupt. `serve` wraps that up in a loop.
+//!
+//! With the `embedded-hal` feature this works with any
+//! `embedded_hal::serial::Read<u8> + Write<u8>`. With the `embedded-io`
+//! feature it works with any `embedded_io` port which implements
+//! `ReadReady` and `WriteReady`, via `poll_io` and `serve_io`.
+//!
+//! `Command::Reset` empties the decoder's buffer and has no response, and
+//! `Command::Exit` makes `poll` return `Status::Boot`. Everything else goes
+//! to the `Dispatcher`.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+#[cfg(feature = "embedded-hal")]
+use embedded_hal::serial;
+#[cfg(feature = "embedded-io")]
+use embedded_io::{Read, ReadReady, Write, WriteReady};
+
+use super::device::{Dispatcher, Status};
+use super::{
+    Command, CommandDecoder, Error, Response, ResponseEncoder, BUFFER_LEN, ESCAPE_CHAR,
+    RES_INTERROR,
+};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Serves commands arriving on a serial port. See the module documentation.
+pub struct Server {
+    decoder: CommandDecoder,
+    /// The encoded response we're sending.
+    tx: [u8; TX_LEN],
+    tx_len: usize,
+    tx_pos: usize,
+}
+
+/// An error from one half of an `embedded-hal` serial port.
+#[cfg(feature = "embedded-hal")]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SerialError<R, W> {
+    /// Reading failed. The frame being received is thrown away.
+    Read(R),
+    /// Writing failed. The rest of the response is thrown away.
+    Write(W),
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// Room for the longest response, even if every payload byte is escaped.
+const TX_LEN: usize = 2 + 2 * BUFFER_LEN;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl Server {
+    /// Create a `Server` with nothing received and nothing to send.
+    pub fn new() -> Server {
+        Server {
+            decoder: CommandDecoder::new(),
+            tx: [0u8; TX_LEN],
+            tx_len: 0,
+            tx_pos: 0,
+        }
+    }
+
+    /// Is there still some of a response to send?
+    pub fn is_sending(&self) -> bool {
+        self.tx_pos < self.tx_len
+    }
+
+    /// Do as much as can be done without blocking.
+    ///
+    /// Sends what's left of the last response, then receives bytes and
+    /// handles each command as it arrives. Returns `Status::Continue` when
+    /// the port would block, and `Status::Boot` when the host sends
+    /// `Command::Exit`.
+    #[cfg(feature = "embedded-hal")]
+    #[allow(clippy::type_complexity)]
+    pub fn poll<S>(
+        &mut self,
+        serial: &mut S,
+        dispatcher: &mut Dispatcher,
+    ) -> Result<Status, SerialError<<S as serial::Read<u8>>::Error, <S as serial::Write<u8>>::Error>>
+    where
+        S: serial::Read<u8> + serial::Write<u8>,
+    {
+        loop {
+            while self.is_sending() {
+                match serial.write(self.tx[self.tx_pos]) {
+                    Ok(()) => self.tx_pos += 1,
+                    Err(nb::Error::WouldBlock) => return Ok(Status::Continue),
+                    Err(nb::Error::Other(e)) => {
+                        self.tx_len = 0;
+                        self.tx_pos = 0;
+                        return Err(SerialError::Write(e));
+                    }
+                }
+            }
+            let byte = match serial.read() {
+                Ok(byte) => byte,
+                Err(nb::Error::WouldBlock) => return Ok(Status::Continue),
+                Err(nb::Error::Other(e)) => {
+                    self.decoder.reset();
+                    return Err(SerialError::Read(e));
+                }
+            };
+            if let Status::Boot = self.receive(byte, dispatcher) {
+                return Ok(Status::Boot);
+            }
+        }
+    }
+
+    /// Do as much as can be done without blocking, as for `poll`, but on an
+    /// `embedded-io` port.
+    #[cfg(feature = "embedded-io")]
+    pub fn poll_io<S>(
+        &mut self,
+        serial: &mut S,
+        dispatcher: &mut Dispatcher,
+    ) -> Result<Status, S::Error>
+    where
+        S: Read + ReadReady + Write + WriteReady,
+    {
+        loop {
+            while self.is_sending() {
+                if !serial.write_ready()? {
+                    return Ok(Status::Continue);
+                }
+                match serial.write(&self.tx[self.tx_pos..self.tx_len]) {
+                    Ok(n) => self.tx_pos += n,
+                    Err(e) => {
+                        self.tx_len = 0;
+                        self.tx_pos = 0;
+                        return Err(e);
+                    }
+                }
+            }
+            if !serial.read_ready()? {
+                return Ok(Status::Continue);
+            }
+            // One byte at a time, so nothing after the end of a frame has
+            // to be kept for later
+            let mut byte = [0u8; 1];
+            match serial.read(&mut byte) {
+                Ok(0) => return Ok(Status::Continue),
+                Ok(_) => {}
+                Err(e) => {
+                    self.decoder.reset();
+                    return Err(e);
+                }
+            }
+            if let Status::Boot = self.receive(byte[0], dispatcher) {
+                return Ok(Status::Boot);
+            }
+        }
+    }
+}
+
+impl Default for Server {
+    fn default() -> Server {
+        Server::new()
+    }
+}
+
+/// Serve commands on `serial` until the host sends `Command::Exit`.
+///
+/// `idle` is called whenever the port would block, and would typically wait
+/// for an interrupt.
+#[cfg(feature = "embedded-hal")]
+#[allow(clippy::type_complexity)]
+pub fn serve<S, F>(
+    serial: &mut S,
+    dispatcher: &mut Dispatcher,
+    mut idle: F,
+) -> Result<(), SerialError<<S as serial::Read<u8>>::Error, <S as serial::Write<u8>>::Error>>
+where
+    S: serial::Read<u8> + serial::Write<u8>,
+    F: FnMut(),
+{
+    let mut server = Server::new();
+    loop {
+        match server.poll(serial, dispatcher)? {
+            Status::Continue => idle(),
+            Status::Boot => return Ok(()),
+        }
+    }
+}
+
+/// Serve commands on an `embedded-io` port until the host sends
+/// `Command::Exit`, as for `serve`.
+#[cfg(feature = "embedded-io")]
+pub fn serve_io<S, F>(
+    serial: &mut S,
+    dispatcher: &mut Dispatcher,
+    mut idle: F,
+) -> Result<(), S::Error>
+where
+    S: Read + ReadReady + Write + WriteReady,
+    F: FnMut(),
+{
+    let mut server = Server::new();
+    loop {
+        match server.poll_io(serial, dispatcher)? {
+            Status::Continue => idle(),
+            Status::Boot => return Ok(()),
+        }
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl Server {
+    /// Decode a byte, and if it finishes a command, carry it out and queue
+    /// up the response.
+    fn receive(&mut self, byte: u8, dispatcher: &mut Dispatcher) -> Status {
+        let cmd = match self.decoder.step(byte) {
+            Some(cmd) => cmd,
+            None => return Status::Continue,
+        };
+        let response = match self.decoder.finish(cmd) {
+            Ok(None) | Ok(Some(Command::Reset)) => return Status::Continue,
+            Ok(Some(Command::Exit)) => return Status::Boot,
+            Ok(Some(ref command)) => dispatcher.dispatch(command),
+            Err(ref e) => error_response(e),
+        };
+        self.tx_pos = 0;
+        self.tx_len = ResponseEncoder::new(&response)
+            .and_then(|encoder| encoder.encode_into(&mut self.tx))
+            .unwrap_or_else(|_| {
+                // A service gave us a response we can't send
+                self.tx[0] = ESCAPE_CHAR;
+                self.tx[1] = RES_INTERROR;
+                2
+            });
+        Status::Continue
+    }
+}
+
+/// The response to a command which wouldn't decode.
+fn error_response(error: &Error) -> Response<'static> {
+    match *error {
+        Error::UnalignedAddress { .. } => Response::BadAddress,
+        _ => Response::BadArguments,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use self::std::collections::VecDeque;
+    use self::std::vec::Vec;
+    use super::*;
+    use device::Service;
+    use {CommandEncoder, ResponseDecoder, ESCAPE_CHAR};
+
+    /// A serial port where the host's bytes are all waiting, and the
+    /// transmitter is only ready every `tx_every` attempts.
+    struct Port {
+        rx: VecDeque<u8>,
+        tx: Vec<u8>,
+        tx_every: usize,
+        tx_attempts: usize,
+    }
+
+    impl Port {
+        fn new(commands: &[Command], tx_every: usize) -> Port {
+            let mut rx = VecDeque::new();
+            for cmd in commands {
+                rx.extend(CommandEncoder::new(cmd).unwrap());
+            }
+            Port {
+                rx,
+                tx: Vec::new(),
+                tx_every,
+                tx_attempts: 0,
+            }
+        }
+
+        fn tx_ready(&mut self) -> bool {
+            self.tx_attempts += 1;
+            self.tx_attempts % self.tx_every == 0
+        }
+
+        fn responses(&self) -> Vec<Response<'static>> {
+            let mut decoder = ResponseDecoder::new();
+            let mut out = Vec::new();
+            let mut bytes = &self.tx[..];
+            while !bytes.is_empty() {
+                let (used, result) = decoder.feed(bytes);
+                if let Some(result) = result {
+                    out.push(match result.unwrap() {
+                        Response::Pong => Response::Pong,
+                        Response::Ok => Response::Ok,
+                        Response::BadArguments => Response::BadArguments,
+                        Response::BadAddress => Response::BadAddress,
+                        Response::Unknown => Response::Unknown,
+                        other => panic!("unexpected {:?}", other),
+                    });
+                }
+                bytes = &bytes[used..];
+            }
+            out
+        }
+    }
+
+    #[cfg(feature = "embedded-hal")]
+    impl serial::Read<u8> for Port {
+        type Error = ();
+
+        fn read(&mut self) -> nb::Result<u8, ()> {
+            self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    #[cfg(feature = "embedded-hal")]
+    impl serial::Write<u8> for Port {
+        type Error = ();
+
+        fn write(&mut self, byte: u8) -> nb::Result<(), ()> {
+            if self.tx_ready() {
+                self.tx.push(byte);
+                Ok(())
+            } else {
+                Err(nb::Error::WouldBlock)
+            }
+        }
+
+        fn flush(&mut self) -> nb::Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl embedded_io::ErrorType for Port {
+        type Error = embedded_io::ErrorKind;
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl Read for Port {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let mut n = 0;
+            while n < buf.len() {
+                match self.rx.pop_front() {
+                    Some(b) => buf[n] = b,
+                    None => break,
+                }
+                n += 1;
+            }
+            Ok(n)
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl ReadReady for Port {
+        fn read_ready(&mut self) -> Result<bool, Self::Error> {
+            Ok(!self.rx.is_empty())
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl Write for Port {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            // Only a few bytes fit in the FIFO at once
+            let n = buf.len().min(3);
+            self.tx.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "embedded-io")]
+    impl WriteReady for Port {
+        fn write_ready(&mut self) -> Result<bool, Self::Error> {
+            Ok(self.tx_ready())
+        }
+    }
+
+    /// Erases anything.
+    struct Eraser;
+
+    impl Service for Eraser {
+        fn handle(&mut self, command: &Command) -> Option<Response<'_>> {
+            match *command {
+                Command::ErasePage { .. } => Some(Response::Ok),
+                _ => None,
+            }
+        }
+    }
+
+    fn commands() -> [Command<'static>; 6] {
+        [
+            Command::Ping,
+            Command::ErasePage { address: 0x1000 },
+            Command::Reset,
+            Command::GetAttr { index: 1 },
+            Command::EraseExPage { address: 0x1000 },
+            Command::Exit,
+        ]
+    }
+
+    fn expected() -> [Response<'static>; 4] {
+        [
+            Response::Pong,
+            Response::Ok,
+            Response::Unknown,
+            Response::Unknown,
+        ]
+    }
+
+    /// A port with a command which is too short, then one which isn't
+    /// aligned.
+    fn bad_frames() -> Port {
+        let mut port = Port::new(&[], 1);
+        port.rx.extend(&[0x00, 0x01, ESCAPE_CHAR, 0x06]);
+        port.rx.extend(&[0x10, 0x00, 0x00, 0x00, ESCAPE_CHAR, 0x06]);
+        port
+    }
+
+    #[cfg(feature = "embedded-hal")]
+    #[test]
+    fn serve_hal() {
+        let mut port = Port::new(&commands(), 3);
+        let mut eraser = Eraser;
+        let mut services: [&mut dyn Service; 1] = [&mut eraser];
+        let mut dispatcher = Dispatcher::new(&mut services);
+        let mut idles = 0;
+        serve(&mut port, &mut dispatcher, || idles += 1).unwrap();
+        assert_eq!(port.responses(), expected());
+        // Each blocked byte means one trip round the idle loop, not a spin
+        assert_eq!(idles, port.tx.len() * 2);
+        assert!(port.rx.is_empty());
+    }
+
+    #[cfg(feature = "embedded-hal")]
+    #[test]
+    fn poll_hal() {
+        let mut port = bad_frames();
+        let mut services: [&mut dyn Service; 0] = [];
+        let mut dispatcher = Dispatcher::new(&mut services);
+        let mut server = Server::new();
+        assert_eq!(
+            server.poll(&mut port, &mut dispatcher),
+            Ok(Status::Continue)
+        );
+        assert!(!server.is_sending());
+        assert_eq!(
+            port.responses(),
+            [Response::BadArguments, Response::BadAddress]
+        );
+    }
+
+    #[cfg(feature = "embedded-io")]
+    #[test]
+    fn serve_embedded_io() {
+        let mut port = Port::new(&commands(), 2);
+        let mut eraser = Eraser;
+        let mut services: [&mut dyn Service; 1] = [&mut eraser];
+        let mut dispatcher = Dispatcher::new(&mut services);
+        let mut idles = 0;
+        serve_io(&mut port, &mut dispatcher, || idles += 1).unwrap();
+        assert_eq!(port.responses(), expected());
+        assert!(idles > 0);
+        assert!(port.rx.is_empty());
+    }
+
+    #[cfg(feature = "embedded-io")]
+    #[test]
+    fn poll_embedded_io() {
+        let mut port = bad_frames();
+        let mut services: [&mut dyn Service; 0] = [];
+        let mut dispatcher = Dispatcher::new(&mut services);
+        let mut server = Server::new();
+        assert_eq!(
+            server.poll_io(&mut port, &mut dispatcher),
+            Ok(Status::Continue)
+        );
+        assert!(!server.is_sending());
+        assert_eq!(
+            port.responses(),
+            [Response::BadArguments, Response::BadAddress]
+        );
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
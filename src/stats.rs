@@ -0,0 +1,380 @@
+//! Link statistics gathered by the decoders.
+//!
+//! With the `stats` feature enabled, `CommandDecoder` and `ResponseDecoder`
+//! both keep a `Stats` as they work, which you can get at with their
+//! `stats` method. A bootloader can send its counters to the host as a
+//! vendor-specific response using `Report`, and a host can print them with
+//! `Display`.
+//!
+//! ```
+//! use tockloader_proto::stats::{Report, Stats};
+//! use tockloader_proto::vendor::Message;
+//! use tockloader_proto::CommandDecoder;
+//!
+//! let mut decoder = CommandDecoder::new();
+//! decoder.feed(&[0xFC, 0x01]);
+//! let mut buffer = [0u8; Stats::LEN];
+//! let response = Report::<0xE5>(*decoder.stats()).to_response(&mut buffer).unwrap();
+//! let report = Report::<0xE5>::from_response(&response).unwrap().unwrap();
+//! assert_eq!(report.0.frames(0x01), 1);
+//! ```
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use byteorder::{ByteOrder, LittleEndian};
+use core::fmt;
+
+use super::vendor::{self, Message};
+use super::Error;
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// Counters describing what a decoder has seen.
+///
+/// All the counters wrap rather than saturate.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct Stats {
+    /// Frames decoded, indexed by `slot`.
+    frames: [u32; FRAME_SLOTS],
+    /// Escape characters which started an escape sequence.
+    pub escapes: u32,
+    /// Escape sequences which were a literal `0xFC` in the payload.
+    pub double_escapes: u32,
+    /// Bytes dropped because the RX buffer was full.
+    pub overflows: u32,
+    /// Escape sequences with a code the decoder didn't recognise.
+    pub unknown_codes: u32,
+    /// Frames rejected with `Error::BadArguments`.
+    pub bad_arguments: u32,
+    /// Bytes thrown away without becoming part of a decoded frame, because
+    /// the frame was rejected or the decoder was reset.
+    pub discarded: u32,
+}
+
+/// Carries a `Stats` in a vendor-specific message with the given code.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Report<const CODE: u8>(pub Stats);
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Types
+//
+// ****************************************************************************
+
+// None
+
+// ****************************************************************************
+//
+// Private Data
+//
+// ****************************************************************************
+
+/// Standard codes run from zero to here.
+const LAST_STANDARD_CODE: u8 = 0x27;
+const STANDARD_SLOTS: usize = LAST_STANDARD_CODE as usize + 1;
+const FRAME_SLOTS: usize = STANDARD_SLOTS + super::VENDOR_CODES;
+/// The counters which aren't per-frame.
+const NUM_COUNTERS: usize = 6;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl Stats {
+    /// The length of a `Stats` once written out with `write`.
+    pub const LEN: usize = (NUM_COUNTERS + FRAME_SLOTS) * 4;
+
+    /// Create a `Stats` with every counter at zero.
+    pub const fn new() -> Stats {
+        Stats {
+            frames: [0; FRAME_SLOTS],
+            escapes: 0,
+            double_escapes: 0,
+            overflows: 0,
+            unknown_codes: 0,
+            bad_arguments: 0,
+            discarded: 0,
+        }
+    }
+
+    /// How many frames with this command or response code have been
+    /// decoded.
+    pub fn frames(&self, code: u8) -> u32 {
+        slot(code).map_or(0, |idx| self.frames[idx])
+    }
+
+    /// The total number of frames decoded.
+    pub fn total_frames(&self) -> u32 {
+        self.frames.iter().fold(0, |acc, n| acc.wrapping_add(*n))
+    }
+
+    /// Iterate through the `(code, count)` pairs for every code which has
+    /// been seen at least once.
+    pub fn frame_counts(&self) -> impl Iterator<Item = (u8, u32)> + '_ {
+        self.frames
+            .iter()
+            .enumerate()
+            .filter(|&(_, count)| *count != 0)
+            .map(|(idx, count)| (code(idx), *count))
+    }
+
+    /// Set every counter back to zero.
+    pub fn clear(&mut self) {
+        *self = Stats::new();
+    }
+
+    /// Parse counters written out by `write`. The payload must be exactly
+    /// `LEN` bytes long.
+    pub fn parse(payload: &[u8]) -> Result<Stats, Error> {
+        if payload.len() != Stats::LEN {
+            return Err(Error::BadArguments {
+                code: 0,
+                expected: Stats::LEN,
+                actual: payload.len(),
+            });
+        }
+        let mut words = payload.chunks(4).map(LittleEndian::read_u32);
+        let mut stats = Stats::new();
+        for counter in stats.counters_mut().iter_mut() {
+            **counter = words.next().unwrap_or(0);
+        }
+        for (frame, word) in stats.frames.iter_mut().zip(words) {
+            *frame = word;
+        }
+        Ok(stats)
+    }
+
+    /// Write the counters out as little-endian `u32`s into a buffer which
+    /// is exactly `LEN` bytes long.
+    pub fn write(&self, payload: &mut [u8]) {
+        let counters = [
+            self.escapes,
+            self.double_escapes,
+            self.overflows,
+            self.unknown_codes,
+            self.bad_arguments,
+            self.discarded,
+        ];
+        let words = counters.iter().chain(self.frames.iter());
+        for (chunk, word) in payload.chunks_mut(4).zip(words) {
+            LittleEndian::write_u32(chunk, *word);
+        }
+    }
+
+    pub(crate) fn frame(&mut self, code: u8) {
+        if let Some(idx) = slot(code) {
+            bump(&mut self.frames[idx], 1);
+        }
+    }
+
+    pub(crate) fn error(&mut self, error: &Error, discarded: usize) {
+        if let Error::BadArguments { .. } = *error {
+            bump(&mut self.bad_arguments, 1);
+        }
+        self.discard(discarded);
+    }
+
+    pub(crate) fn escape(&mut self) {
+        bump(&mut self.escapes, 1);
+    }
+
+    pub(crate) fn double_escape(&mut self) {
+        bump(&mut self.double_escapes, 1);
+    }
+
+    pub(crate) fn overflow(&mut self) {
+        bump(&mut self.overflows, 1);
+    }
+
+    pub(crate) fn unknown_code(&mut self) {
+        bump(&mut self.unknown_codes, 1);
+    }
+
+    pub(crate) fn discard(&mut self, count: usize) {
+        bump(&mut self.discarded, count as u32);
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats::new()
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "frames: {}", self.total_frames())?;
+        for (idx, (code, count)) in self.frame_counts().enumerate() {
+            let sep = if idx == 0 { " (" } else { ", " };
+            write!(f, "{}0x{:02x}: {}", sep, code, count)?;
+        }
+        if self.total_frames() != 0 {
+            write!(f, ")")?;
+        }
+        write!(
+            f,
+            "; escapes: {}; double escapes: {}; overflows: {}; unknown codes: {}; bad arguments: {}; discarded: {}",
+            self.escapes,
+            self.double_escapes,
+            self.overflows,
+            self.unknown_codes,
+            self.bad_arguments,
+            self.discarded
+        )
+    }
+}
+
+impl<const CODE: u8> Message for Report<CODE> {
+    const CODE: u8 = CODE;
+    const LEN: usize = Stats::LEN;
+
+    fn parse(payload: &[u8]) -> Result<Report<CODE>, Error> {
+        Stats::parse(payload).map(Report)
+    }
+
+    fn write(&self, payload: &mut [u8]) {
+        self.0.write(payload);
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+impl Stats {
+    /// The counters in the order `write` sends them.
+    fn counters_mut(&mut self) -> [&mut u32; NUM_COUNTERS] {
+        [
+            &mut self.escapes,
+            &mut self.double_escapes,
+            &mut self.overflows,
+            &mut self.unknown_codes,
+            &mut self.bad_arguments,
+            &mut self.discarded,
+        ]
+    }
+}
+
+/// Where the count for `code` lives in `Stats::frames`.
+fn slot(code: u8) -> Option<usize> {
+    if code <= LAST_STANDARD_CODE {
+        Some(code as usize)
+    } else if vendor::is_vendor_code(code) {
+        Some(STANDARD_SLOTS + (code - vendor::FIRST_CODE) as usize)
+    } else {
+        None
+    }
+}
+
+/// The inverse of `slot`.
+fn code(slot: usize) -> u8 {
+    if slot < STANDARD_SLOTS {
+        slot as u8
+    } else {
+        vendor::FIRST_CODE + (slot - STANDARD_SLOTS) as u8
+    }
+}
+
+fn bump(counter: &mut u32, by: u32) {
+    *counter = counter.wrapping_add(by);
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use self::std::string::ToString;
+    use super::*;
+
+    #[test]
+    fn slots() {
+        for code in 0..=255u8 {
+            if let Some(idx) = slot(code) {
+                assert_eq!(super::code(idx), code);
+            }
+        }
+        assert_eq!(slot(0x28), None);
+        assert_eq!(slot(vendor::LAST_CODE), Some(FRAME_SLOTS - 1));
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut stats = Stats::new();
+        stats.frame(0x01);
+        stats.frame(0x01);
+        stats.frame(0xE3);
+        stats.escape();
+        stats.double_escape();
+        stats.error(
+            &Error::BadArguments {
+                code: 0x06,
+                expected: 4,
+                actual: 3,
+            },
+            3,
+        );
+        stats.discard(u32::MAX as usize);
+        assert_eq!(stats.discarded, 2);
+        assert_eq!(stats.frames(0x01), 2);
+        assert_eq!(stats.frames(0xE3), 1);
+        assert_eq!(stats.frames(0xFF), 0);
+        assert_eq!(stats.total_frames(), 3);
+
+        let mut buffer = [0u8; Stats::LEN];
+        stats.write(&mut buffer);
+        assert_eq!(&buffer[0..4], &[1, 0, 0, 0]);
+        assert_eq!(Stats::parse(&buffer), Ok(stats));
+        assert!(Stats::parse(&buffer[1..]).is_err());
+
+        let rsp = Report::<0xE5>(stats).to_response(&mut buffer).unwrap();
+        assert_eq!(Report::<0xE5>::from_response(&rsp), Some(Ok(Report(stats))));
+        assert_eq!(Report::<0xE6>::from_response(&rsp), None);
+
+        stats.clear();
+        assert_eq!(stats, Stats::default());
+    }
+
+    #[test]
+    fn display() {
+        let mut stats = Stats::new();
+        assert_eq!(
+            stats.to_string(),
+            "frames: 0; escapes: 0; double escapes: 0; overflows: 0; unknown codes: 0; bad arguments: 0; discarded: 0"
+        );
+        stats.frame(0x01);
+        stats.frame(0x07);
+        stats.frame(0x07);
+        stats.overflow();
+        stats.unknown_code();
+        assert_eq!(
+            stats.to_string(),
+            "frames: 3 (0x01: 1, 0x07: 2); escapes: 0; double escapes: 0; overflows: 1; unknown codes: 1; bad arguments: 0; discarded: 0"
+        );
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
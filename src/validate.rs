@@ -0,0 +1,279 @@
+//! Argument checks shared by the encoders and the decoders.
+//!
+//! Anything we refuse to encode, we also refuse to decode, so a bootloader
+//! doesn't have to check the arguments to a decoded `Command` itself.
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::vendor;
+use super::{
+    Command, Error, Field, Response, BUFFER_LEN, EXT_BLOCK_SIZE, EXT_PAGE_SIZE, ID_LEN,
+    INT_PAGE_SIZE, KEY_LEN, MAX_ATTR_LEN, MAX_INDEX, MAX_INFO_LEN,
+};
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Check the arguments to a `Command`.
+pub(crate) fn command(command: &Command) -> Result<(), Error> {
+    let code = command.code();
+    match *command {
+        Command::ErasePage { address } => aligned(code, address, INT_PAGE_SIZE),
+        Command::WritePage { address, data } => {
+            aligned(code, address, INT_PAGE_SIZE)?;
+            exact(code, Field::Data, data, INT_PAGE_SIZE)
+        }
+        Command::EraseExBlock { address } => aligned(code, address, EXT_BLOCK_SIZE),
+        Command::WriteExPage { address, data } => {
+            aligned(code, address, EXT_PAGE_SIZE)?;
+            exact(code, Field::Data, data, EXT_PAGE_SIZE)
+        }
+        Command::EraseExPage { address } => aligned(code, address, EXT_PAGE_SIZE),
+        Command::SetAttr { index, key, value } => {
+            attr_index(code, index)?;
+            exact(code, Field::Key, key, KEY_LEN)?;
+            // Values must be 1 to 55 bytes long, except that an all-zero key
+            // with no value clears the slot. That exception is our own
+            // extension to the protocol - see `Command::SetAttr`.
+            if value.is_empty() && !key.iter().all(|&b| b == 0) {
+                return Err(Error::BadLength {
+                    code,
+                    field: Field::Value,
+                    limit: MAX_ATTR_LEN,
+                    actual: 0,
+                });
+            }
+            at_most(code, Field::Value, value, MAX_ATTR_LEN)
+        }
+        Command::GetAttr { index } => attr_index(code, index),
+        Command::Vendor { code, payload } => {
+            vendor_code(code)?;
+            at_most(code, Field::Data, payload, BUFFER_LEN)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check the arguments to a `Response`.
+pub(crate) fn response(response: &Response) -> Result<(), Error> {
+    let code = response.code();
+    match *response {
+        Response::GetAttr { key, value } => {
+            exact(code, Field::Key, key, KEY_LEN)?;
+            at_most(code, Field::Value, value, MAX_ATTR_LEN)
+        }
+        Response::Info { info } => at_most(code, Field::Info, info, MAX_INFO_LEN),
+        Response::Id { id } => exact(code, Field::Id, id, ID_LEN),
+        Response::Vendor { code, payload } => {
+            vendor_code(code)?;
+            // The RX buffer also has to hold the response code
+            at_most(code, Field::Data, payload, BUFFER_LEN - 1)
+        }
+        _ => Ok(()),
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+fn aligned(code: u8, address: u32, alignment: usize) -> Result<(), Error> {
+    if (address as usize) % alignment == 0 {
+        Ok(())
+    } else {
+        Err(Error::UnalignedAddress {
+            code,
+            address,
+            alignment,
+        })
+    }
+}
+
+fn exact(code: u8, field: Field, data: &[u8], limit: usize) -> Result<(), Error> {
+    if data.len() == limit {
+        Ok(())
+    } else {
+        Err(Error::BadLength {
+            code,
+            field,
+            limit,
+            actual: data.len(),
+        })
+    }
+}
+
+fn at_most(code: u8, field: Field, data: &[u8], limit: usize) -> Result<(), Error> {
+    if data.len() <= limit {
+        Ok(())
+    } else {
+        Err(Error::BadLength {
+            code,
+            field,
+            limit,
+            actual: data.len(),
+        })
+    }
+}
+
+fn vendor_code(code: u8) -> Result<(), Error> {
+    if vendor::is_vendor_code(code) {
+        Ok(())
+    } else {
+        Err(Error::UnknownCommand { code })
+    }
+}
+
+fn attr_index(code: u8, index: u8) -> Result<(), Error> {
+    if index < MAX_INDEX {
+        Ok(())
+    } else {
+        Err(Error::BadIndex { code, index })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{CMD_EPAGE, CMD_GATTR, CMD_SATTR, CMD_WPAGE, CMD_XEBLOCK, CMD_XWPAGE};
+    use super::*;
+
+    #[test]
+    fn attr_limits() {
+        let key = b"board\0\0\0";
+        let ok = Command::SetAttr {
+            index: MAX_INDEX - 1,
+            key,
+            value: &[0x01; MAX_ATTR_LEN],
+        };
+        assert_eq!(command(&ok), Ok(()));
+        let bad = Command::SetAttr {
+            index: MAX_INDEX,
+            key,
+            value: b"x",
+        };
+        assert_eq!(
+            command(&bad),
+            Err(Error::BadIndex {
+                code: CMD_SATTR,
+                index: MAX_INDEX,
+            })
+        );
+        let bad = Command::SetAttr {
+            index: 0,
+            key,
+            value: &[0x01; MAX_ATTR_LEN + 1],
+        };
+        assert_eq!(
+            command(&bad),
+            Err(Error::BadLength {
+                code: CMD_SATTR,
+                field: Field::Value,
+                limit: MAX_ATTR_LEN,
+                actual: MAX_ATTR_LEN + 1,
+            })
+        );
+        let bad = Command::SetAttr {
+            index: 0,
+            key,
+            value: &[],
+        };
+        assert_eq!(
+            command(&bad),
+            Err(Error::BadLength {
+                code: CMD_SATTR,
+                field: Field::Value,
+                limit: MAX_ATTR_LEN,
+                actual: 0,
+            })
+        );
+        let bad = Command::SetAttr {
+            index: 0,
+            key: b"board",
+            value: b"x",
+        };
+        assert_eq!(
+            command(&bad),
+            Err(Error::BadLength {
+                code: CMD_SATTR,
+                field: Field::Key,
+                limit: KEY_LEN,
+                actual: 5,
+            })
+        );
+        let clear = Command::SetAttr {
+            index: 0,
+            key: &[0; KEY_LEN],
+            value: &[],
+        };
+        assert_eq!(command(&clear), Ok(()));
+        assert_eq!(command(&Command::GetAttr { index: 15 }), Ok(()));
+        assert_eq!(
+            command(&Command::GetAttr { index: 16 }),
+            Err(Error::BadIndex {
+                code: CMD_GATTR,
+                index: 16,
+            })
+        );
+    }
+
+    #[test]
+    fn alignment() {
+        assert_eq!(command(&Command::ErasePage { address: 0x400 }), Ok(()));
+        assert_eq!(
+            command(&Command::ErasePage { address: 0x500 }),
+            Err(Error::UnalignedAddress {
+                code: CMD_EPAGE,
+                address: 0x500,
+                alignment: INT_PAGE_SIZE,
+            })
+        );
+        assert_eq!(command(&Command::EraseExPage { address: 0x500 }), Ok(()));
+        assert_eq!(
+            command(&Command::EraseExBlock { address: 0x500 }),
+            Err(Error::UnalignedAddress {
+                code: CMD_XEBLOCK,
+                address: 0x500,
+                alignment: EXT_BLOCK_SIZE,
+            })
+        );
+        assert_eq!(command(&Command::EraseExBlock { address: 0x1000 }), Ok(()));
+        let page = [0u8; INT_PAGE_SIZE];
+        assert_eq!(
+            command(&Command::WritePage {
+                address: 0x300,
+                data: &page,
+            }),
+            Err(Error::UnalignedAddress {
+                code: CMD_WPAGE,
+                address: 0x300,
+                alignment: INT_PAGE_SIZE,
+            })
+        );
+        assert_eq!(
+            command(&Command::WriteExPage {
+                address: 0x300,
+                data: &page,
+            }),
+            Err(Error::BadLength {
+                code: CMD_XWPAGE,
+                field: Field::Data,
+                limit: EXT_PAGE_SIZE,
+                actual: INT_PAGE_SIZE,
+            })
+        );
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
@@ -0,0 +1,534 @@
+//! Support for vendor-specific commands and responses.
+//!
+//! Codes `FIRST_CODE` to `LAST_CODE` are never used by the standard
+//! protocol. A command with one of these codes decodes as
+//! `Command::Vendor`, carrying whatever payload bytes came before it. A
+//! response decodes as `Response::Vendor`, but as responses aren't
+//! self-delimiting you must tell the `ResponseDecoder` how long the payload
+//! is with `set_vendor_len` first.
+//!
+//! You can work with the raw payloads directly, or implement `Message` for
+//! your own types to parse and encode them:
+//!
+//! ```
+//! use tockloader_proto::vendor::Message;
+//! use tockloader_proto::{Command, Error};
+//!
+//! struct BatteryVoltage {
+//!     millivolts: u16,
+//! }
+//!
+//! impl Message for BatteryVoltage {
+//!     const CODE: u8 = 0xE0;
+//!     const LEN: usize = 2;
+//!
+//!     fn parse(payload: &[u8]) -> Result<BatteryVoltage, Error> {
+//!         Ok(BatteryVoltage {
+//!             millivolts: u16::from(payload[0]) | u16::from(payload[1]) << 8,
+//!         })
+//!     }
+//!
+//!     fn write(&self, payload: &mut [u8]) {
+//!         payload[0] = self.millivolts as u8;
+//!         payload[1] = (self.millivolts >> 8) as u8;
+//!     }
+//! }
+//!
+//! let mut buffer = [0u8; BatteryVoltage::LEN];
+//! let msg = BatteryVoltage { millivolts: 3300 };
+//! let cmd = msg.to_command(&mut buffer).unwrap();
+//! let decoded = BatteryVoltage::from_command(&cmd).unwrap().unwrap();
+//! assert_eq!(decoded.millivolts, 3300);
+//! ```
+//!
+//! A `Registry` records which vendor-specific codes you use. A bootloader
+//! registers a `Handler` for each of its commands and passes every decoded
+//! `Command` to `Registry::handle`. A flash tool registers the payload
+//! length of each response and has the `Registry` configure its
+//! `ResponseDecoder`:
+//!
+//! ```
+//! use tockloader_proto::vendor::Registry;
+//! use tockloader_proto::{Command, Response};
+//!
+//! fn battery<'r>(_payload: &[u8], reply: &'r mut [u8]) -> Response<'r> {
+//!     reply[..2].copy_from_slice(&3300u16.to_le_bytes());
+//!     Response::Vendor { code: 0xE0, payload: &reply[..2] }
+//! }
+//!
+//! let mut registry = Registry::new();
+//! registry.register_command(0xE0, battery).unwrap();
+//! let mut reply = [0u8; 8];
+//! let cmd = Command::Vendor { code: 0xE0, payload: &[] };
+//! let response = registry.handle(&cmd, &mut reply).unwrap();
+//! assert_eq!(response, Response::Vendor { code: 0xE0, payload: &[0xE4, 0x0C] });
+//! ```
+
+// ****************************************************************************
+//
+// Imports
+//
+// ****************************************************************************
+
+use super::{Command, Error, Field, Response, ResponseDecoder, BUFFER_LEN, VENDOR_CODES};
+
+// ****************************************************************************
+//
+// Public Types
+//
+// ****************************************************************************
+
+/// A vendor-specific message with a fixed-length payload.
+pub trait Message: Sized {
+    /// The command or response code, between `FIRST_CODE` and `LAST_CODE`.
+    const CODE: u8;
+    /// The number of payload bytes.
+    const LEN: usize;
+
+    /// Parse a payload, which is always exactly `LEN` bytes long.
+    fn parse(payload: &[u8]) -> Result<Self, Error>;
+
+    /// Write the payload into a buffer which is exactly `LEN` bytes long.
+    fn write(&self, payload: &mut [u8]);
+
+    /// Parse a `Command`. Returns `None` if it isn't this kind of message.
+    fn from_command(command: &Command) -> Option<Result<Self, Error>> {
+        match *command {
+            Command::Vendor { code, payload } if code == Self::CODE => {
+                Some(check_len::<Self>(payload).and_then(Self::parse))
+            }
+            _ => None,
+        }
+    }
+
+    /// Parse a `Response`. Returns `None` if it isn't this kind of message.
+    fn from_response(response: &Response) -> Option<Result<Self, Error>> {
+        match *response {
+            Response::Vendor { code, payload } if code == Self::CODE => {
+                Some(check_len::<Self>(payload).and_then(Self::parse))
+            }
+            _ => None,
+        }
+    }
+
+    /// Build a `Command`, using `buffer` to hold the payload.
+    fn to_command<'a>(&self, buffer: &'a mut [u8]) -> Result<Command<'a>, Error> {
+        let payload = fill(self, buffer)?;
+        Ok(Command::Vendor {
+            code: Self::CODE,
+            payload,
+        })
+    }
+
+    /// Build a `Response`, using `buffer` to hold the payload.
+    fn to_response<'a>(&self, buffer: &'a mut [u8]) -> Result<Response<'a>, Error> {
+        let payload = fill(self, buffer)?;
+        Ok(Response::Vendor {
+            code: Self::CODE,
+            payload,
+        })
+    }
+}
+
+/// Carries out a vendor-specific command. It is given the command's
+/// payload and a buffer for the response payload, and returns the
+/// `Response` to send.
+pub type Handler = for<'r> fn(payload: &[u8], reply: &'r mut [u8]) -> Response<'r>;
+
+/// The vendor-specific codes in use, with a `Handler` for each command and
+/// the payload length of each response.
+#[derive(Clone, Copy)]
+pub struct Registry {
+    handlers: [Option<Handler>; VENDOR_CODES],
+    response_lens: [Option<u16>; VENDOR_CODES],
+}
+
+// ****************************************************************************
+//
+// Public Data
+//
+// ****************************************************************************
+
+/// The first code available for vendor-specific messages.
+pub const FIRST_CODE: u8 = 0xE0;
+/// The last code available for vendor-specific messages.
+pub const LAST_CODE: u8 = 0xEF;
+
+// ****************************************************************************
+//
+// Public Impl/Functions/Modules
+//
+// ****************************************************************************
+
+/// Is this code reserved for vendor-specific messages?
+pub fn is_vendor_code(code: u8) -> bool {
+    (FIRST_CODE..=LAST_CODE).contains(&code)
+}
+
+impl Registry {
+    /// Create an empty `Registry`.
+    pub fn new() -> Registry {
+        Registry {
+            handlers: [None; VENDOR_CODES],
+            response_lens: [None; VENDOR_CODES],
+        }
+    }
+
+    /// Handle command `code` with `handler`, replacing any handler it
+    /// already had.
+    pub fn register_command(&mut self, code: u8, handler: Handler) -> Result<(), Error> {
+        let slot = slot(code)?;
+        self.handlers[slot] = Some(handler);
+        Ok(())
+    }
+
+    /// Record that response `code` carries `length` bytes of payload.
+    pub fn register_response(&mut self, code: u8, length: usize) -> Result<(), Error> {
+        let slot = slot(code)?;
+        if length >= BUFFER_LEN {
+            return Err(Error::BadLength {
+                code,
+                field: Field::Data,
+                limit: BUFFER_LEN - 1,
+                actual: length,
+            });
+        }
+        self.response_lens[slot] = Some(length as u16);
+        Ok(())
+    }
+
+    /// Record the payload length of the response `M`.
+    pub fn register_message<M: Message>(&mut self) -> Result<(), Error> {
+        self.register_response(M::CODE, M::LEN)
+    }
+
+    /// Is there a handler for command `code`?
+    pub fn handles(&self, code: u8) -> bool {
+        slot(code)
+            .map(|s| self.handlers[s].is_some())
+            .unwrap_or(false)
+    }
+
+    /// Pass a vendor-specific command to its handler, which may use
+    /// `reply` for the response payload.
+    ///
+    /// Returns `None` if `command` isn't vendor-specific or has no handler,
+    /// in which case you should reply with `Response::Unknown`.
+    pub fn handle<'r>(&self, command: &Command, reply: &'r mut [u8]) -> Option<Response<'r>> {
+        match *command {
+            Command::Vendor { code, payload } => {
+                let handler = self.handlers[slot(code).ok()?]?;
+                Some(handler(payload, reply))
+            }
+            _ => None,
+        }
+    }
+
+    /// Tell `decoder` the payload length of every registered response.
+    pub fn configure(&self, decoder: &mut ResponseDecoder) {
+        for (code, length) in (FIRST_CODE..=LAST_CODE).zip(self.response_lens.iter()) {
+            if let Some(length) = *length {
+                // Both have already been checked
+                let _ = decoder.set_vendor_len(code, length as usize);
+            }
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::new()
+    }
+}
+
+// ****************************************************************************
+//
+// Private Impl/Functions/Modules
+//
+// ****************************************************************************
+
+fn slot(code: u8) -> Result<usize, Error> {
+    if is_vendor_code(code) {
+        Ok((code - FIRST_CODE) as usize)
+    } else {
+        Err(Error::UnknownCommand { code })
+    }
+}
+
+fn check_len<M: Message>(payload: &[u8]) -> Result<&[u8], Error> {
+    if payload.len() == M::LEN {
+        Ok(payload)
+    } else {
+        Err(Error::BadArguments {
+            code: M::CODE,
+            expected: M::LEN,
+            actual: payload.len(),
+        })
+    }
+}
+
+fn fill<'a, M: Message>(message: &M, buffer: &'a mut [u8]) -> Result<&'a [u8], Error> {
+    if buffer.len() < M::LEN {
+        return Err(Error::BufferTooSmall {
+            needed: M::LEN,
+            available: buffer.len(),
+        });
+    }
+    let payload = &mut buffer[..M::LEN];
+    message.write(payload);
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {CommandDecoder, ESCAPE_CHAR};
+
+    #[derive(Debug, PartialEq)]
+    struct Fuses {
+        bank: u8,
+        bits: u8,
+    }
+
+    impl Message for Fuses {
+        const CODE: u8 = 0xE3;
+        const LEN: usize = 2;
+
+        fn parse(payload: &[u8]) -> Result<Fuses, Error> {
+            if payload[0] > 3 {
+                return Err(Error::BadIndex {
+                    code: Self::CODE,
+                    index: payload[0],
+                });
+            }
+            Ok(Fuses {
+                bank: payload[0],
+                bits: payload[1],
+            })
+        }
+
+        fn write(&self, payload: &mut [u8]) {
+            payload[0] = self.bank;
+            payload[1] = self.bits;
+        }
+    }
+
+    fn read_fuses<'r>(payload: &[u8], reply: &'r mut [u8]) -> Response<'r> {
+        match Fuses::parse(payload) {
+            Ok(fuses) => {
+                let bits = Fuses {
+                    bits: 0xA5,
+                    ..fuses
+                };
+                bits.to_response(reply).unwrap_or(Response::InternalError)
+            }
+            Err(_) => Response::BadArguments,
+        }
+    }
+
+    #[test]
+    fn vendor_code_bounds() {
+        assert!(!is_vendor_code(0x00));
+        assert!(!is_vendor_code(FIRST_CODE - 1));
+        assert!(is_vendor_code(FIRST_CODE));
+        assert!(is_vendor_code(LAST_CODE));
+        assert!(!is_vendor_code(LAST_CODE + 1));
+        assert!(!is_vendor_code(ESCAPE_CHAR));
+        assert!(!is_vendor_code(0xFF));
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let mut buffer = [0u8; 4];
+        let fuses = Fuses {
+            bank: 1,
+            bits: 0x42,
+        };
+        let response = fuses.to_response(&mut buffer).unwrap();
+        assert_eq!(
+            response,
+            Response::Vendor {
+                code: 0xE3,
+                payload: &[1, 0x42]
+            }
+        );
+        assert_eq!(Fuses::from_response(&response), Some(Ok(fuses)));
+    }
+
+    #[test]
+    fn message_errors() {
+        // Too small a buffer to build the message in
+        let mut buffer = [0u8; 1];
+        let fuses = Fuses {
+            bank: 1,
+            bits: 0x42,
+        };
+        let e = Error::BufferTooSmall {
+            needed: 2,
+            available: 1,
+        };
+        assert_eq!(fuses.to_response(&mut buffer), Err(e.clone()));
+        assert_eq!(fuses.to_command(&mut buffer), Err(e));
+        // Someone else's message
+        assert_eq!(Fuses::from_response(&Response::Pong), None);
+        let other = Response::Vendor {
+            code: 0xE4,
+            payload: &[1, 2],
+        };
+        assert_eq!(Fuses::from_response(&other), None);
+        assert_eq!(Fuses::from_command(&Command::Ping), None);
+        // The wrong length
+        let short = Response::Vendor {
+            code: 0xE3,
+            payload: &[1],
+        };
+        assert_eq!(
+            Fuses::from_response(&short),
+            Some(Err(Error::BadArguments {
+                code: 0xE3,
+                expected: 2,
+                actual: 1,
+            }))
+        );
+        let long = Command::Vendor {
+            code: 0xE3,
+            payload: &[1, 2, 3],
+        };
+        assert_eq!(
+            Fuses::from_command(&long),
+            Some(Err(Error::BadArguments {
+                code: 0xE3,
+                expected: 2,
+                actual: 3,
+            }))
+        );
+        // The right length, but `parse` doesn't like it
+        let bad = Response::Vendor {
+            code: 0xE3,
+            payload: &[9, 0],
+        };
+        assert_eq!(
+            Fuses::from_response(&bad),
+            Some(Err(Error::BadIndex {
+                code: 0xE3,
+                index: 9,
+            }))
+        );
+    }
+
+    #[test]
+    fn registry_handles_commands() {
+        let mut registry = Registry::new();
+        assert!(!registry.handles(Fuses::CODE));
+        registry.register_command(Fuses::CODE, read_fuses).unwrap();
+        assert!(registry.handles(Fuses::CODE));
+        assert!(!registry.handles(0xE4));
+        assert!(!registry.handles(0x01));
+
+        let mut reply = [0u8; 8];
+        let cmd = Command::Vendor {
+            code: Fuses::CODE,
+            payload: &[2, 0],
+        };
+        assert_eq!(
+            registry.handle(&cmd, &mut reply),
+            Some(Response::Vendor {
+                code: Fuses::CODE,
+                payload: &[2, 0xA5]
+            })
+        );
+        let cmd = Command::Vendor {
+            code: Fuses::CODE,
+            payload: &[7, 0],
+        };
+        assert_eq!(
+            registry.handle(&cmd, &mut reply),
+            Some(Response::BadArguments)
+        );
+        // Not registered, or not vendor-specific at all
+        let cmd = Command::Vendor {
+            code: 0xE4,
+            payload: &[],
+        };
+        assert_eq!(registry.handle(&cmd, &mut reply), None);
+        assert_eq!(registry.handle(&Command::Ping, &mut reply), None);
+    }
+
+    #[test]
+    fn registry_decodes_commands() {
+        let mut registry = Registry::new();
+        registry.register_command(Fuses::CODE, read_fuses).unwrap();
+        let mut decoder = CommandDecoder::new();
+        let mut reply = [0u8; 8];
+        let (_, result) = decoder.feed(&[0x01, 0x00, ESCAPE_CHAR, Fuses::CODE]);
+        let cmd = result.unwrap().unwrap();
+        assert_eq!(
+            registry.handle(&cmd, &mut reply),
+            Some(Response::Vendor {
+                code: Fuses::CODE,
+                payload: &[1, 0xA5]
+            })
+        );
+    }
+
+    #[test]
+    fn registry_configures_decoder() {
+        let mut registry = Registry::new();
+        registry.register_message::<Fuses>().unwrap();
+        registry.register_response(0xEF, 0).unwrap();
+        let mut decoder = ResponseDecoder::new();
+        registry.configure(&mut decoder);
+        let (_, result) = decoder.feed(&[ESCAPE_CHAR, Fuses::CODE, 3, 0x11]);
+        assert_eq!(
+            result
+                .unwrap()
+                .and_then(|r| Fuses::from_response(&r).unwrap()),
+            Ok(Fuses {
+                bank: 3,
+                bits: 0x11
+            })
+        );
+        let (_, result) = decoder.feed(&[ESCAPE_CHAR, 0xEF]);
+        assert_eq!(
+            result,
+            Some(Ok(Response::Vendor {
+                code: 0xEF,
+                payload: &[]
+            }))
+        );
+    }
+
+    #[test]
+    fn registry_errors() {
+        let mut registry = Registry::default();
+        assert_eq!(
+            registry.register_command(0x01, read_fuses),
+            Err(Error::UnknownCommand { code: 0x01 })
+        );
+        assert_eq!(
+            registry.register_response(LAST_CODE + 1, 2),
+            Err(Error::UnknownCommand {
+                code: LAST_CODE + 1
+            })
+        );
+        assert_eq!(
+            registry.register_response(FIRST_CODE, BUFFER_LEN),
+            Err(Error::BadLength {
+                code: FIRST_CODE,
+                field: Field::Data,
+                limit: BUFFER_LEN - 1,
+                actual: BUFFER_LEN,
+            })
+        );
+        assert_eq!(
+            registry.register_response(FIRST_CODE, BUFFER_LEN - 1),
+            Ok(())
+        );
+    }
+}
+
+// ****************************************************************************
+//
+// End Of File
+//
+// ****************************************************************************
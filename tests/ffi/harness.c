@@ -0,0 +1,150 @@
+/*
+ * Exercises the C API in include/tockloader_proto.h.
+ *
+ * Build and run it with `make -C tests/ffi`.
+ */
+
+#include <stdio.h>
+#include <string.h>
+
+#include "tockloader_proto.h"
+
+#define ESCAPE 0xFC
+
+static int failures = 0;
+
+#define CHECK(cond)                                                   \
+    do {                                                              \
+        if (!(cond)) {                                                \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__,    \
+                    __LINE__, #cond);                                 \
+            failures++;                                               \
+        }                                                             \
+    } while (0)
+
+/* Feed `len` bytes, returning the status after the last one. */
+static tl_status feed(tl_decoder *decoder, const uint8_t *bytes, size_t len,
+                      tl_command *command)
+{
+    tl_status status = TL_STATUS_PENDING;
+    for (size_t i = 0; i < len; i++) {
+        status = tl_decoder_feed(decoder, bytes[i], command);
+    }
+    return status;
+}
+
+static void test_decode(void)
+{
+    tl_decoder decoder;
+    tl_command command;
+    CHECK(tl_decoder_init(&decoder) == TL_STATUS_OK);
+
+    const uint8_t ping[] = { ESCAPE, 0x01 };
+    CHECK(feed(&decoder, ping, sizeof(ping), &command) == TL_STATUS_OK);
+    CHECK(command.tag == TL_COMMAND_PING);
+
+    /* Each byte but the last is pending */
+    const uint8_t erase[] = { 0x00, 0x02, 0x01, 0x00 };
+    for (size_t i = 0; i < sizeof(erase); i++) {
+        CHECK(tl_decoder_feed(&decoder, erase[i], &command) ==
+              TL_STATUS_PENDING);
+    }
+    CHECK(tl_decoder_feed(&decoder, ESCAPE, &command) == TL_STATUS_PENDING);
+    CHECK(tl_decoder_feed(&decoder, 0x06, &command) == TL_STATUS_OK);
+    CHECK(command.tag == TL_COMMAND_ERASE_PAGE);
+    CHECK(command.ERASE_PAGE.address == 0x00010200);
+
+    /* An escaped escape in the key */
+    const uint8_t sattr[] = { 0x02, 'b', 'o',  'a', 'r', 'd', ESCAPE, ESCAPE,
+                              0x00, 0x00, 0x03, 'f', 'o', 'o', ESCAPE, 0x13 };
+    CHECK(feed(&decoder, sattr, sizeof(sattr), &command) == TL_STATUS_OK);
+    CHECK(command.tag == TL_COMMAND_SET_ATTR);
+    CHECK(command.SET_ATTR.index == 2);
+    CHECK(memcmp(command.SET_ATTR.key, "board\xFC\0\0", 8) == 0);
+    CHECK(command.SET_ATTR.value_len == 3);
+    CHECK(memcmp(command.SET_ATTR.value, "foo", 3) == 0);
+
+    const uint8_t baud[] = { 0x02, 0x00, 0xC2, 0x01, 0x00, ESCAPE, 0x21 };
+    CHECK(feed(&decoder, baud, sizeof(baud), &command) == TL_STATUS_OK);
+    CHECK(command.tag == TL_COMMAND_CHANGE_BAUD);
+    CHECK(command.CHANGE_BAUD.mode == TL_BAUD_MODE_VERIFY);
+    CHECK(command.CHANGE_BAUD.baud == 115200);
+}
+
+static void test_decode_errors(void)
+{
+    tl_decoder decoder;
+    tl_command command;
+    CHECK(tl_decoder_init(&decoder) == TL_STATUS_OK);
+
+    const uint8_t short_erase[] = { 0x01, ESCAPE, 0x06 };
+    CHECK(feed(&decoder, short_erase, sizeof(short_erase), &command) ==
+          TL_STATUS_BAD_ARGUMENTS);
+
+    const uint8_t unaligned[] = { 0x01, 0x00, 0x00, 0x00, ESCAPE, 0x06 };
+    CHECK(feed(&decoder, unaligned, sizeof(unaligned), &command) ==
+          TL_STATUS_UNALIGNED_ADDRESS);
+
+    /* Junk, then a reset, then a good command */
+    const uint8_t junk[] = { 0x01, 0x02, 0x03 };
+    CHECK(feed(&decoder, junk, sizeof(junk), &command) == TL_STATUS_PENDING);
+    CHECK(tl_decoder_reset(&decoder) == TL_STATUS_OK);
+    const uint8_t info[] = { ESCAPE, 0x03 };
+    CHECK(feed(&decoder, info, sizeof(info), &command) == TL_STATUS_OK);
+    CHECK(command.tag == TL_COMMAND_INFO);
+
+    CHECK(tl_decoder_init(NULL) == TL_STATUS_NULL_POINTER);
+    CHECK(tl_decoder_feed(&decoder, 0, NULL) == TL_STATUS_NULL_POINTER);
+}
+
+static void test_encode(void)
+{
+    uint8_t buffer[16];
+    size_t written = 0;
+    tl_response response;
+
+    response.tag = TL_RESPONSE_PONG;
+    CHECK(tl_response_encode(&response, buffer, sizeof(buffer), &written) ==
+          TL_STATUS_OK);
+    CHECK(written == 2);
+    CHECK(buffer[0] == ESCAPE && buffer[1] == 0x11);
+
+    const uint8_t data[] = { 0x01, ESCAPE, 0x02 };
+    response.tag = TL_RESPONSE_READ_RANGE;
+    response.READ_RANGE.data = data;
+    response.READ_RANGE.data_len = sizeof(data);
+    CHECK(tl_response_encode(&response, buffer, sizeof(buffer), &written) ==
+          TL_STATUS_OK);
+    const uint8_t expected[] = { ESCAPE, 0x20, 0x01, ESCAPE, ESCAPE, 0x02 };
+    CHECK(written == sizeof(expected));
+    CHECK(memcmp(buffer, expected, sizeof(expected)) == 0);
+    CHECK(tl_response_encode(&response, buffer, 4, &written) ==
+          TL_STATUS_BUFFER_TOO_SMALL);
+
+    response.tag = TL_RESPONSE_CRC_INT_FLASH;
+    response.CRC_INT_FLASH.crc = 0xCBF43926;
+    CHECK(tl_response_encode(&response, buffer, sizeof(buffer), &written) ==
+          TL_STATUS_OK);
+    const uint8_t crc[] = { ESCAPE, 0x23, 0x26, 0x39, 0xF4, 0xCB };
+    CHECK(written == sizeof(crc));
+    CHECK(memcmp(buffer, crc, sizeof(crc)) == 0);
+
+    response.tag = TL_RESPONSE_INFO;
+    response.INFO.info = NULL;
+    response.INFO.info_len = 4;
+    CHECK(tl_response_encode(&response, buffer, sizeof(buffer), &written) ==
+          TL_STATUS_NULL_POINTER);
+}
+
+int main(void)
+{
+    test_decode();
+    test_decode_errors();
+    test_encode();
+    if (failures) {
+        fprintf(stderr, "%d check(s) failed\n", failures);
+        return 1;
+    }
+    printf("all checks passed\n");
+    return 0;
+}